@@ -0,0 +1,263 @@
+//! Command line argument parsing.
+
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use loxide::{Backend, GcConfig};
+use loxide::interpreter::DEFAULT_MAX_CALL_DEPTH;
+
+pub const USAGE: &str = "\
+Usage: loxide [options] [script]
+       loxide compile [options] <script> [-o <file>]
+
+Options:
+  --backend tree|vm   Walk the syntax tree (default) or run on the bytecode VM
+  --dump-bytecode     Print the compiled bytecode before running it (VM only)
+  --trace             Print the stack and every instruction as it runs (VM only)
+  --gc-threshold <n>  Number of objects on the heap before the first garbage
+                      collection (default: 10000)
+  --gc-stress         Collect garbage on every allocation
+  --max-call-depth <n>
+                      Number of nested function calls before raising a stack
+                      overflow error (default: 1000)
+  -o, --output <file> Where to write compiled bytecode (defaults to the script
+                      with a .loxc extension)
+
+Scripts can also be bytecode files written by `loxide compile`, which always
+run on the VM.";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub backend: Backend,
+
+    /// Print a disassembly of every chunk of code after compiling it
+    pub dump_bytecode: bool,
+
+    /// Trace the execution of every instruction
+    pub trace: bool,
+
+    pub gc: GcConfig,
+
+    /// The maximum number of nested function calls
+    pub max_call_depth: usize,
+
+    /// The script to run, or `None` to start a REPL
+    pub script: Option<String>,
+
+    /// Compile the script to a bytecode file instead of running it
+    pub compile: bool,
+
+    /// The file to write compiled bytecode to
+    pub output: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            backend: Backend::default(),
+            dump_bytecode: false,
+            trace: false,
+            gc: GcConfig::default(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            script: None,
+            compile: false,
+            output: None,
+        }
+    }
+}
+
+impl Options {
+    /// Parse the command line arguments, without the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, UsageError> {
+        let mut options = Options::default();
+        let mut backend = None;
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            // Accept both `--flag value` and `--flag=value`
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_owned(), Some(value.to_owned())),
+                _ => (arg.clone(), None),
+            };
+
+            match flag.as_str() {
+                "--backend" => {
+                    let Some(value) = inline_value.or_else(|| args.next()) else {
+                        return Err(UsageError::MissingValue("--backend"));
+                    };
+
+                    backend = match value.as_str() {
+                        "tree" => Some(Backend::Tree),
+                        "vm" => Some(Backend::Vm),
+                        _ => return Err(UsageError::UnknownBackend(value)),
+                    };
+                },
+
+                "--dump-bytecode" => options.dump_bytecode = true,
+
+                "--trace" => options.trace = true,
+
+                "--gc-threshold" => {
+                    let Some(value) = inline_value.or_else(|| args.next()) else {
+                        return Err(UsageError::MissingValue("--gc-threshold"));
+                    };
+
+                    options.gc.threshold = match value.parse() {
+                        Ok(threshold) if threshold > 0 => threshold,
+                        _ => return Err(UsageError::InvalidNumber("--gc-threshold", value)),
+                    };
+                },
+
+                "--gc-stress" => options.gc.stress = true,
+
+                "--max-call-depth" => {
+                    let Some(value) = inline_value.or_else(|| args.next()) else {
+                        return Err(UsageError::MissingValue("--max-call-depth"));
+                    };
+
+                    options.max_call_depth = match value.parse() {
+                        Ok(depth) if depth > 0 => depth,
+                        _ => return Err(UsageError::InvalidNumber("--max-call-depth", value)),
+                    };
+                },
+
+                "-o" | "--output" => {
+                    let Some(value) = inline_value.or_else(|| args.next()) else {
+                        return Err(UsageError::MissingValue("--output"));
+                    };
+
+                    options.output = Some(value);
+                },
+
+                _ if flag.starts_with('-') => return Err(UsageError::UnknownFlag(flag)),
+
+                "compile" if !options.compile && options.script.is_none() => options.compile = true,
+
+                _ if options.script.is_some() => return Err(UsageError::TooManyArguments),
+
+                _ => options.script = Some(arg),
+            }
+        }
+
+        if options.compile {
+            let Some(script) = &options.script else {
+                return Err(UsageError::MissingScript);
+            };
+
+            if options.output.is_none() {
+                let output = PathBuf::from(script).with_extension("loxc");
+                options.output = Some(output.to_string_lossy().into_owned());
+            }
+        } else if options.output.is_some() {
+            return Err(UsageError::OutputWithoutCompile);
+        }
+
+        // Bytecode only makes sense on the VM, so these options pick it
+        // unless another backend was asked for explicitly
+        let needs_vm = options.dump_bytecode || options.trace || options.compile;
+
+        options.backend = match backend {
+            Some(Backend::Tree) if needs_vm => return Err(UsageError::RequiresVm),
+            Some(backend) => backend,
+            None if needs_vm => Backend::Vm,
+            None => Backend::Tree,
+        };
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsageError {
+    UnknownFlag(String),
+    UnknownBackend(String),
+    MissingValue(&'static str),
+    InvalidNumber(&'static str, String),
+    TooManyArguments,
+    MissingScript,
+    OutputWithoutCompile,
+    RequiresVm,
+}
+
+impl Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsageError::UnknownFlag(flag) => write!(f, "Unknown option '{flag}'"),
+            UsageError::UnknownBackend(name) => write!(f, "Unknown backend '{name}' (expected 'tree' or 'vm')"),
+            UsageError::MissingValue(flag) => write!(f, "Option '{flag}' expects a value"),
+            UsageError::InvalidNumber(flag, value) => write!(f, "Option '{flag}' expects a positive number, but found '{value}'"),
+            UsageError::TooManyArguments => write!(f, "Expected at most one script"),
+            UsageError::MissingScript => write!(f, "Expected a script to compile"),
+            UsageError::OutputWithoutCompile => write!(f, "Option '--output' can only be used with 'compile'"),
+            UsageError::RequiresVm => write!(f, "Bytecode is only supported by the 'vm' backend"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, UsageError> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn backends() {
+        assert_eq!(parse(&[]), Ok(Options::default()));
+        assert_eq!(parse(&["--backend", "vm"]).unwrap().backend, Backend::Vm);
+        assert_eq!(parse(&["--backend=tree", "a.lox"]).unwrap().backend, Backend::Tree);
+        assert!(parse(&["--backend", "jit"]).is_err());
+        assert!(parse(&["--backend"]).is_err());
+    }
+
+    #[test]
+    fn bytecode_flags_select_vm() {
+        let options = parse(&["--dump-bytecode", "a.lox"]).unwrap();
+        assert!(options.dump_bytecode);
+        assert_eq!(options.backend, Backend::Vm);
+
+        assert!(parse(&["--trace", "--backend", "vm"]).unwrap().trace);
+        assert_eq!(parse(&["--trace", "--backend=tree"]), Err(UsageError::RequiresVm));
+    }
+
+    #[test]
+    fn scripts() {
+        assert_eq!(parse(&["a.lox", "--backend", "vm"]).unwrap().script.as_deref(), Some("a.lox"));
+        assert_eq!(parse(&["a.lox", "b.lox"]), Err(UsageError::TooManyArguments));
+        assert!(parse(&["--frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn gc() {
+        let options = parse(&["--gc-stress", "--gc-threshold=100"]).unwrap();
+        assert!(options.gc.stress);
+        assert_eq!(options.gc.threshold, 100);
+        assert_eq!(options.backend, Backend::Tree);
+
+        assert!(parse(&["--gc-threshold", "0"]).is_err());
+        assert!(parse(&["--gc-threshold", "lots"]).is_err());
+    }
+
+    #[test]
+    fn max_call_depth() {
+        assert_eq!(parse(&[]).unwrap().max_call_depth, DEFAULT_MAX_CALL_DEPTH);
+        assert_eq!(parse(&["--max-call-depth", "50"]).unwrap().max_call_depth, 50);
+        assert!(parse(&["--max-call-depth=0"]).is_err());
+        assert!(parse(&["--max-call-depth"]).is_err());
+    }
+
+    #[test]
+    fn compile() {
+        let options = parse(&["compile", "a.lox", "-o", "out.loxc"]).unwrap();
+        assert!(options.compile);
+        assert_eq!(options.script.as_deref(), Some("a.lox"));
+        assert_eq!(options.output.as_deref(), Some("out.loxc"));
+        assert_eq!(options.backend, Backend::Vm);
+
+        assert_eq!(parse(&["compile", "dir/a.lox"]).unwrap().output.as_deref(), Some("dir/a.loxc"));
+        assert_eq!(parse(&["compile"]), Err(UsageError::MissingScript));
+        assert_eq!(parse(&["a.lox", "--output=b.loxc"]), Err(UsageError::OutputWithoutCompile));
+        assert_eq!(parse(&["compile", "a.lox", "--backend", "tree"]), Err(UsageError::RequiresVm));
+    }
+}
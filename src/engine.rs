@@ -0,0 +1,1320 @@
+//! The entry point for running Lox code from Rust.
+//!
+//! An `Engine` is a long-lived session on either backend. It takes care of
+//! the whole pipeline (scanning, parsing, resolving and, for the VM,
+//! compiling) and hands back every error as an `Error`, rather than printing
+//! it.
+
+use std::fmt::Display;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::colors::{NORMAL, RED};
+use crate::gc::GcConfig;
+use crate::interpreter::{Interpreter, RuntimeError, UncaughtError, Visitor, DEFAULT_MAX_CALL_DEPTH};
+use crate::interpreter::class::Instance;
+use crate::interpreter::functions::{Arity, Call, NativeFunction};
+use crate::interpreter::resolver::{ResolutionError, Resolutions, Resolver};
+use crate::interpreter::value::LoxValue;
+use crate::output::Sink;
+use crate::sourcemap::Source;
+use crate::span::{Span, Spanned};
+use crate::symbol::Symbol;
+use crate::syntax::ast::{Ast, Expr};
+use crate::syntax::parser::{ParseError, Parser};
+use crate::syntax::tokens::Token;
+use crate::syntax::tokenizer::{LexError, Scanner};
+use crate::vm::Vm;
+use crate::vm::compiler::{CompileError, Compiler};
+use crate::vm::disassembler::disassemble;
+use crate::vm::serialize::{self, LoadError};
+use crate::vm::value::Value;
+
+/// The engine that executes code.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Walk the syntax tree directly
+    #[default]
+    Tree,
+
+    /// Compile to bytecode and run it on the VM
+    Vm,
+}
+
+/// The backend a session runs its code on.
+enum Runtime {
+    Tree(Interpreter),
+    Vm(Vm),
+}
+
+impl Runtime {
+    fn add_source(&mut self, chunk: &str) -> usize {
+        match self {
+            Runtime::Tree(interpreter) => interpreter.add_source(chunk),
+            Runtime::Vm(vm) => vm.add_source(chunk),
+        }
+    }
+
+    fn source(&self) -> &Source {
+        match self {
+            Runtime::Tree(interpreter) => interpreter.source(),
+            Runtime::Vm(vm) => vm.source(),
+        }
+    }
+
+    fn define_native(&mut self, native: &Rc<NativeFunction>) {
+        let name = Symbol::intern(&native.name);
+
+        match self {
+            Runtime::Tree(interpreter) => interpreter.globals_mut().define(name, LoxValue::NativeFunction(native.clone())),
+            Runtime::Vm(vm) => { vm.globals_mut().insert(name, Value::Native(native.clone())); },
+        }
+    }
+}
+
+/// A Lox session.
+///
+/// Every chunk of code that gets evaluated runs in the same session, so
+/// globals, functions and classes defined by one chunk can be used by the
+/// next.
+pub struct Engine {
+    runtime: Runtime,
+    backend: Backend,
+    gc: GcConfig,
+    max_call_depth: usize,
+
+    /// Print the stack and every instruction while executing (VM only)
+    trace: bool,
+
+    /// Print a disassembly of every chunk of code after compiling it (VM only)
+    dump_bytecode: bool,
+
+    /// Where `print` and bytecode dumps get written to
+    output: Sink,
+
+    /// Where error reports and the VM's execution trace get written to
+    diagnostics: Sink,
+
+    /// The first node id for the next chunk of code, so nodes from every
+    /// chunk in the session can be told apart
+    next_node_id: usize,
+
+    /// The functions registered with `register_fn`, which survive a reset
+    natives: Vec<Rc<NativeFunction>>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    /// Create a session on the tree-walking backend.
+    pub fn new() -> Self {
+        Self {
+            runtime: Runtime::Tree(Interpreter::new()),
+            backend: Backend::Tree,
+            gc: GcConfig::default(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            trace: false,
+            dump_bytecode: false,
+            output: Sink::stdout(),
+            diagnostics: Sink::stderr(),
+            next_node_id: 0,
+            natives: Vec::new(),
+        }
+    }
+
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self.reset();
+        self
+    }
+
+    pub fn with_gc(mut self, config: GcConfig) -> Self {
+        self.gc = config;
+        self.reset();
+        self
+    }
+
+    /// Limit the number of nested function calls. Going any deeper raises a
+    /// `RuntimeError::StackOverflow`.
+    pub fn with_max_call_depth(mut self, depth: usize) -> Self {
+        self.max_call_depth = depth;
+        self.reset();
+        self
+    }
+
+    /// Print the contents of the stack and every instruction that runs (VM
+    /// only).
+    pub fn with_trace(mut self) -> Self {
+        self.trace = true;
+        self.reset();
+        self
+    }
+
+    /// Print the bytecode for every chunk of code after compiling it (VM
+    /// only).
+    pub fn with_dump_bytecode(mut self) -> Self {
+        self.dump_bytecode = true;
+        self
+    }
+
+    /// Write program output to `writer`, rather than stdout.
+    ///
+    /// ```
+    /// use loxide::Engine;
+    /// use loxide::output::SharedBuffer;
+    ///
+    /// let output = SharedBuffer::new();
+    /// let mut engine = Engine::new().with_output(output.clone());
+    ///
+    /// engine.eval("print 1 + 2;").unwrap();
+    /// assert_eq!(output.contents(), "3\n");
+    /// ```
+    pub fn with_output(mut self, writer: impl Write + 'static) -> Self {
+        self.output = Sink::new(writer);
+        self.reset();
+        self
+    }
+
+    /// Write diagnostics to `writer`, rather than stderr. See `report_error`.
+    pub fn with_diagnostics(mut self, writer: impl Write + 'static) -> Self {
+        self.diagnostics = Sink::new(writer);
+        self.reset();
+        self
+    }
+
+    pub fn backend(&self) -> Backend {
+        match self.runtime {
+            Runtime::Tree(_) => Backend::Tree,
+            Runtime::Vm(_) => Backend::Vm,
+        }
+    }
+
+    /// All the code the session was handed so far. The spans in errors point
+    /// into this.
+    pub fn source(&self) -> &Source {
+        self.runtime.source()
+    }
+
+    /// Throw away everything the session defined, starting over with a clean
+    /// slate.
+    pub fn reset(&mut self) {
+        self.runtime = match self.backend {
+            Backend::Tree => Runtime::Tree(Interpreter::new()
+                .with_gc(self.gc)
+                .with_max_call_depth(self.max_call_depth)
+                .with_output(self.output.clone())),
+
+            Backend::Vm => Runtime::Vm(self.vm()),
+        };
+
+        self.define_natives();
+    }
+
+    fn vm(&self) -> Vm {
+        let vm = Vm::new()
+            .with_gc(self.gc)
+            .with_max_call_depth(self.max_call_depth)
+            .with_output(self.output.clone())
+            .with_diagnostics(self.diagnostics.clone());
+
+        if self.trace { vm.with_trace() } else { vm }
+    }
+
+    fn define_natives(&mut self) {
+        for native in &self.natives {
+            self.runtime.define_native(native);
+        }
+    }
+
+    /// Make a Rust function available to Lox code as a global.
+    ///
+    /// The function gets called with the evaluated arguments, after checking
+    /// there are as many as `arity` asks for. Pass `Arity::AtLeast` for
+    /// functions that take a variable number of arguments. Errors get reported
+    /// at the call, like any other runtime error.
+    ///
+    /// On the VM, only data (`nil`, booleans, numbers, strings, and lists and
+    /// maps of those) can be passed to and returned from the function. Lists
+    /// and maps get copied on the way, so changes the function makes to its
+    /// arguments don't show up in the script.
+    ///
+    /// ```
+    /// use loxide::{Engine, LoxValue, RuntimeError};
+    ///
+    /// let mut engine = Engine::new();
+    /// engine.register_fn("sqrt", 1, |args| {
+    ///     let num = args[0].as_num()?;
+    ///
+    ///     if num < 0.0 {
+    ///         return Err(RuntimeError::Native(format!("Can't take the square root of {num}")));
+    ///     }
+    ///
+    ///     Ok(LoxValue::Num(num.sqrt()))
+    /// });
+    ///
+    /// engine.eval("var root = sqrt(16);").unwrap();
+    /// assert_eq!(engine.global("root").unwrap(), Some(LoxValue::Num(4.0)));
+    /// assert!(engine.eval("sqrt(-1);").is_err());
+    /// ```
+    pub fn register_fn(
+        &mut self,
+        name: &str,
+        arity: impl Into<Arity>,
+        fun: impl Fn(&[LoxValue]) -> Result<LoxValue, RuntimeError> + 'static,
+    ) {
+        let native = Rc::new(NativeFunction::new(name, arity, fun));
+        self.runtime.define_native(&native);
+        self.natives.retain(|existing| existing.name != native.name);
+        self.natives.push(native);
+    }
+
+    /// Write an error to the diagnostics sink, pointing out where in the
+    /// session's source code it happened.
+    pub fn report_error(&self, error: &Error) {
+        // There's nowhere left to report a failure to report an error
+        let _ = writeln!(self.diagnostics, "{}", error.report(self.source()));
+    }
+
+    /// Run a chunk of code.
+    pub fn eval(&mut self, code: &str) -> Result<(), Error> {
+        self.execute(code, false)
+    }
+
+    /// Run a chunk of code the way the REPL does: a trailing expression
+    /// without a semicolon gets its value printed.
+    pub fn eval_repl(&mut self, code: &str) -> Result<(), Error> {
+        self.execute(code, true)
+    }
+
+    /// Run a script, which can also be a bytecode file written by `compile`.
+    pub fn run_file(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let bytes = std::fs::read(path)?;
+
+        if serialize::is_bytecode(&bytes) {
+            return self.run_bytecode(&bytes);
+        }
+
+        let code = String::from_utf8(bytes)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+        self.eval(&code)
+    }
+
+    /// Run a script that was compiled with `compile`. Bytecode can't be run by
+    /// walking a tree, so this fails on engines that use the tree-walking
+    /// backend, leaving the session as it was.
+    pub fn run_bytecode(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let Runtime::Vm(vm) = &mut self.runtime else {
+            return Err(Error::Load(LoadError::RequiresVm));
+        };
+
+        let script = vm.load(bytes)?;
+
+        if self.dump_bytecode {
+            let _ = write!(self.output, "{}", disassemble(&script, vm.source()));
+        }
+
+        vm.interpret(script)?;
+        Ok(())
+    }
+
+    /// Compile a chunk of code to the contents of a bytecode file, without
+    /// running it.
+    pub fn compile(&mut self, code: &str) -> Result<Vec<u8>, Error> {
+        let (ast, _) = self.analyze(code, false)?;
+        let script = Compiler::new().compile(&ast)?;
+        let source = self.runtime.source();
+
+        if self.dump_bytecode {
+            let _ = write!(self.output, "{}", disassemble(&script, source));
+        }
+
+        Ok(serialize::encode(&script, source))
+    }
+
+    /// Parse a single expression, without running it.
+    pub fn parse_expression(&mut self, code: &str) -> Result<Expr, Error> {
+        let offset = self.runtime.add_source(code);
+        let source = self.runtime.source();
+        let mut scanner = Scanner::new_at(source, offset);
+        let parsed = Parser::new(source, &mut scanner).expression();
+
+        if scanner.had_error() {
+            return Err(Error::Lex(scanner.take_errors()));
+        }
+
+        parsed.map_err(|err| Error::Parse(vec![err]))
+    }
+
+    /// Look up the value of a global variable, or `None` if it isn't
+    /// defined.
+    ///
+    /// Fails with `Error::Unsupported` if the global holds a value that the
+    /// VM can't hand over to Rust.
+    pub fn global(&self, name: &str) -> Result<Option<LoxValue>, Error> {
+        let Some(name) = Symbol::lookup(name) else {
+            return Ok(None);
+        };
+
+        match &self.runtime {
+            Runtime::Tree(interpreter) => Ok(interpreter.globals().find(name).cloned()),
+
+            Runtime::Vm(vm) => match vm.globals().get(&name) {
+                Some(value) => value.to_lox().map(Some).map_err(Error::Unsupported),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Define a global variable, or overwrite it if it already exists.
+    pub fn set_global(&mut self, name: &str, value: LoxValue) -> Result<(), Error> {
+        let name = Symbol::intern(name);
+
+        match &mut self.runtime {
+            Runtime::Tree(interpreter) => {
+                value.register(interpreter.heap_mut());
+                interpreter.globals_mut().define(name, value);
+            },
+
+            Runtime::Vm(vm) => {
+                let value = Value::from_lox(&value, vm.heap_mut()).map_err(Error::Unsupported)?;
+                vm.globals_mut().insert(name, value);
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Call a global function or class defined by the script.
+    ///
+    /// On the VM, only data can be passed as arguments or returned, as with
+    /// `register_fn`.
+    ///
+    /// ```
+    /// use loxide::{Engine, LoxValue};
+    ///
+    /// let mut engine = Engine::new();
+    /// engine.eval("fun on_event(name) { return \"handled \" + name; }").unwrap();
+    ///
+    /// let result = engine.call("on_event", &[LoxValue::from("click")]).unwrap();
+    /// assert_eq!(result, LoxValue::from("handled click"));
+    /// ```
+    pub fn call(&mut self, name: &str, args: &[LoxValue]) -> Result<LoxValue, Error> {
+        let undeclared = || Error::Host(RuntimeError::UndeclaredVar(name.to_owned()));
+        let symbol = Symbol::lookup(name).ok_or_else(undeclared)?;
+
+        let vm = match &mut self.runtime {
+            Runtime::Tree(interpreter) => {
+                let callee = interpreter.globals().find(symbol).cloned().ok_or_else(undeclared)?;
+                return self.call_value(&callee, args);
+            },
+
+            Runtime::Vm(vm) => vm,
+        };
+
+        let callee = vm.globals().get(&symbol).cloned().ok_or_else(undeclared)?;
+        let arity = callee.arity().ok_or(Error::Host(RuntimeError::NotCallable))?;
+        check_arity(arity, args.len())?;
+
+        // Native functions take Rust values to begin with
+        if let Value::Native(native) = &callee {
+            return native.call_native(args).map_err(Error::Host);
+        }
+
+        let args = args.iter()
+            .map(|arg| Value::from_lox(arg, vm.heap_mut()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::Unsupported)?;
+
+        let result = vm.call_from_host(callee, &args)?;
+        result.to_lox().map_err(Error::Unsupported)
+    }
+
+    /// Call a function, class or bound method that was handed over to Rust,
+    /// e.g., through `global`. Only the tree-walker hands those out.
+    pub fn call_value(&mut self, callee: &LoxValue, args: &[LoxValue]) -> Result<LoxValue, Error> {
+        let Runtime::Tree(interpreter) = &mut self.runtime else {
+            return Err(Error::Unsupported(callee.type_name()));
+        };
+
+        match callee {
+            LoxValue::Function(fun) => {
+                check_arity(fun.arity(), args.len())?;
+                let name = fun.name.as_ref().map(Token::name);
+                Ok(interpreter.call_from_host(name, fun.as_ref(), args)?)
+            },
+
+            LoxValue::Class(class) => {
+                check_arity(class.arity(), args.len())?;
+                Ok(interpreter.call_from_host(Some(Symbol::INIT), class, args)?)
+            },
+
+            // Errors from native functions don't happen anywhere in the
+            // source code, so there's nothing to point them at
+            LoxValue::NativeFunction(native) => {
+                check_arity(native.arity(), args.len())?;
+                native.call(interpreter, args, Span::default()).map_err(|err| Error::Host(err.value))
+            },
+
+            _ => Err(Error::Host(RuntimeError::NotCallable)),
+        }
+    }
+
+    /// Call a method on an instance from Rust. See `Instance::call_method`.
+    pub fn call_method(&mut self, instance: &Instance, name: &str, args: &[LoxValue]) -> Result<LoxValue, Error> {
+        let Runtime::Tree(interpreter) = &mut self.runtime else {
+            return Err(Error::Unsupported("instance"));
+        };
+
+        let undefined = || Error::Host(RuntimeError::UndefinedProperty(name.to_owned()));
+        let method = Symbol::lookup(name)
+            .and_then(|name| instance.property(name, interpreter.heap_mut()))
+            .ok_or_else(undefined)?;
+
+        self.call_value(&method, args)
+    }
+
+    /// The names of all globals, along with their values the way `print`
+    /// would show them, sorted by name.
+    pub fn globals(&self) -> Vec<(String, String)> {
+        let mut globals: Vec<_> = match &self.runtime {
+            Runtime::Tree(interpreter) => interpreter.globals()
+                .iter()
+                .map(|(name, value)| (name.to_owned(), value.to_string()))
+                .collect(),
+
+            Runtime::Vm(vm) => vm.globals()
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        };
+
+        globals.sort();
+        globals
+    }
+
+    fn execute(&mut self, code: &str, bare_expressions: bool) -> Result<(), Error> {
+        let (ast, resolutions) = self.analyze(code, bare_expressions)?;
+
+        match &mut self.runtime {
+            Runtime::Tree(interpreter) => {
+                interpreter.resolve(resolutions);
+                interpreter.visit(&ast)?;
+            },
+
+            Runtime::Vm(vm) => {
+                let script = Compiler::new().compile(&ast)?;
+
+                if self.dump_bytecode {
+                    let _ = write!(self.output, "{}", disassemble(&script, vm.source()));
+                }
+
+                vm.interpret(script)?;
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Scan, parse and resolve a chunk of code. Returns the syntax tree
+    /// together with the resolved variables.
+    fn analyze(&mut self, code: &str, bare_expressions: bool) -> Result<(Ast, Resolutions), Error> {
+        let offset = self.runtime.add_source(code);
+        let source = self.runtime.source();
+
+        let mut scanner = Scanner::new_at(source, offset);
+        let mut parser = Parser::new(source, &mut scanner).with_first_id(self.next_node_id);
+
+        if bare_expressions {
+            parser = parser.with_bare_expressions();
+        }
+
+        let parsed = parser.parse();
+        self.next_node_id = parser.next_id();
+
+        // Invalid tokens tend to trip up the parser, so only report the
+        // errors that caused the rest
+        if scanner.had_error() {
+            return Err(Error::Lex(scanner.take_errors()));
+        }
+
+        let ast = parsed.map_err(Error::Parse)?;
+
+        // Top-level declarations are globals, which are never tracked in the
+        // resolver's scopes, so every chunk can get a fresh resolver.
+        let mut resolver = Resolver::new();
+        let _ = resolver.visit(&ast);
+        let resolutions = resolver.resolutions().map_err(Error::Resolution)?;
+
+        Ok((ast, resolutions))
+    }
+}
+
+fn check_arity(arity: Arity, argc: usize) -> Result<(), Error> {
+    if arity.accepts(argc) {
+        Ok(())
+    } else {
+        Err(Error::Host(RuntimeError::ArityMismatch(arity, argc)))
+    }
+}
+
+/// Everything that can go wrong when running code in an `Engine`.
+#[derive(Debug)]
+pub enum Error {
+    /// The code contains characters or literals that aren't valid Lox
+    Lex(Vec<Spanned<LexError>>),
+
+    /// The code isn't syntactically valid
+    Parse(Vec<Spanned<ParseError>>),
+
+    /// The code misuses a variable or keyword (e.g., `return` outside of a
+    /// function)
+    Resolution(Vec<Spanned<ResolutionError>>),
+
+    /// The code exceeds one of the VM's limits
+    Compile(Spanned<CompileError>),
+
+    /// The code raised an error while running
+    Runtime(UncaughtError),
+
+    /// A call from Rust went wrong before it got to run any Lox code, e.g.,
+    /// because the callee isn't a function
+    Host(RuntimeError),
+
+    /// A bytecode file couldn't be loaded
+    Load(LoadError),
+
+    /// A script couldn't be read
+    Io(std::io::Error),
+
+    /// A value of the given type can't be passed between Rust and the VM
+    Unsupported(&'static str),
+}
+
+impl Error {
+    /// The runtime error, if that's what this is.
+    pub fn runtime_error(&self) -> Option<&RuntimeError> {
+        match self {
+            Error::Runtime(uncaught) => Some(&uncaught.error.value),
+            Error::Host(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Whether the code was rejected before it got to run.
+    pub fn is_static(&self) -> bool {
+        matches!(self, Error::Lex(_) | Error::Parse(_) | Error::Resolution(_) | Error::Compile(_) | Error::Load(_))
+    }
+
+    /// Format the error for the user, pointing out where in the source code
+    /// every error happened.
+    pub fn report(&self, source: &Source) -> String {
+        fn annotate_all<T: Display + Clone>(errors: &[Spanned<T>], source: &Source) -> String {
+            errors.iter()
+                .map(|error| source.annotate(error.clone()).to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+
+        match self {
+            Error::Lex(errors) => annotate_all(errors, source),
+            Error::Parse(errors) => annotate_all(errors, source),
+            Error::Resolution(errors) => annotate_all(errors, source),
+            Error::Compile(error) => source.annotate(error.clone()).to_string(),
+            Error::Runtime(uncaught) => uncaught.report(source),
+            _ => format!("[{RED}ERR{NORMAL}] {self}"),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn join<T: Display>(errors: &[Spanned<T>]) -> String {
+            errors.iter().map(|error| error.value.to_string()).collect::<Vec<_>>().join("; ")
+        }
+
+        match self {
+            Error::Lex(errors) => write!(f, "{}", join(errors)),
+            Error::Parse(errors) => write!(f, "{}", join(errors)),
+            Error::Resolution(errors) => write!(f, "{}", join(errors)),
+            Error::Compile(error) => write!(f, "{}", error.value),
+            Error::Runtime(uncaught) => write!(f, "{}", uncaught.error.value),
+            Error::Host(error) => write!(f, "{error}"),
+            Error::Load(err) => write!(f, "{err}"),
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Unsupported(type_name) => write!(f, "Values of type {type_name} can't be passed between Rust and the VM"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<UncaughtError> for Error {
+    fn from(error: UncaughtError) -> Self {
+        Error::Runtime(error)
+    }
+}
+
+impl From<Spanned<CompileError>> for Error {
+    fn from(error: Spanned<CompileError>) -> Self {
+        Error::Compile(error)
+    }
+}
+
+impl From<LoadError> for Error {
+    fn from(error: LoadError) -> Self {
+        Error::Load(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::output::SharedBuffer;
+
+    /// Run a check against a fresh session on each backend.
+    fn on_both_backends(check: impl Fn(Engine)) {
+        for backend in [Backend::Tree, Backend::Vm] {
+            check(Engine::new().with_backend(backend));
+        }
+    }
+
+    /// Run a program, expecting it to fail at runtime.
+    fn run(mut engine: Engine, program: &str) -> (UncaughtError, Source) {
+        match engine.eval(program) {
+            Err(Error::Runtime(error)) => (error, Source::new(program)),
+            _ => panic!("expected a runtime error on {:?}", engine.backend()),
+        }
+    }
+
+    /// The function names and line numbers of a stack trace.
+    fn lines((error, source): (UncaughtError, Source)) -> Vec<(String, usize)> {
+        error.trace.iter()
+            .map(|frame| (frame.function.clone(), source.map_span(frame.span).0))
+            .collect()
+    }
+
+    const FAILING: &str = "\
+fun fail(n) {
+  if (n == 0) return nil + 1;
+  return fail(n - 1);
+}
+class Point { init(x) { fail(x); } }
+var make = fun (x) { return Point(x); };
+make(2);
+";
+
+    const RECURSIVE: &str = "\
+fun recurse(n) {
+  return recurse(n + 1);
+}
+recurse(0);
+";
+
+    #[test]
+    fn stack_trace() {
+        let expected: Vec<_> = [("fail", 2), ("fail", 3), ("fail", 3), ("init", 5), ("<lambda>", 6), ("<script>", 7)]
+            .into_iter()
+            .map(|(function, line)| (function.to_owned(), line))
+            .collect();
+
+        on_both_backends(|engine| assert_eq!(lines(run(engine, FAILING)), expected));
+    }
+
+    #[test]
+    fn stack_trace_in_a_later_chunk() {
+        on_both_backends(|mut engine| {
+            engine.eval("var a = 1;\nvar b = 2;\n").unwrap();
+
+            let Err(Error::Runtime(error)) = engine.eval(FAILING) else {
+                panic!("expected a runtime error on {:?}", engine.backend());
+            };
+
+            let lines: Vec<_> = error.trace.iter()
+                .map(|frame| engine.source().map_span(frame.span).0)
+                .collect();
+
+            assert_eq!(lines, [2, 3, 3, 5, 6, 7]);
+        });
+    }
+
+    #[test]
+    fn stack_overflow() {
+        on_both_backends(|engine| {
+            let (error, _) = run(engine.with_max_call_depth(50), RECURSIVE);
+            assert!(matches!(error.error.value, RuntimeError::StackOverflow));
+            assert_eq!(error.trace.len(), 51);
+        });
+    }
+
+    #[test]
+    fn deeply_nested_recursion() {
+        // Every call nests a long chain of negations, which takes far more
+        // native stack than a plain call, especially in debug builds
+        let program = format!("fun f(n) {{ return {}f(n + 1); }}\nf(0);", "-".repeat(40));
+
+        on_both_backends(|engine| {
+            let (error, _) = run(engine, &program);
+            assert!(matches!(error.error.value, RuntimeError::StackOverflow));
+        });
+    }
+
+    #[test]
+    fn recursion_on_a_small_stack() {
+        // Embedders don't have to set aside any stack for the call depth
+        // limit, even for heavy function bodies
+        let program = "\
+fun A(n) {
+  { { for (var i = 0; i < 1; i = i + 1) { var s = \"${n} ${-(-n)}\"; { return A(n - 1) + 1; } } } }
+}
+A(0);";
+
+        let session = std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || on_both_backends(|engine| {
+                let (error, _) = run(engine, program);
+                assert!(matches!(error.error.value, RuntimeError::StackOverflow));
+            }))
+            .unwrap();
+
+        session.join().unwrap();
+    }
+
+    /// Run a program, expecting it to succeed, and return what it printed.
+    fn output(engine: Engine, program: &str) -> String {
+        let output = SharedBuffer::new();
+        let mut engine = engine.with_output(output.clone());
+        engine.eval(program).unwrap();
+        output.take()
+    }
+
+    #[test]
+    fn list_containing_itself() {
+        on_both_backends(|engine| {
+            let program = "var xs = [1]; xs.push(xs); print xs; print [xs, xs];";
+            assert_eq!(output(engine, program), "[1, [...]]\n[[1, [...]], [1, [...]]]\n");
+        });
+    }
+
+    #[test]
+    fn deeply_nested_collections() {
+        // Printing stops at a limited depth, and the lists and maps get freed
+        // (when they're overwritten and when the session goes away) without
+        // recursing through all of them
+        on_both_backends(|engine| {
+            let program = "
+                var xs = [];
+                var m = {};
+
+                for (var i = 0; i < 50000; i = i + 1) {
+                    xs = [xs];
+                    m = {\"m\": m};
+                }
+
+                print xs;
+                xs = nil;
+            ";
+
+            let depth = crate::util::MAX_NESTING;
+            let expected = format!("{}[...]{}\n", "[".repeat(depth), "]".repeat(depth));
+            assert_eq!(output(engine, program), expected);
+        });
+    }
+
+    #[test]
+    fn map_containing_itself() {
+        on_both_backends(|engine| {
+            let program = r#"var m = {}; m["self"] = m; m["xs"] = [m]; print m;"#;
+            assert_eq!(output(engine, program), "{\"self\": {...}, \"xs\": [{...}]}\n");
+        });
+    }
+
+    #[test]
+    fn runtime_keys_dont_become_symbols() {
+        on_both_backends(|engine| {
+            let program = r#"
+                var m = {};
+                var key = "k";
+                var i = 0;
+
+                while (i < 1000) {
+                    key = key + "x";
+                    m[key] = i;
+                    m.has(key + "?");
+                    i = i + 1;
+                }
+
+                print m[key];
+            "#;
+
+            let before = Symbol::count();
+            assert_eq!(output(engine, program), "999\n");
+            assert!(Symbol::count() - before < 100, "{} names got interned", Symbol::count() - before);
+        });
+    }
+
+    #[test]
+    fn host_lookups_dont_intern() {
+        on_both_backends(|mut engine| {
+            let before = Symbol::count();
+
+            assert_eq!(engine.global("no such global").unwrap(), None);
+            assert!(matches!(engine.call("no such function", &[]), Err(Error::Host(RuntimeError::UndeclaredVar(_)))));
+            assert_eq!(Symbol::count(), before);
+        });
+    }
+
+    #[test]
+    fn declarations_as_bodies() {
+        // A function or class declared as the body of an if or a loop belongs
+        // to the surrounding scope, whether or not the body runs
+        let program = r#"
+            fun skipped() { var a = "a"; if (false) fun s() {} var c = "c"; return a + c; }
+            print skipped();
+
+            fun skipped_class() { var a = "a"; if (false) class K {} var c = "c"; return a + c; }
+            print skipped_class();
+
+            { if (false) fun f() {} var c = 3; print c; }
+
+            fun repeated() { var i = 0; while ((i = i + 1) < 3) fun q() { return i; } var z = 10; return z + q(); }
+            print repeated();
+
+            fun looped() { for (var i = 0; i < 2; i = i + 1) fun q() {} var z = "z"; return z + "!"; }
+            print looped();
+
+            fun taken() { if (true) fun r(n) { if (n == 0) return "done"; return r(n - 1); } return r(3); }
+            print taken();
+        "#;
+
+        on_both_backends(|engine| assert_eq!(output(engine, program), "ac\nac\n3\n13\nz!\ndone\n"));
+    }
+
+    #[test]
+    fn examples_run_the_same_on_both_backends() {
+        let examples = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let mut paths: Vec<_> = std::fs::read_dir(examples).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+            // The benchmark prints how long it took
+            .filter(|path| !path.ends_with("fib-bench.lox"))
+            .collect();
+
+        paths.sort();
+        assert!(!paths.is_empty());
+
+        for path in paths {
+            let outputs: Vec<_> = [Backend::Tree, Backend::Vm].into_iter()
+                .map(|backend| {
+                    let (output, diagnostics) = (SharedBuffer::new(), SharedBuffer::new());
+                    let mut engine = Engine::new()
+                        .with_backend(backend)
+                        .with_output(output.clone())
+                        .with_diagnostics(diagnostics.clone());
+
+                    if let Err(error) = engine.run_file(&path) {
+                        engine.report_error(&error);
+                    }
+
+                    (output.take(), diagnostics.take())
+                })
+                .collect();
+
+            let (output, diagnostics) = &outputs[0];
+            assert!(!output.is_empty() || !diagnostics.is_empty(), "{} printed nothing", path.display());
+            assert_eq!(outputs[0], outputs[1], "{} runs differently on the VM", path.display());
+        }
+    }
+
+    #[test]
+    fn native_functions() {
+        on_both_backends(|mut engine| {
+            engine.register_fn("sum", Arity::AtLeast(1), |args| {
+                args.iter().map(LoxValue::as_num).sum::<Result<f64, _>>().map(LoxValue::Num)
+            });
+
+            engine.eval("var total = sum(1, 2, 3);").unwrap();
+            assert_eq!(engine.global("total").unwrap(), Some(LoxValue::Num(6.0)));
+
+            let error = engine.eval("sum();").unwrap_err();
+            assert!(matches!(error.runtime_error(), Some(RuntimeError::ArityMismatch(Arity::AtLeast(1), 0))));
+
+            // Errors get reported at the call
+            let (error, source) = run(engine, "var x = 1;\nsum(x, \"two\");");
+            assert!(matches!(error.error.value, RuntimeError::ArgumentType("a number", "string")));
+            assert_eq!(source.map_span(error.error.span).0, 2);
+        });
+    }
+
+    #[test]
+    fn natives_survive_a_reset() {
+        on_both_backends(|mut engine| {
+            engine.register_fn("answer", 0, |_| Ok(LoxValue::Num(42.0)));
+            engine.reset();
+
+            engine.eval("var answer = answer();").unwrap();
+            assert_eq!(engine.global("answer").unwrap(), Some(LoxValue::Num(42.0)));
+        });
+    }
+
+    #[test]
+    fn collections_cross_over() {
+        on_both_backends(|mut engine| {
+            engine.register_fn("total", 1, |args| {
+                let values: Vec<f64> = args[0].clone().try_into()?;
+                Ok(LoxValue::from(HashMap::from([("total", values.iter().sum::<f64>())])))
+            });
+
+            engine.set_global("xs", LoxValue::from(vec![1, 2, 3])).unwrap();
+            engine.eval("var sum = total(xs)[\"total\"]; var ys = [xs, xs]; ys.push(ys);").unwrap();
+            assert_eq!(engine.global("sum").unwrap(), Some(LoxValue::Num(6.0)));
+
+            let Some(LoxValue::List(ys)) = engine.global("ys").unwrap() else {
+                panic!("expected a list on {:?}", engine.backend());
+            };
+
+            // Collections that show up more than once stay shared
+            let values = ys.0.borrow();
+            assert!(matches!((&values[0], &values[1]), (LoxValue::List(a), LoxValue::List(b)) if Rc::ptr_eq(&a.0, &b.0)));
+            assert!(matches!(&values[2], LoxValue::List(list) if Rc::ptr_eq(&list.0, &ys.0)));
+        });
+    }
+
+    #[test]
+    fn cycles_through_rust_collections_get_collected() {
+        on_both_backends(|engine| {
+            let mut engine = engine.with_gc(GcConfig { stress: true, ..GcConfig::default() });
+            let list = LoxValue::from(vec![1, 2]);
+            let LoxValue::List(inner) = &list else { unreachable!() };
+            let global = Rc::downgrade(&inner.0);
+
+            let made = Rc::new(RefCell::new(Vec::new()));
+            let handle = made.clone();
+
+            engine.register_fn("make", 0, move |_| {
+                let map = LoxValue::from(HashMap::from([("answer", 42)]));
+                let LoxValue::Map(inner) = &map else { unreachable!() };
+                handle.borrow_mut().push(Rc::downgrade(&inner.0));
+                Ok(map)
+            });
+
+            engine.set_global("xs", list).unwrap();
+            engine.eval(r#"xs.push(xs); xs = nil; var m = make(); m["self"] = m; m = nil;"#).unwrap();
+
+            // Collections happen on allocations
+            engine.eval("var ys = [1];").unwrap();
+            assert!(global.upgrade().is_none());
+            assert!(made.borrow().iter().all(|map| map.upgrade().is_none()));
+        });
+    }
+
+    #[test]
+    fn globals() {
+        on_both_backends(|mut engine| {
+            engine.eval("var answer = 42; fun f() {}").unwrap();
+            assert_eq!(engine.global("answer").unwrap(), Some(LoxValue::Num(42.0)));
+            assert_eq!(engine.global("question").unwrap(), None);
+
+            match engine.backend() {
+                Backend::Tree => assert!(matches!(engine.global("f"), Ok(Some(LoxValue::Function(_))))),
+                Backend::Vm => assert!(matches!(engine.global("f"), Err(Error::Unsupported("function")))),
+            }
+        });
+    }
+
+    const HANDLERS: &str = "\
+fun on_event(name) {
+  if (name == \"crash\") return nil + 1;
+  return \"handled \" + name;
+}
+";
+
+    #[test]
+    fn host_calls() {
+        on_both_backends(|mut engine| {
+            engine.eval(HANDLERS).unwrap();
+
+            let result = engine.call("on_event", &[LoxValue::from("click")]).unwrap();
+            assert_eq!(result, LoxValue::from("handled click"));
+
+            let error = engine.call("on_event", &[]).unwrap_err();
+            assert!(matches!(error, Error::Host(RuntimeError::ArityMismatch(Arity::Exactly(1), 0))));
+
+            let error = engine.call("missing", &[]).unwrap_err();
+            assert!(matches!(error, Error::Host(RuntimeError::UndeclaredVar(_))));
+
+            // The stack trace ends at the function that got called from Rust
+            let Err(Error::Runtime(error)) = engine.call("on_event", &[LoxValue::from("crash")]) else {
+                panic!("expected a runtime error");
+            };
+
+            assert_eq!(lines((error, Source::new(HANDLERS))), [(String::from("on_event"), 2)]);
+
+            // The session is still usable afterwards
+            assert!(engine.call("on_event", &[LoxValue::from("tap")]).is_ok());
+        });
+    }
+
+    #[test]
+    fn call_method() {
+        let mut engine = Engine::new();
+        engine.eval("class Counter { init() { this.count = 0; } add(n) { this.count = this.count + n; return this.count; } }").unwrap();
+
+        let Ok(LoxValue::Instance(counter)) = engine.call("Counter", &[]) else {
+            panic!("expected an instance");
+        };
+
+        counter.call_method(&mut engine, "add", &[LoxValue::Num(2.0)]).unwrap();
+        let count = counter.call_method(&mut engine, "add", &[LoxValue::Num(3.0)]).unwrap();
+        assert_eq!(count, LoxValue::Num(5.0));
+
+        let error = counter.call_method(&mut engine, "reset", &[]).unwrap_err();
+        assert!(matches!(error, Error::Host(RuntimeError::UndefinedProperty(_))));
+    }
+
+    #[test]
+    fn captured_output() {
+        on_both_backends(|engine| {
+            let output = SharedBuffer::new();
+            let diagnostics = SharedBuffer::new();
+            let mut engine = engine.with_output(output.clone()).with_diagnostics(diagnostics.clone());
+
+            let error = engine.eval("print \"one\";\nprint 1 + 1;\nprint nil + 1;").unwrap_err();
+            engine.report_error(&error);
+
+            assert_eq!(output.take(), "one\n2\n");
+            assert!(diagnostics.take().contains("at <script> (line 3)"));
+
+            // The sinks survive a reset
+            engine.reset();
+            engine.eval("print true;").unwrap();
+            assert_eq!(output.take(), "true\n");
+        });
+    }
+
+    #[test]
+    fn bytecode_needs_the_vm() {
+        let bytecode = Engine::new().compile("print x;").unwrap();
+
+        let mut engine = Engine::new().with_backend(Backend::Tree);
+        engine.eval("var x = 1;").unwrap();
+
+        let error = engine.run_bytecode(&bytecode).unwrap_err();
+        assert!(matches!(error, Error::Load(LoadError::RequiresVm)));
+        assert_eq!(engine.backend(), Backend::Tree);
+        assert_eq!(engine.global("x").unwrap(), Some(LoxValue::Num(1.0)));
+
+        let output = SharedBuffer::new();
+        let mut engine = Engine::new().with_backend(Backend::Vm).with_output(output.clone());
+        engine.eval("var x = 2;").unwrap();
+        engine.run_bytecode(&bytecode).unwrap();
+        assert_eq!(output.take(), "2\n");
+    }
+
+    #[test]
+    fn break_and_continue() {
+        // `continue` in a `for` loop still runs the increment, rather than
+        // looping forever
+        let program = "
+            for (var i = 0; i < 5; i = i + 1) {
+                if (i == 2) continue;
+                print i;
+            }
+
+            var j = 0;
+
+            while (true) {
+                j = j + 1;
+                if (j == 2) continue;
+                if (j == 4) break;
+                print j;
+            }
+
+            for (var i = 0; i < 2; i = i + 1) {
+                for (var k = 0; k < 3; k = k + 1) {
+                    if (k == 1) continue;
+                    print \"${i} ${k}\";
+                }
+            }
+        ";
+
+        on_both_backends(|engine| {
+            assert_eq!(output(engine, program), "0\n1\n3\n4\n1\n3\n0 0\n0 2\n1 0\n1 2\n");
+        });
+
+        on_both_backends(|mut engine| {
+            assert!(matches!(engine.eval("fun f() { break; }"), Err(Error::Resolution(_))));
+            assert!(matches!(engine.eval("continue;"), Err(Error::Resolution(_))));
+        });
+    }
+
+    #[test]
+    fn index_errors() {
+        // Errors point at the opening bracket of the index
+        let cases = [
+            ("print xs[2];", "Index 2 is out of bounds for list of length 2", 8),
+            ("xs[-1] = 3;", "Index -1 is out of bounds for list of length 2", 2),
+            ("print xs[0.5];", "Index must be a whole number, but found 0.5", 8),
+            ("xs[1.5] = 3;", "Index must be a whole number, but found 1.5", 2),
+            ("print xs[\"a\"];", "Operand must be number", 8),
+        ];
+
+        for (line, message, column) in cases {
+            let program = format!("var xs = [1, 2];\n{line}");
+
+            on_both_backends(|engine| {
+                let (error, source) = run(engine, &program);
+                assert_eq!(error.error.value.to_string(), message);
+                assert_eq!(source.map_span(error.error.span), (2, column, line));
+            });
+        }
+    }
+
+    #[test]
+    fn super_calls() {
+        // `super` looks up methods from the class the method was declared in,
+        // while `this` stays the instance the method was called on
+        let program = "
+            class A {
+                hi() { return \"A.hi\"; }
+                name() { return \"A\"; }
+            }
+
+            class B < A {
+                hi() { return \"B>\" + super.hi() + \" \" + this.name(); }
+                name() { return \"B\"; }
+            }
+
+            class C < B {
+                hi() { return \"C>\" + super.hi(); }
+                name() { return \"C\"; }
+                bound() { return super.name; }
+            }
+
+            print C().hi();
+            print C().bound()();
+        ";
+
+        on_both_backends(|engine| assert_eq!(output(engine, program), "C>B>A.hi C\nB\n"));
+    }
+
+    #[test]
+    fn super_errors() {
+        on_both_backends(|mut engine| {
+            for program in ["class A < A {}", "super.hi();", "class A { hi() { return super.hi(); } }"] {
+                assert!(matches!(engine.eval(program), Err(Error::Resolution(_))), "{program}");
+            }
+        });
+
+        on_both_backends(|engine| {
+            let (error, _) = run(engine, "var N = 1;\nclass A < N {}");
+            assert!(matches!(error.error.value, RuntimeError::SuperclassNotClass));
+        });
+
+        on_both_backends(|engine| {
+            let (error, _) = run(engine, "class A {}\nclass B < A { hi() { return super.hi(); } }\nB().hi();");
+            assert!(matches!(&error.error.value, RuntimeError::UndefinedProperty(name) if name == "hi"));
+        });
+    }
+
+    #[test]
+    fn initializers() {
+        // An early `return;` still hands back the instance, and so does
+        // calling `init` again
+        let program = "
+            class Point {
+                init(x, y) {
+                    this.x = x;
+                    this.y = y;
+                    if (x > 0) return;
+                    this.y = -y;
+                }
+            }
+
+            var p = Point(1, 2);
+            print p.y;
+            print Point(0, 2).y;
+            print p.init(0, 3) == p;
+            print p.y;
+        ";
+
+        on_both_backends(|engine| assert_eq!(output(engine, program), "2\n-2\ntrue\n-3\n"));
+    }
+
+    #[test]
+    fn initializer_errors() {
+        on_both_backends(|engine| {
+            let (error, _) = run(engine, "class Point { init(x, y) {} }\nPoint(1);");
+            assert!(matches!(error.error.value, RuntimeError::ArityMismatch(_, 1)));
+        });
+
+        on_both_backends(|engine| {
+            let (error, _) = run(engine, "class Empty {}\nEmpty(1);");
+            assert!(matches!(error.error.value, RuntimeError::ArityMismatch(_, 1)));
+        });
+
+        on_both_backends(|mut engine| {
+            let result = engine.eval("class Point { init() { return 1; } }");
+            assert!(matches!(result, Err(Error::Resolution(_))));
+        });
+    }
+
+    #[test]
+    fn local_slots() {
+        // Declarations that never run still get their slot, so the locals
+        // declared after them don't get mixed up
+        let program = "
+            {
+                var a = 1;
+                if (false) fun f() {}
+                while (false) class K {}
+                var b = 2;
+                print a + b;
+            }
+
+            {
+                var s = \"outer\";
+                { var s = \"inner\"; print s; }
+                print s;
+            }
+
+            fun outer() {
+                var x = \"x\";
+                fun inner() { return x; }
+                var y = \"y\";
+                return inner() + y;
+            }
+
+            print outer();
+
+            fun late() { return g; }
+            var g = \"late\";
+            print late();
+        ";
+
+        on_both_backends(|engine| {
+            assert_eq!(output(engine, program), "3\ninner\nouter\nxy\nlate\n");
+        });
+    }
+}
@@ -0,0 +1,331 @@
+//! A cycle collector for reference-counted values.
+//!
+//! Both backends store objects like instances, closures and lists behind
+//! `Rc`s, which frees them as soon as the last reference goes away, but never
+//! frees objects that refer to each other in a cycle (an instance holding a
+//! method bound to itself, or a closure stored in the scope it closes over).
+//!
+//! Every such object gets registered with a `Heap` when it is created. When
+//! enough objects were allocated, the heap looks for cycles that nothing else
+//! refers to:
+//!
+//! 1. For every object, count its strong references, minus the ones held by
+//!    other registered objects. Whatever is left comes from outside of the
+//!    heap: the stack, environments, or Rust code that is working with the
+//!    object. These objects are the roots.
+//! 2. Mark everything that can be reached from the roots.
+//! 3. Clear every object that wasn't marked, which drops its references to
+//!    other objects. That breaks the cycles, so reference counting frees them.
+//!
+//! Since the roots are found by counting, a collection can safely run at any
+//! point, without the backends having to enumerate their roots.
+
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+/// An object that can hold references to other objects on the heap.
+pub trait Trace {
+    /// Report every registered object this object holds a strong reference
+    /// to. Every reference must be reported once: reporting a reference that
+    /// the object doesn't own could get a live object cleared.
+    fn trace(&self, tracer: &mut Tracer);
+
+    /// Drop the object's references to other objects. Only called on
+    /// unreachable objects, so there's no need to leave them usable.
+    ///
+    /// Every cycle runs through something mutable, so objects that can't be
+    /// modified don't need to do anything.
+    fn clear(&self) {}
+}
+
+/// Collects the references reported by `Trace::trace`.
+#[derive(Debug, Default)]
+pub struct Tracer {
+    edges: Vec<*const ()>,
+    busy: bool,
+}
+
+impl Tracer {
+    /// Report a reference to an object.
+    pub fn edge<T: ?Sized>(&mut self, object: &Rc<T>) {
+        self.edges.push(Rc::as_ptr(object) as *const ());
+    }
+
+    /// Report that the object is in use (e.g., it's mutably borrowed), so
+    /// its references can't be inspected. The object and everything it refers
+    /// to is kept alive.
+    pub fn busy(&mut self) {
+        self.busy = true;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GcConfig {
+    /// The number of objects on the heap before the first collection, and
+    /// the minimum for every collection after that
+    pub threshold: usize,
+
+    /// After a collection, the next one happens once the number of live
+    /// objects has grown by this factor
+    pub growth_factor: usize,
+
+    /// Collect on every allocation, to shake out bugs in the collector
+    pub stress: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self { threshold: 10_000, growth_factor: 2, stress: false }
+    }
+}
+
+/// The registry of all objects that can be part of a cycle.
+pub struct Heap {
+    config: GcConfig,
+
+    /// Every registered object, by address. Holding on to a `Weak` keeps the
+    /// allocation around, so no other object can show up at the same address
+    /// until the entry gets pruned.
+    objects: HashMap<*const (), Weak<dyn Trace>>,
+
+    /// The number of registered objects that triggers the next collection
+    next_collection: usize,
+
+    collections: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new(GcConfig::default())
+    }
+}
+
+impl Heap {
+    pub fn new(config: GcConfig) -> Self {
+        Self {
+            config,
+            objects: HashMap::new(),
+            next_collection: config.threshold,
+            collections: 0,
+        }
+    }
+
+    pub fn config(&self) -> GcConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: GcConfig) {
+        self.config = config;
+        self.next_collection = config.threshold;
+    }
+
+    /// The number of collections so far.
+    pub fn collections(&self) -> usize {
+        self.collections
+    }
+
+    /// The number of registered objects that are still alive.
+    pub fn live(&self) -> usize {
+        self.objects.values().filter(|object| object.strong_count() > 0).count()
+    }
+
+    /// Move a value into a new `Rc`, and register it.
+    pub fn alloc<T: Trace + 'static>(&mut self, value: T) -> Rc<T> {
+        let object = Rc::new(value);
+        self.track(&object);
+        object
+    }
+
+    /// Whether an object is registered.
+    pub fn is_tracked<T: ?Sized>(&self, object: &Rc<T>) -> bool {
+        self.objects
+            .get(&(Rc::as_ptr(object) as *const ()))
+            .is_some_and(|object| object.strong_count() > 0)
+    }
+
+    /// Register a newly created object, and collect garbage if enough objects
+    /// were allocated since the last collection. Registering an object more
+    /// than once has no effect.
+    pub fn track<T: Trace + 'static>(&mut self, object: &Rc<T>) {
+        let ptr = Rc::as_ptr(object) as *const ();
+        let object: Weak<T> = Rc::downgrade(object);
+        self.objects.insert(ptr, object);
+
+        if self.config.stress || self.objects.len() >= self.next_collection {
+            self.collect();
+        }
+    }
+
+    /// Free all unreachable cycles, and return the number of objects that
+    /// were cleared.
+    pub fn collect(&mut self) -> usize {
+        let objects: Vec<Rc<dyn Trace>> = self.objects.values().filter_map(Weak::upgrade).collect();
+
+        let index: HashMap<*const (), usize> = objects.iter()
+            .enumerate()
+            .map(|(idx, object)| (Rc::as_ptr(object) as *const (), idx))
+            .collect();
+
+        // Count the references from outside the heap, not counting the ones
+        // we just took ourselves
+        let mut external: Vec<usize> = objects.iter()
+            .map(|object| Rc::strong_count(object) - 1)
+            .collect();
+
+        let mut edges = Vec::with_capacity(objects.len());
+        let mut marked = vec![false; objects.len()];
+        let mut pending = Vec::new();
+
+        for (idx, object) in objects.iter().enumerate() {
+            let mut tracer = Tracer::default();
+            object.trace(&mut tracer);
+
+            let targets: Vec<usize> = tracer.edges.iter()
+                .filter_map(|edge| index.get(edge).copied())
+                .collect();
+
+            for &target in &targets {
+                external[target] = external[target].saturating_sub(1);
+            }
+
+            if tracer.busy {
+                pending.push(idx);
+            }
+
+            edges.push(targets);
+        }
+
+        // Mark everything that's reachable from the roots
+        pending.extend((0..objects.len()).filter(|&idx| external[idx] > 0));
+
+        while let Some(idx) = pending.pop() {
+            if marked[idx] {
+                continue;
+            }
+
+            marked[idx] = true;
+            pending.extend(edges[idx].iter().filter(|&&target| !marked[target]));
+        }
+
+        let mut cleared = 0;
+
+        for (object, &marked) in objects.iter().zip(&marked) {
+            if !marked {
+                object.clear();
+                cleared += 1;
+            }
+        }
+
+        self.objects = objects.iter()
+            .zip(&marked)
+            .filter(|(_, &marked)| marked)
+            .map(|(object, _)| (Rc::as_ptr(object) as *const (), Rc::downgrade(object)))
+            .collect();
+
+        let live = self.objects.len();
+        self.next_collection = self.config.threshold.max(live * self.config.growth_factor);
+        self.collections += 1;
+
+        // Dropping our own references frees the cleared objects
+        drop(objects);
+
+        cleared
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Node(RefCell<Vec<Rc<Node>>>);
+
+    impl Trace for Node {
+        fn trace(&self, tracer: &mut Tracer) {
+            let Ok(children) = self.0.try_borrow() else {
+                return tracer.busy();
+            };
+
+            for child in children.iter() {
+                tracer.edge(child);
+            }
+        }
+
+        fn clear(&self) {
+            let children = std::mem::take(&mut *self.0.borrow_mut());
+            drop(children);
+        }
+    }
+
+    fn node(heap: &mut Heap) -> Rc<Node> {
+        let node = Rc::new(Node::default());
+        heap.track(&node);
+        node
+    }
+
+    #[test]
+    fn collects_unreachable_cycles() {
+        let mut heap = Heap::default();
+        let a = node(&mut heap);
+        let b = node(&mut heap);
+        a.0.borrow_mut().push(b.clone());
+        b.0.borrow_mut().push(a.clone());
+
+        let weak = Rc::downgrade(&a);
+
+        // Still referenced from out here
+        assert_eq!(heap.collect(), 0);
+
+        drop((a, b));
+        assert_eq!(heap.collect(), 2);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(heap.live(), 0);
+    }
+
+    #[test]
+    fn keeps_everything_reachable_from_roots() {
+        let mut heap = Heap::default();
+        let root = node(&mut heap);
+        let a = node(&mut heap);
+        let b = node(&mut heap);
+
+        // root -> a <-> b, and a refers to itself
+        root.0.borrow_mut().push(a.clone());
+        a.0.borrow_mut().push(a.clone());
+        a.0.borrow_mut().push(b.clone());
+        b.0.borrow_mut().push(a.clone());
+        drop((a, b));
+
+        assert_eq!(heap.collect(), 0);
+        assert_eq!(heap.live(), 3);
+
+        root.0.borrow_mut().clear();
+        assert_eq!(heap.collect(), 2);
+        assert_eq!(heap.live(), 1);
+    }
+
+    #[test]
+    fn tracking_twice_has_no_effect() {
+        let mut heap = Heap::default();
+        let a = node(&mut heap);
+        assert!(heap.is_tracked(&a));
+
+        heap.track(&a);
+        a.0.borrow_mut().push(a.clone());
+        assert_eq!(heap.live(), 1);
+
+        drop(a);
+        assert_eq!(heap.collect(), 1);
+        assert!(!heap.is_tracked(&Rc::new(Node::default())));
+    }
+
+    #[test]
+    fn stress_mode_collects_on_every_allocation() {
+        let mut heap = Heap::new(GcConfig { stress: true, ..GcConfig::default() });
+        node(&mut heap);
+        node(&mut heap);
+        assert_eq!(heap.collections(), 2);
+    }
+}
@@ -1,83 +1,309 @@
 #![allow(dead_code)]
 use value::LoxValue;
-use environment::Env;
+use environment::{Binding, Env, Globals};
+use resolver::{Resolution, Resolutions};
+use functions::{Arity, Call};
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::rc::Rc;
 
+use crate::gc::{GcConfig, Heap};
+use crate::output::Sink;
 use crate::sourcemap::Source;
 use crate::syntax::ast::Ast;
-use crate::syntax::ast::Expr;
-use crate::span::Spanned;
+use crate::syntax::ast::NodeId;
+use crate::symbol::Symbol;
+use crate::syntax::tokens::Token;
+use crate::span::{Span, Spanned};
 
 mod expr;
 mod stmt;
-mod functions;
+pub mod functions;
 mod environment;
-mod class;
+pub mod class;
+mod convert;
+pub mod list;
+pub mod map;
 pub mod resolver;
 pub mod value;
 
 type Result<T> = std::result::Result<T, Spanned<RuntimeError>>;
 type LoxResult = std::result::Result<LoxValue, Spanned<RuntimeError>>;
+type ExecResult = std::result::Result<(), Unwind>;
+
+/// The default for the number of nested function calls, on both backends.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+/// The tree-walker recurses on the native stack for every call and every
+/// nested expression or statement, which can take well over 50KB per Lox call
+/// in debug builds. Rather than relying on whoever runs the code to provide
+/// enough stack, it switches to a fresh segment of this size whenever less
+/// than `STACK_RED_ZONE` is left, so only the call depth limit applies.
+const STACK_SEGMENT_SIZE: usize = 1024 * 1024;
+
+/// The most stack a single step of the tree-walker (between two nested
+/// statements or expressions) can take.
+const STACK_RED_ZONE: usize = 128 * 1024;
 
 pub trait Visitor<T> {
     type Output;
     fn visit(&mut self, node: T) -> Self::Output;
 }
 
-pub struct Interpreter<'a> {
-    source: &'a Source<'a>,
+/// A long-lived interpreter session.
+///
+/// The interpreter owns all the source code it was handed, so globals,
+/// functions and classes defined by earlier chunks of code (and the spans
+/// that point into them) stay around while new chunks get executed.
+pub struct Interpreter {
+    source: Source,
     pub env: Rc<Env>,
-    globals: Rc<Env>,
-    locals: HashMap<&'a Expr, usize>,
+    globals: Globals,
+    locals: HashMap<NodeId, Binding>,
+
+    /// The slots of variables declared in local scopes
+    declarations: HashMap<NodeId, usize>,
+
+    heap: Heap,
+
+    /// The functions that are currently being called, outermost first
+    calls: Vec<ActiveCall>,
+
+    /// The stack trace for the error that is currently unwinding, captured
+    /// before the calls it passes through get popped
+    trace: Option<Vec<StackFrame>>,
+
+    /// The maximum number of nested function calls
+    max_call_depth: usize,
+
+    /// Where `print` writes to
+    output: Sink,
+}
+
+/// A call to a Lox function that is in progress.
+struct ActiveCall {
+    /// The name of the function, or `None` for anonymous functions
+    function: Option<Symbol>,
+
+    /// Where the function got called from, or `None` if it got called from
+    /// Rust
+    call_site: Option<Span>,
 }
 
-impl<'a> Interpreter<'a> {
-    pub fn new(source: &'a Source<'a>, locals: HashMap<&'a Expr, usize>) -> Self {
-        let globals = Rc::new(Env::global());
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
+impl Interpreter {
+    pub fn new() -> Self {
         Self {
-            source,
-            env: globals.clone(),
-            globals,
-            locals,
+            source: Source::default(),
+            env: Rc::new(Env::default()),
+            globals: Globals::default(),
+            locals: HashMap::new(),
+            declarations: HashMap::new(),
+            heap: Heap::default(),
+            calls: Vec::new(),
+            trace: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            output: Sink::stdout(),
         }
     }
 
+    pub fn with_output(mut self, output: Sink) -> Self {
+        self.output = output;
+        self
+    }
+
+    pub fn with_gc(mut self, config: GcConfig) -> Self {
+        self.heap.set_config(config);
+        self
+    }
+
+    /// Limit the number of nested function calls. Going any deeper raises a
+    /// `RuntimeError::StackOverflow`.
+    pub fn with_max_call_depth(mut self, depth: usize) -> Self {
+        self.max_call_depth = depth;
+        self
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    pub fn globals(&self) -> &Globals {
+        &self.globals
+    }
+
+    pub fn globals_mut(&mut self) -> &mut Globals {
+        &mut self.globals
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    /// Append a new chunk of code to the session's source, returning the
+    /// offset at which it starts.
+    pub fn add_source(&mut self, chunk: &str) -> usize {
+        self.source.push(chunk)
+    }
+
     pub fn push_scope(&mut self) {
-        let new_scope =  Env::new(self.env.clone());
-        self.env = Rc::new(new_scope);
+        let new_scope = Env::new(self.env.clone());
+        self.env = self.heap.alloc(new_scope);
     }
 
     pub fn pop_scope(&mut self) {
         self.env = self.env.parent.clone().unwrap();
     }
 
-    pub fn resolve(&mut self, expr: &'a Expr, depth: usize) {
-        self.locals.insert(expr, depth);
+    /// Take in the resolver's results for a chunk of code that is about to
+    /// run. Globals get their index looked up the first time they're used.
+    pub fn resolve(&mut self, resolutions: Resolutions) {
+        for (id, resolution) in resolutions.variables {
+            if let Resolution::Local { depth, slot } = resolution {
+                self.locals.insert(id, Binding::Local { depth, slot });
+            }
+        }
+
+        self.declarations.extend(resolutions.declarations);
+    }
+
+    /// Define the variable declared by node `id`, either in its slot in the
+    /// current scope, or as a global.
+    pub fn define(&mut self, id: NodeId, name: &Token, value: LoxValue) {
+        match self.declarations.get(&id) {
+            Some(&slot) => self.env.define(slot, value),
+            None => self.globals.define(name.name(), value),
+        }
+    }
+
+    /// Write a line of program output.
+    pub fn print(&self, value: &LoxValue, span: Span) -> Result<()> {
+        writeln!(self.output, "{value}").map_err(|err| Spanned { value: RuntimeError::Output(err.to_string()), span })
+    }
+
+    /// Run a call to a Lox function, keeping track of it on the call stack.
+    pub fn traced_call(
+        &mut self,
+        function: Option<Symbol>,
+        call_site: Span,
+        call: impl FnOnce(&mut Self) -> LoxResult,
+    ) -> LoxResult {
+        if self.calls.len() >= self.max_call_depth {
+            return Err(Spanned { value: RuntimeError::StackOverflow, span: call_site });
+        }
+
+        self.calls.push(ActiveCall { function, call_site: Some(call_site) });
+        let result = call(self);
+
+        // The innermost call is the first one an error passes through, so
+        // that's where the whole stack is still around
+        if let Err(err) = &result {
+            if self.trace.is_none() {
+                self.trace = Some(self.stack_trace(err.span));
+            }
+        }
+
+        self.calls.pop();
+        result
+    }
+
+    /// Call a function or class from Rust, rather than from Lox code. The
+    /// arguments should already be checked against the callee's arity.
+    ///
+    /// There's no call site, so the stack trace of an error ends at the
+    /// callee.
+    pub fn call_from_host(
+        &mut self,
+        function: Option<Symbol>,
+        callee: &dyn Call,
+        args: &[LoxValue],
+    ) -> std::result::Result<LoxValue, UncaughtError> {
+        for arg in args {
+            arg.register(&mut self.heap);
+        }
+
+        self.calls.push(ActiveCall { function, call_site: None });
+
+        let result = callee.call(self, args, Span::default()).map_err(|error| {
+            let trace = self.trace.take().unwrap_or_else(|| self.stack_trace(error.span));
+            UncaughtError { error, trace }
+        });
+
+        self.calls.pop();
+        result
     }
 
-    pub fn error(&mut self, spanned: Spanned<RuntimeError>) {
-        eprintln!("{}", self.source.annotate(spanned));
+    /// Build a stack trace from the calls in progress, for an error at `span`.
+    fn stack_trace(&self, span: Span) -> Vec<StackFrame> {
+        let mut trace = Vec::with_capacity(self.calls.len() + 1);
+        let mut span = span;
+
+        for call in self.calls.iter().rev() {
+            let function = call.function.map_or_else(|| String::from("<lambda>"), |name| name.to_string());
+            trace.push(StackFrame { function, span });
+
+            match call.call_site {
+                Some(call_site) => span = call_site,
+                None => return trace,
+            }
+        }
+
+        trace.push(StackFrame { function: String::from("<script>"), span });
+        trace
     }
 }
 
-impl<'a> Visitor<&Ast> for Interpreter<'a> {
-    type Output = LoxResult;
+impl Visitor<&Ast> for Interpreter {
+    type Output = std::result::Result<LoxValue, UncaughtError>;
 
-    fn visit(&mut self, ast: &Ast) -> LoxResult {
+    fn visit(&mut self, ast: &Ast) -> Self::Output {
         for statement in ast.iter() {
-            self.visit(statement)?;
+            match self.visit(statement) {
+                Err(Unwind::Error(error)) => {
+                    let trace = self.trace.take().unwrap_or_else(|| self.stack_trace(error.span));
+                    return Err(UncaughtError { error, trace });
+                },
+
+                // The resolver makes sure `return`, `break` and `continue`
+                // never make it out to the top level.
+                Err(_) => unreachable!(),
+
+                Ok(()) => {}
+            }
         }
 
         Ok(LoxValue::Nil)
     }
 }
 
-#[derive(Clone)]
+/// Everything that can cut the execution of a statement short: either a
+/// genuine runtime error, or a `return`, `break` or `continue` that is
+/// making its way up to the construct that handles it.
+pub enum Unwind {
+    Error(Spanned<RuntimeError>),
+    Return(LoxValue),
+    Break,
+    Continue,
+}
+
+impl From<Spanned<RuntimeError>> for Unwind {
+    fn from(err: Spanned<RuntimeError>) -> Self {
+        Unwind::Error(err)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum RuntimeError {
-    ArityMismatch(usize, usize),
+    ArityMismatch(Arity, usize),
     NotCallable,
     TypeError(&'static str),
     MultiTypeError(&'static str),
@@ -85,9 +311,25 @@ pub enum RuntimeError {
     IllegalPropertyAccess,
     IllegalFieldAccess,
     UndefinedProperty(String),
+    SuperclassNotClass,
+    NotIndexable,
+    NonIntegerIndex(f64),
+    IndexOutOfBounds(f64, usize),
+    EmptyList,
+    UnhashableKey(&'static str),
+    UndefinedKey(String),
+    StackOverflow,
+    Output(String),
+    ArgumentType(&'static str, &'static str),
+    NotAnInteger(f64, &'static str),
+    Unsupported(&'static str),
 
-    // Not actual errors
-    Return(LoxValue),
+    /// Loaded bytecode that used a value the wrong way, like defining a method
+    /// on something that isn't a class. Code from the compiler never does this.
+    InvalidBytecode(&'static str),
+
+    /// An error raised by a native function, with its own message
+    Native(String),
 }
 
 impl Display for RuntimeError {
@@ -101,10 +343,92 @@ impl Display for RuntimeError {
             RuntimeError::IllegalPropertyAccess => write!(f, "Only class instances have properties"),
             RuntimeError::IllegalFieldAccess => write!(f, "Only class instances have fields"),
             RuntimeError::UndefinedProperty(name) => write!(f, "Undefined property '{name}'"),
+            RuntimeError::SuperclassNotClass => write!(f, "Superclass must be a class"),
+            RuntimeError::NotIndexable => write!(f, "Only lists and maps can be indexed"),
+            RuntimeError::NonIntegerIndex(idx) => write!(f, "Index must be a whole number, but found {idx}"),
+            RuntimeError::IndexOutOfBounds(idx, len) => write!(f, "Index {idx} is out of bounds for list of length {len}"),
+            RuntimeError::EmptyList => write!(f, "Can't pop from an empty list"),
+            RuntimeError::UnhashableKey(type_name) => write!(f, "Value of type {type_name} can't be used as a map key"),
+            RuntimeError::UndefinedKey(key) => write!(f, "Undefined key {key}"),
+            RuntimeError::StackOverflow => write!(f, "Stack overflow: too many nested calls"),
+            RuntimeError::Output(err) => write!(f, "Failed to write output: {err}"),
+            RuntimeError::ArgumentType(expected, found) => write!(f, "Argument must be {expected}, but found {found}"),
+            RuntimeError::NotAnInteger(num, ty) => write!(f, "Expected a whole number that fits in {ty}, but found {num}"),
+            RuntimeError::Unsupported(type_name) => write!(f, "Values of type {type_name} can't be passed between Lox and Rust on the VM"),
+            RuntimeError::InvalidBytecode(problem) => write!(f, "Invalid bytecode: {problem}"),
+            RuntimeError::Native(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// A function call that was in progress when an error happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    /// The name of the function, `<lambda>` for anonymous functions, or
+    /// `<script>` for the top-level code
+    pub function: String,
+
+    /// Where execution was at in the function: the call to the next frame
+    /// down, or the error itself for the innermost frame
+    pub span: Span,
+}
+
+/// A runtime error that made it all the way out to the top level, along with
+/// the calls that led up to it.
+#[derive(Debug, Clone)]
+pub struct UncaughtError {
+    pub error: Spanned<RuntimeError>,
+
+    /// The calls that were in progress, innermost first. The last frame is
+    /// always the top-level script.
+    pub trace: Vec<StackFrame>,
+}
+
+impl UncaughtError {
+    /// Format the error for the user: the annotated source line, followed by
+    /// the stack trace. Runs of frames that would print the same line (e.g.,
+    /// from deep recursion) get collapsed.
+    pub fn report(&self, source: &Source) -> String {
+        let mut report = source.annotate(self.error.clone()).to_string();
+        let mut frames = self.trace.iter()
+            .map(|frame| (&frame.function, source.map_span(frame.span).0))
+            .peekable();
 
-            // Not an actual error, should never make it to the error reporting
-            // stage
-            RuntimeError::Return(_) => unreachable!()
+        while let Some(frame) = frames.next() {
+            let (function, line) = frame;
+            report.push_str(&format!("  at {function} (line {line})\n"));
+
+            let mut repeated = 0;
+
+            while frames.next_if_eq(&frame).is_some() {
+                repeated += 1;
+            }
+
+            if repeated > 0 {
+                report.push_str(&format!("  ... repeated {repeated} more times\n"));
+            }
         }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_frames_are_collapsed() {
+        let source = Source::new("fun f() {\n  f();\n}\nf();");
+        let frame = |function: &str, offset| StackFrame { function: function.to_owned(), span: Span::new_at(offset) };
+
+        let error = UncaughtError {
+            error: Spanned { value: RuntimeError::NotCallable, span: Span::new_at(12) },
+            trace: vec![frame("f", 12), frame("f", 13), frame("f", 12), frame("<script>", 22)],
+        };
+
+        let report = error.report(&source);
+        let trace: Vec<_> = report.lines().skip(3).collect();
+        assert_eq!(trace, ["  at f (line 2)", "  ... repeated 2 more times", "  at <script> (line 4)"]);
     }
 }
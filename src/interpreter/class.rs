@@ -2,18 +2,47 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::rc::Rc;
-use super::functions::LoxFunction;
-use super::functions::Call;
+use super::functions::{Arity, Call, LoxFunction};
 use super::RuntimeError;
-use crate::span::Spanned;
+use crate::gc::{Heap, Trace, Tracer};
+use crate::span::{Span, Spanned};
 use crate::interpreter::Interpreter;
 use crate::interpreter::value::LoxValue;
+use crate::engine::{Engine, Error};
+use crate::symbol::Symbol;
 use crate::syntax::tokens::Token;
 
 #[derive(Debug, Clone)]
 pub struct Class {
     pub name: Token,
-    pub methods: HashMap<String, Rc<LoxFunction>>
+    pub superclass: Option<Rc<Class>>,
+    pub methods: HashMap<Symbol, Rc<LoxFunction>>
+}
+
+impl Class {
+    /// Look up a method on the class, falling back to the superclass chain
+    /// if the class itself doesn't define it.
+    pub fn find_method(&self, name: Symbol) -> Option<Rc<LoxFunction>> {
+        if let Some(method) = self.methods.get(&name) {
+            Some(method.clone())
+        } else if let Some(superclass) = &self.superclass {
+            superclass.find_method(name)
+        } else {
+            None
+        }
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(superclass) = &self.superclass {
+            tracer.edge(superclass);
+        }
+
+        for method in self.methods.values() {
+            tracer.edge(method);
+        }
+    }
 }
 
 impl Display for Class {
@@ -25,26 +54,33 @@ impl Display for Class {
 impl Call for Rc<Class> {
     fn call(
         &self,
-        _interpreter: &mut Interpreter,
-        _args: &[LoxValue],
+        interpreter: &mut Interpreter,
+        args: &[LoxValue],
+        call_site: Span,
     ) -> Result<LoxValue, Spanned<RuntimeError>> {
-        let instance = Instance(Rc::new(RefCell::new(InstanceInner {
+        let instance = Instance(interpreter.heap.alloc(RefCell::new(InstanceInner {
             class: self.clone(),
             fields: HashMap::new(),
         })));
 
+        if let Some(initializer) = self.find_method(Symbol::INIT) {
+            Rc::unwrap_or_clone(initializer)
+                .bind(&instance, &mut interpreter.heap)
+                .call(interpreter, args, call_site)?;
+        }
+
         Ok(LoxValue::Instance(instance))
     }
 
-    fn arity(&self) -> usize {
-        return 0;
+    fn arity(&self) -> Arity {
+        self.find_method(Symbol::INIT).map_or(Arity::Exactly(0), |init| init.arity())
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct InstanceInner {
     pub class: Rc<Class>,
-    pub fields: HashMap<String, LoxValue>
+    pub fields: HashMap<Symbol, LoxValue>
 }
 
 #[derive(Debug, Clone)]
@@ -52,21 +88,52 @@ pub struct Instance(pub Rc<RefCell<InstanceInner>>);
 
 
 impl Instance {
-    pub fn get(&self, name: &Token) -> Result<LoxValue, Spanned<RuntimeError>> {
-        if let Some(value) = self.0.borrow().fields.get(&name.lexeme) {
-            Ok(value.to_owned())
-        } else if let Some(method) = self.0.borrow().class.methods.get(&name.lexeme) {
-            Ok(LoxValue::Function(Rc::new(Rc::unwrap_or_clone(method.clone()).bind(&self.clone()))))
+    pub fn get(&self, name: &Token, heap: &mut Heap) -> Result<LoxValue, Spanned<RuntimeError>> {
+        self.property(name.name(), heap).ok_or_else(|| Spanned {
+            value: RuntimeError::UndefinedProperty(name.to_string()),
+            span: name.span
+        })
+    }
+
+    /// Look up a field, or a method bound to the instance.
+    pub fn property(&self, name: Symbol, heap: &mut Heap) -> Option<LoxValue> {
+        if let Some(value) = self.0.borrow().fields.get(&name) {
+            Some(value.to_owned())
+        } else if let Some(method) = self.0.borrow().class.find_method(name) {
+            let method = Rc::unwrap_or_clone(method).bind(self, heap);
+            Some(LoxValue::Function(heap.alloc(method)))
         } else {
-            Err(Spanned {
-                value: RuntimeError::UndefinedProperty(name.lexeme.clone()),
-                span: name.span
-            })
+            None
         }
     }
 
+    /// Call a method (or a field holding a function) on the instance from
+    /// Rust. The instance has to come from the engine's own session.
+    pub fn call_method(&self, engine: &mut Engine, name: &str, args: &[LoxValue]) -> Result<LoxValue, Error> {
+        engine.call_method(self, name, args)
+    }
+
     pub fn set(&mut self, name: &Token, value: LoxValue) {
-        self.0.borrow_mut().fields.insert(name.lexeme.clone(), value);
+        self.0.borrow_mut().fields.insert(name.name(), value);
+    }
+}
+
+impl Trace for RefCell<InstanceInner> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let Ok(instance) = self.try_borrow() else {
+            return tracer.busy();
+        };
+
+        tracer.edge(&instance.class);
+
+        for value in instance.fields.values() {
+            value.trace(tracer);
+        }
+    }
+
+    fn clear(&self) {
+        let fields = std::mem::take(&mut self.borrow_mut().fields);
+        drop(fields);
     }
 }
 
@@ -0,0 +1,480 @@
+//! Conversions between `LoxValue`s and plain Rust types.
+//!
+//! Converting into a `LoxValue` can't fail. Converting out of one fails with
+//! a `RuntimeError` when the value has the wrong type, or is a number that the
+//! integer type can't hold, so native functions can use `?` on their
+//! arguments.
+//!
+//! Lists and maps built from Rust aren't registered with a heap until they're
+//! handed to a session (as a global, an argument, or the result of a native
+//! function), which registers them with `LoxValue::register`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::rc::Rc;
+
+use super::RuntimeError;
+use super::list::List;
+use super::map::{Entries, HashKey, Map};
+use super::value::LoxValue;
+use crate::gc::Heap;
+use crate::symbol::LoxStr;
+
+impl LoxValue {
+    /// Register the lists and maps in a value that was built in Rust with a
+    /// session's heap, so cycles that get formed through them are collected.
+    /// Collections the heap already knows about are skipped, along with what
+    /// they hold, which got registered when it was put there.
+    pub(crate) fn register(&self, heap: &mut Heap) {
+        match self {
+            LoxValue::List(list) if !heap.is_tracked(&list.0) => {
+                heap.track(&list.0);
+
+                for value in list.0.borrow().iter() {
+                    value.register(heap);
+                }
+            },
+
+            LoxValue::Map(map) if !heap.is_tracked(&map.0) => {
+                heap.track(&map.0);
+
+                for (_, value) in map.0.borrow().iter() {
+                    value.register(heap);
+                }
+            },
+
+            _ => {},
+        }
+    }
+}
+
+impl From<bool> for LoxValue {
+    fn from(value: bool) -> Self {
+        LoxValue::Bool(value)
+    }
+}
+
+/// Lox only has one kind of number, so integers get converted to floats.
+/// Integers beyond 2^53 lose precision.
+macro_rules! impl_from_number {
+    ($($ty:ty),*) => {
+        $(
+            impl From<$ty> for LoxValue {
+                fn from(value: $ty) -> Self {
+                    LoxValue::Num(value as f64)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_number!(f64, f32, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl From<&str> for LoxValue {
+    fn from(value: &str) -> Self {
+        LoxValue::Str(value.into())
+    }
+}
+
+impl From<String> for LoxValue {
+    fn from(value: String) -> Self {
+        LoxValue::Str(value.into())
+    }
+}
+
+impl From<LoxStr> for LoxValue {
+    fn from(value: LoxStr) -> Self {
+        LoxValue::Str(value)
+    }
+}
+
+/// `None` becomes `nil`.
+impl<T: Into<LoxValue>> From<Option<T>> for LoxValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(LoxValue::Nil, Into::into)
+    }
+}
+
+impl<T: Into<LoxValue>> From<Vec<T>> for LoxValue {
+    fn from(values: Vec<T>) -> Self {
+        let values = values.into_iter().map(Into::into).collect();
+        LoxValue::List(List(Rc::new(RefCell::new(values))))
+    }
+}
+
+/// Maps with string keys. The entries end up in the order the `HashMap`
+/// iterates over them.
+impl<K: Into<LoxStr>, V: Into<LoxValue>, S: BuildHasher> From<HashMap<K, V, S>> for LoxValue {
+    fn from(values: HashMap<K, V, S>) -> Self {
+        let mut entries = Entries::default();
+
+        for (key, value) in values {
+            let Ok(key) = HashKey::try_from(LoxValue::Str(key.into())) else {
+                unreachable!("strings are always hashable");
+            };
+
+            entries.insert(key, value.into());
+        }
+
+        LoxValue::Map(Map(Rc::new(RefCell::new(entries))))
+    }
+}
+
+impl TryFrom<&LoxValue> for f64 {
+    type Error = RuntimeError;
+
+    fn try_from(value: &LoxValue) -> Result<Self, RuntimeError> {
+        value.as_num()
+    }
+}
+
+impl TryFrom<&LoxValue> for bool {
+    type Error = RuntimeError;
+
+    fn try_from(value: &LoxValue) -> Result<Self, RuntimeError> {
+        value.as_bool()
+    }
+}
+
+impl TryFrom<&LoxValue> for String {
+    type Error = RuntimeError;
+
+    fn try_from(value: &LoxValue) -> Result<Self, RuntimeError> {
+        value.as_str().map(str::to_owned)
+    }
+}
+
+/// Conversions out of owned values, for the types that can be converted out
+/// of a borrowed one.
+macro_rules! impl_try_from_owned {
+    ($($ty:ty),*) => {
+        $(
+            impl TryFrom<LoxValue> for $ty {
+                type Error = RuntimeError;
+
+                fn try_from(value: LoxValue) -> Result<Self, RuntimeError> {
+                    Self::try_from(&value)
+                }
+            }
+        )*
+    };
+}
+
+/// Numbers convert to integer types only when they're whole and in range for
+/// the type, rather than getting truncated or saturated along the way.
+macro_rules! impl_try_from_integer {
+    ($($ty:ty),*) => {
+        $(
+            impl TryFrom<&LoxValue> for $ty {
+                type Error = RuntimeError;
+
+                fn try_from(value: &LoxValue) -> Result<Self, RuntimeError> {
+                    let num = value.as_num()?;
+                    let error = || RuntimeError::NotAnInteger(num, stringify!($ty));
+
+                    // Also rules out NaN and infinities. Every whole float
+                    // that fits any of these types fits an `i128` exactly.
+                    if num.fract() != 0.0 {
+                        return Err(error());
+                    }
+
+                    Self::try_from(num as i128).map_err(|_| error())
+                }
+            }
+        )*
+    };
+}
+
+impl_try_from_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl_try_from_owned!(f64, bool, String, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+/// `nil` becomes `None`.
+impl<T: TryFrom<LoxValue, Error = RuntimeError>> TryFrom<LoxValue> for Option<T> {
+    type Error = RuntimeError;
+
+    fn try_from(value: LoxValue) -> Result<Self, RuntimeError> {
+        match value {
+            LoxValue::Nil => Ok(None),
+            value => T::try_from(value).map(Some),
+        }
+    }
+}
+
+/// Copies the elements out of a list.
+impl<T: TryFrom<LoxValue, Error = RuntimeError>> TryFrom<LoxValue> for Vec<T> {
+    type Error = RuntimeError;
+
+    fn try_from(value: LoxValue) -> Result<Self, RuntimeError> {
+        let LoxValue::List(list) = value else {
+            return Err(RuntimeError::ArgumentType("a list", value.type_name()));
+        };
+
+        let values = list.0.borrow();
+        values.iter().cloned().map(T::try_from).collect()
+    }
+}
+
+/// Copies the entries out of a map, which can only have string keys.
+impl<T: TryFrom<LoxValue, Error = RuntimeError>> TryFrom<LoxValue> for HashMap<String, T> {
+    type Error = RuntimeError;
+
+    fn try_from(value: LoxValue) -> Result<Self, RuntimeError> {
+        let LoxValue::Map(map) = value else {
+            return Err(RuntimeError::ArgumentType("a map", value.type_name()));
+        };
+
+        let entries = map.0.borrow();
+        entries.iter()
+            .map(|(key, value)| Ok((String::try_from(key.value())?, T::try_from(value.clone())?)))
+            .collect()
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde {
+    use std::fmt::Formatter;
+
+    use ::serde::de::{Deserialize, Deserializer, Error as _, MapAccess, SeqAccess, Visitor};
+    use ::serde::ser::{Error as _, Serialize, SerializeMap, SerializeSeq, Serializer};
+
+    use super::*;
+    use crate::util::{CycleGuard, Revisit, MAX_NESTING};
+
+    /// Every integer up to this size is exactly representable as a float.
+    const MAX_SAFE_INTEGER: f64 = 9007199254740992.0;
+
+    /// Plain data serializes as itself, lists as sequences, and maps as maps.
+    /// Instances serialize as a map of their fields, sorted by name. Integral
+    /// numbers serialize as integers, so they don't turn into `1.0` in formats
+    /// that tell the two apart, except for `-0`, which would lose its sign.
+    ///
+    /// Functions and classes can't be serialized. Neither can collections or
+    /// instances that contain themselves, or that are nested more than
+    /// `MAX_NESTING` levels deep.
+    impl Serialize for LoxValue {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                LoxValue::Nil => serializer.serialize_unit(),
+                LoxValue::Bool(value) => serializer.serialize_bool(*value),
+
+                LoxValue::Num(num) if num.fract() == 0.0 && num.abs() <= MAX_SAFE_INTEGER && !is_negative_zero(*num) => {
+                    serializer.serialize_i64(*num as i64)
+                },
+
+                LoxValue::Num(num) => serializer.serialize_f64(*num),
+                LoxValue::Str(string) => serializer.serialize_str(string),
+
+                LoxValue::List(list) => {
+                    let _guard = CycleGuard::enter(Rc::as_ptr(&list.0)).map_err(|revisit| nesting_error(self, revisit))?;
+                    let values = list.0.borrow();
+                    let mut seq = serializer.serialize_seq(Some(values.len()))?;
+
+                    for value in values.iter() {
+                        seq.serialize_element(value)?;
+                    }
+
+                    seq.end()
+                },
+
+                LoxValue::Map(map) => {
+                    let _guard = CycleGuard::enter(Rc::as_ptr(&map.0)).map_err(|revisit| nesting_error(self, revisit))?;
+                    let entries = map.0.borrow();
+                    let mut map = serializer.serialize_map(Some(entries.len()))?;
+
+                    for (key, value) in entries.iter() {
+                        map.serialize_entry(key.value(), value)?;
+                    }
+
+                    map.end()
+                },
+
+                LoxValue::Instance(instance) => {
+                    let _guard = CycleGuard::enter(Rc::as_ptr(&instance.0)).map_err(|revisit| nesting_error(self, revisit))?;
+                    let instance = instance.0.borrow();
+                    let mut fields: Vec<_> = instance.fields.iter().collect();
+                    fields.sort_by_key(|(name, _)| name.as_str());
+
+                    let mut map = serializer.serialize_map(Some(fields.len()))?;
+
+                    for (name, value) in fields {
+                        map.serialize_entry(name.as_str(), value)?;
+                    }
+
+                    map.end()
+                },
+
+                LoxValue::NativeFunction(_) | LoxValue::Function(_) | LoxValue::Class(_) => {
+                    Err(S::Error::custom(format!("can't serialize a {}", self.type_name())))
+                },
+            }
+        }
+    }
+
+    fn is_negative_zero(num: f64) -> bool {
+        num == 0.0 && num.is_sign_negative()
+    }
+
+    fn nesting_error<E: ::serde::ser::Error>(value: &LoxValue, revisit: Revisit) -> E {
+        match revisit {
+            Revisit::Cycle => E::custom(format!("can't serialize a {} that contains itself", value.type_name())),
+            Revisit::TooDeep => E::custom(format!("can't serialize values nested more than {MAX_NESTING} levels deep")),
+        }
+    }
+
+    /// Sequences deserialize as lists, and maps as maps. Map keys have to be
+    /// plain data.
+    impl<'de> Deserialize<'de> for LoxValue {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(LoxValueVisitor)
+        }
+    }
+
+    struct LoxValueVisitor;
+
+    impl<'de> Visitor<'de> for LoxValueVisitor {
+        type Value = LoxValue;
+
+        fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+            write!(f, "a Lox value")
+        }
+
+        fn visit_unit<E>(self) -> Result<LoxValue, E> {
+            Ok(LoxValue::Nil)
+        }
+
+        fn visit_none<E>(self) -> Result<LoxValue, E> {
+            Ok(LoxValue::Nil)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<LoxValue, D::Error> {
+            LoxValue::deserialize(deserializer)
+        }
+
+        fn visit_bool<E>(self, value: bool) -> Result<LoxValue, E> {
+            Ok(LoxValue::Bool(value))
+        }
+
+        fn visit_i64<E>(self, value: i64) -> Result<LoxValue, E> {
+            Ok(value.into())
+        }
+
+        fn visit_u64<E>(self, value: u64) -> Result<LoxValue, E> {
+            Ok(value.into())
+        }
+
+        fn visit_f64<E>(self, value: f64) -> Result<LoxValue, E> {
+            Ok(value.into())
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<LoxValue, E> {
+            Ok(value.into())
+        }
+
+        fn visit_string<E>(self, value: String) -> Result<LoxValue, E> {
+            Ok(value.into())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<LoxValue, A::Error> {
+            let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+
+            while let Some(value) = seq.next_element::<LoxValue>()? {
+                values.push(value);
+            }
+
+            Ok(values.into())
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<LoxValue, A::Error> {
+            let mut entries = Entries::default();
+
+            while let Some((key, value)) = map.next_entry::<LoxValue, LoxValue>()? {
+                let key = HashKey::try_from(key).map_err(A::Error::custom)?;
+                entries.insert(key, value);
+            }
+
+            Ok(LoxValue::Map(Map(Rc::new(RefCell::new(entries)))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_data() {
+        assert_eq!(LoxValue::from(3), LoxValue::Num(3.0));
+        assert_eq!(LoxValue::from("foo"), LoxValue::Str("foo".into()));
+        assert_eq!(LoxValue::from(None::<bool>), LoxValue::Nil);
+
+        assert_eq!(f64::try_from(LoxValue::Num(1.5)).unwrap(), 1.5);
+        assert_eq!(Option::<String>::try_from(LoxValue::Nil).unwrap(), None);
+        assert!(matches!(bool::try_from(LoxValue::Nil), Err(RuntimeError::ArgumentType("a bool", "nil"))));
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(i32::try_from(LoxValue::Num(-7.0)).unwrap(), -7);
+        assert_eq!(u8::try_from(LoxValue::Num(255.0)).unwrap(), 255);
+        assert_eq!(u64::try_from(LoxValue::Num(-0.0)).unwrap(), 0);
+        assert_eq!(i64::try_from(LoxValue::Num(-9223372036854775808.0)).unwrap(), i64::MIN);
+
+        for num in [1.5, f64::NAN, f64::INFINITY] {
+            assert!(matches!(i64::try_from(LoxValue::Num(num)), Err(RuntimeError::NotAnInteger(_, "i64"))));
+        }
+
+        assert!(matches!(u8::try_from(LoxValue::Num(256.0)), Err(RuntimeError::NotAnInteger(_, "u8"))));
+        assert!(matches!(usize::try_from(LoxValue::Num(-1.0)), Err(RuntimeError::NotAnInteger(_, "usize"))));
+        assert!(matches!(i64::try_from(LoxValue::Num(9223372036854775808.0)), Err(RuntimeError::NotAnInteger(..))));
+        assert!(matches!(i32::try_from(LoxValue::Nil), Err(RuntimeError::ArgumentType("a number", "nil"))));
+    }
+
+    #[test]
+    fn collections() {
+        let list = LoxValue::from(vec![1, 2, 3]);
+        assert_eq!(Vec::<f64>::try_from(list).unwrap(), [1.0, 2.0, 3.0]);
+
+        let map = LoxValue::from(HashMap::from([("answer", 42)]));
+        assert_eq!(HashMap::<String, f64>::try_from(map).unwrap(), HashMap::from([(String::from("answer"), 42.0)]));
+
+        let mixed = LoxValue::from(vec![LoxValue::Num(1.0), LoxValue::Nil]);
+        assert!(Vec::<f64>::try_from(mixed).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let json = r#"{"name":"loxide","tags":["fast",null,true],"version":1,"ratio":0.5}"#;
+        let value: LoxValue = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_string(&value).unwrap(), json);
+
+        let flags: Vec<Option<bool>> = serde_json::from_str::<LoxValue>("[true, null]").unwrap().try_into().unwrap();
+        assert_eq!(flags, [Some(true), None]);
+
+        let zeros = LoxValue::from(vec![0.0, -0.0]);
+        assert_eq!(serde_json::to_string(&zeros).unwrap(), "[0,-0.0]");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serializing_cycles() {
+        let list = LoxValue::from(vec![1]);
+        let LoxValue::List(inner) = &list else { unreachable!() };
+
+        // Showing up twice is fine, as long as it's not inside of itself
+        let twice = LoxValue::from(vec![list.clone(), list.clone()]);
+        assert_eq!(serde_json::to_string(&twice).unwrap(), "[[1],[1]]");
+
+        inner.0.borrow_mut().push(list.clone());
+        let error = serde_json::to_string(&list).unwrap_err();
+        assert_eq!(error.to_string(), "can't serialize a list that contains itself");
+
+        // Break the cycle, so the list gets freed
+        inner.0.borrow_mut().clear();
+
+        let deep = (0..=crate::util::MAX_NESTING).fold(LoxValue::from(Vec::<bool>::new()), |list, _| vec![list].into());
+        assert!(serde_json::to_string(&deep).unwrap_err().to_string().contains("nested more than"));
+    }
+}
@@ -1,93 +1,186 @@
-use std::borrow::Borrow;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 
 use super::RuntimeError;
-use super::functions::globals::Clock;
+use super::functions::clock;
+use crate::gc::{Trace, Tracer};
 use crate::span::Spanned;
+use crate::symbol::Symbol;
 use crate::syntax::tokens::Token;
 use crate::interpreter::value::LoxValue;
 
-type Bindings = HashMap<String, LoxValue>;
+/// Where a variable lives at runtime, as worked out from the resolver's
+/// results.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Binding {
+    /// The variable at index `slot` in the scope `depth` levels up
+    Local { depth: usize, slot: usize },
 
-#[derive(Debug)]
+    /// The global variable at the given index in `Globals`
+    Global(usize),
+}
+
+/// A local scope. The resolver assigns every variable declared in a scope a
+/// slot, so variables are accessed by index rather than by name.
+///
+/// The top-level scope is an empty `Env` without a parent: globals live in
+/// `Globals` instead.
+#[derive(Debug, Default)]
 pub struct Env {
     pub parent: Option<Rc<Env>>,
-    pub bindings: Rc<RefCell<Bindings>>,
+    pub values: RefCell<Vec<LoxValue>>,
 }
 
 impl Env {
     pub fn new(parent: Rc<Env>) -> Self {
         Self {
             parent: Some(parent),
-            bindings: Rc::new(RefCell::new(Bindings::new())),
-        }
-    }
-
-    pub fn global() -> Self {
-        let mut bindings = Bindings::new();
-        bindings.insert(format!("clock"), LoxValue::NativeFunction(Rc::new(Clock)));
-
-        Self {
-            parent: None,
-            bindings: Rc::new(RefCell::new(bindings))
+            values: RefCell::new(Vec::new()),
         }
     }
 
-    pub fn define(&self, name: String, value: LoxValue) {
-        self.bindings.borrow_mut().insert(name, value);
-    }
+    pub fn define(&self, slot: usize, value: LoxValue) {
+        let mut values = self.values.borrow_mut();
 
-    pub fn assign(&self, name: &Token, value: LoxValue) -> Result<(), Spanned<RuntimeError>> {
-        if RefCell::borrow(&self.bindings).contains_key(&name.lexeme) {
-            self.bindings.borrow_mut().insert(name.lexeme.to_owned(), value);
-            Ok(())
-        } else if let Some(parent) = &self.parent {
-            parent.assign(name, value)
-        } else {
-            Err(Spanned {
-                value: RuntimeError::UndeclaredVar(format!("{name}")),
-                span: name.span
-            })
+        // A declaration can get skipped (e.g., `if (false) fun f() {}`), so
+        // later slots might get filled in first
+        if slot >= values.len() {
+            values.resize(slot + 1, LoxValue::Nil);
         }
-    }
 
-    pub fn get(&self, name: &Token) -> Result<LoxValue, Spanned<RuntimeError>> {
-        if let Some(value) = RefCell::borrow(&self.bindings).get(&name.lexeme) {
-            Ok(value.to_owned())
-        } else if let Some(parent) = &self.parent {
-            parent.get(name)
-        } else {
-            Err(Spanned {
-                value: RuntimeError::UndeclaredVar(format!("{name}")),
-                span: name.span
-            })
-        }
+        values[slot] = value;
     }
 
-    pub fn get_at(&self, dist: usize, name: &Token) -> Result<LoxValue, Spanned<RuntimeError>> {
+    /// Walk up the chain of parent environments `dist` times.
+    pub fn ancestor(&self, dist: usize) -> &Env {
         let mut env = self;
 
         for _ in 0..dist {
-            env = env.parent.as_ref().unwrap().borrow();
+            env = env.parent.as_ref().unwrap();
         }
 
-        env.get(name)
+        env
+    }
+
+    pub fn get_at(&self, dist: usize, slot: usize, name: &Token) -> Result<LoxValue, Spanned<RuntimeError>> {
+        self.ancestor(dist).values.borrow().get(slot).cloned().ok_or_else(|| undeclared(name))
     }
 
     pub fn assign_at(
         &self,
         dist: usize,
+        slot: usize,
         name: &Token,
         value: LoxValue
     ) -> Result<(), Spanned<RuntimeError>> {
-        let mut env = self;
+        let mut values = self.ancestor(dist).values.borrow_mut();
+        let variable = values.get_mut(slot).ok_or_else(|| undeclared(name))?;
+        *variable = value;
+        Ok(())
+    }
+}
 
-        for _ in 0..dist {
-            env = env.parent.as_ref().unwrap().borrow();
+impl Trace for Env {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(parent) = &self.parent {
+            tracer.edge(parent);
+        }
+
+        let Ok(values) = self.values.try_borrow() else {
+            return tracer.busy();
+        };
+
+        for value in values.iter() {
+            value.trace(tracer);
+        }
+    }
+
+    fn clear(&self) {
+        // Dropping the values can free other objects, so only do that after
+        // the values are no longer borrowed
+        let values = std::mem::take(&mut *self.values.borrow_mut());
+        drop(values);
+    }
+}
+
+/// The global variables of a session.
+///
+/// Every global gets an index the first time it is referenced or defined, so
+/// resolved code can access globals without hashing their names. Globals are
+/// late-bound: code can refer to a global before it is defined, as long as it
+/// doesn't run before the definition does.
+#[derive(Debug)]
+pub struct Globals {
+    indices: HashMap<Symbol, usize>,
+    names: Vec<Symbol>,
+
+    /// The value of every global, or `None` if it wasn't defined yet
+    values: Vec<Option<LoxValue>>,
+}
+
+impl Default for Globals {
+    fn default() -> Self {
+        let mut globals = Self {
+            indices: HashMap::new(),
+            names: Vec::new(),
+            values: Vec::new(),
+        };
+
+        globals.define(Symbol::intern("clock"), LoxValue::NativeFunction(Rc::new(clock())));
+        globals
+    }
+}
+
+impl Globals {
+    /// Look up the index of a global, reserving one if it doesn't exist yet.
+    pub fn index(&mut self, name: Symbol) -> usize {
+        if let Some(&idx) = self.indices.get(&name) {
+            return idx;
         }
 
-        env.assign(name, value)
+        let idx = self.values.len();
+        self.indices.insert(name, idx);
+        self.names.push(name);
+        self.values.push(None);
+        idx
+    }
+
+    pub fn define(&mut self, name: Symbol, value: LoxValue) {
+        let idx = self.index(name);
+        self.values[idx] = Some(value);
+    }
+
+    /// Look up a global by name, if it was defined.
+    pub fn find(&self, name: Symbol) -> Option<&LoxValue> {
+        let &idx = self.indices.get(&name)?;
+        self.values[idx].as_ref()
+    }
+
+    pub fn get(&self, idx: usize, name: &Token) -> Result<LoxValue, Spanned<RuntimeError>> {
+        self.values[idx].clone().ok_or_else(|| undeclared(name))
+    }
+
+    pub fn assign(&mut self, idx: usize, name: &Token, value: LoxValue) -> Result<(), Spanned<RuntimeError>> {
+        let Some(variable) = &mut self.values[idx] else {
+            return Err(undeclared(name));
+        };
+
+        *variable = value;
+        Ok(())
+    }
+
+    /// Iterate over the names and values of all defined globals.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &LoxValue)> {
+        self.names.iter()
+            .zip(&self.values)
+            .filter_map(|(name, value)| Some((name.as_str(), value.as_ref()?)))
+    }
+}
+
+fn undeclared(name: &Token) -> Spanned<RuntimeError> {
+    Spanned {
+        value: RuntimeError::UndeclaredVar(format!("{name}")),
+        span: name.span,
     }
 }
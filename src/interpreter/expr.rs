@@ -3,23 +3,27 @@ use std::rc::Rc;
 
 use crate::interpreter::LoxValue as Val;
 use super::RuntimeError;
-use super::functions::Call;
+use super::environment::Binding;
+use super::functions::{Call, LoxFunction};
+use super::list::List;
+use super::map::{Entries, HashKey, Map};
 use crate::span::Spanned;
-use crate::syntax::ast::Expr;
+use crate::symbol::Symbol;
+use crate::syntax::ast::{Expr, NodeId};
 use crate::syntax::tokens::Token;
 use crate::syntax::tokens::TokenType;
 
-use super::{Interpreter, LoxResult, Visitor};
+use super::{Interpreter, LoxResult, Visitor, STACK_RED_ZONE, STACK_SEGMENT_SIZE};
 
-impl<'a> Visitor<&Expr> for Interpreter<'a> {
+impl Visitor<&Expr> for Interpreter {
     type Output = LoxResult;
     fn visit(&mut self, expr: &Expr) -> LoxResult {
         match expr {
-            Expr::Literal { value } => Ok(value.clone().into()),
+            Expr::Literal { value, .. } => Ok(value.clone().into()),
 
             Expr::Call { callee, arguments, paren } => self.visit_call(callee, arguments, &paren),
 
-            Expr::Grouping { expr } => self.visit(expr.as_ref()),
+            Expr::Grouping { expr } => self.evaluate(expr),
 
             Expr::Unary { op, right } => self.visit_unary(op, right),
 
@@ -27,15 +31,14 @@ impl<'a> Visitor<&Expr> for Interpreter<'a> {
 
             Expr::Logical { op, left, right } => self.visit_logical(op, left, right),
 
-            Expr::Variable { name } => { self.lookup(name, expr)},
+            Expr::Variable { id, name } => self.lookup(*id, name),
 
-            Expr::Assignment { name, value } => {
+            Expr::Assignment { id, name, value } => {
                 let value = self.evaluate(value)?;
 
-                if let Some(distance) = self.locals.get(expr) {
-                    self.env.assign_at(*distance, name, value.clone())?;
-                } else {
-                    self.globals.assign(name, value.clone())?;
+                match self.binding(*id, name) {
+                    Binding::Local { depth, slot } => self.env.assign_at(depth, slot, name, value.clone())?,
+                    Binding::Global(idx) => self.globals.assign(idx, name, value.clone())?,
                 }
 
                 Ok(value)
@@ -45,7 +48,11 @@ impl<'a> Visitor<&Expr> for Interpreter<'a> {
                 let object = self.evaluate(object)?;
 
                 if let Val::Instance(instance) = object {
-                    instance.get(name)
+                    instance.get(name, &mut self.heap)
+                } else if let Val::List(list) = object {
+                    list.get_method(name, &mut self.heap)
+                } else if let Val::Map(map) = object {
+                    map.get_method(name, &mut self.heap)
                 } else {
                     Err(Spanned {
                         value: RuntimeError::IllegalPropertyAccess,
@@ -70,26 +77,130 @@ impl<'a> Visitor<&Expr> for Interpreter<'a> {
                 }
             },
 
-            Expr::This { keyword } => {
-                self.lookup(keyword, expr)
+            Expr::This { id, keyword } => self.lookup(*id, keyword),
+
+            Expr::Super { id, keyword, method } => self.visit_super(*id, keyword, method),
+
+            Expr::List { elements, .. } => {
+                let mut values = Vec::with_capacity(elements.len());
+
+                for element in elements {
+                    values.push(self.evaluate(element)?);
+                }
+
+                Ok(Val::List(List::new(values, &mut self.heap)))
+            },
+
+            Expr::Interpolation { parts, .. } => {
+                let mut string = String::new();
+
+                for part in parts {
+                    string.push_str(&self.evaluate(part)?.to_string());
+                }
+
+                Ok(Val::Str(string.into()))
+            },
+
+            Expr::Lambda { params, body, .. } => {
+                let function = LoxFunction {
+                    name: None,
+                    params: params.clone(),
+                    body: body.clone(),
+                    env: self.env.clone(),
+                    is_initializer: false,
+                };
+
+                Ok(Val::Function(self.heap.alloc(function)))
+            },
+
+            Expr::Map { brace, entries } => {
+                let mut map = Entries::default();
+
+                for (key, value) in entries {
+                    let key = HashKey::new(self.evaluate(key)?, brace.span)?;
+                    map.insert(key, self.evaluate(value)?);
+                }
+
+                Ok(Val::Map(Map::new(map, &mut self.heap)))
+            },
+
+            Expr::Index { object, bracket, index } => {
+                let object = self.evaluate(object)?;
+                let index = self.evaluate(index)?;
+
+                match object {
+                    Val::List(list) => list.get(index, bracket),
+                    Val::Map(map) => map.get(index, bracket),
+                    _ => Err(Spanned { value: RuntimeError::NotIndexable, span: bracket.span }),
+                }
+            },
+
+            Expr::SetIndex { object, bracket, index, value } => {
+                let object = self.evaluate(object)?;
+                let index = self.evaluate(index)?;
+                let value = self.evaluate(value)?;
+
+                match object {
+                    Val::List(list) => list.set(index, value.clone(), bracket)?,
+                    Val::Map(map) => map.set(index, value.clone(), bracket)?,
+                    _ => Err(Spanned { value: RuntimeError::NotIndexable, span: bracket.span })?,
+                }
+
+                Ok(value)
             },
         }
     }
 }
 
-impl<'a> Interpreter<'a> {
+impl Interpreter {
     pub fn evaluate(&mut self, expr: &Expr) -> LoxResult {
-        self.visit(expr)
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || self.visit(expr))
     }
 
-    fn lookup(&self, name: &Token, expr: &Expr) -> LoxResult {
-        if let Some(&dist) = self.locals.get(expr) {
-            self.env.get_at(dist, name)
-        } else {
-            self.globals.get(name)
+    /// Look up where the variable referred to by node `id` lives.
+    fn binding(&mut self, id: NodeId, name: &Token) -> Binding {
+        if let Some(&binding) = self.locals.get(&id) {
+            return binding;
+        }
+
+        // Anything that didn't resolve to a local is a global, which only
+        // needs to be looked up by name once
+        let binding = Binding::Global(self.globals.index(name.name()));
+        self.locals.insert(id, binding);
+        binding
+    }
+
+    fn lookup(&mut self, id: NodeId, name: &Token) -> LoxResult {
+        match self.binding(id, name) {
+            Binding::Local { depth, slot } => self.env.get_at(depth, slot, name),
+            Binding::Global(idx) => self.globals.get(idx, name),
         }
     }
 
+    fn visit_super(&mut self, id: NodeId, keyword: &Token, method: &Token) -> LoxResult {
+        let Binding::Local { depth, slot } = self.binding(id, keyword) else { unreachable!() };
+
+        let Val::Class(superclass) = self.env.get_at(depth, slot, keyword)? else { unreachable!() };
+
+        // `this` always lives in the first slot of the scope right inside the
+        // one holding `super`
+        let Some(Val::Instance(instance)) = self.env.ancestor(depth - 1)
+            .values
+            .borrow()
+            .first()
+            .cloned() else { unreachable!() };
+
+        let Some(method) = superclass.find_method(method.name()) else {
+            return Err(Spanned {
+                value: RuntimeError::UndefinedProperty(method.to_string()),
+                span: method.span,
+            });
+        };
+
+        let method = Rc::unwrap_or_clone(method).bind(&instance, &mut self.heap);
+        Ok(Val::Function(self.heap.alloc(method)))
+    }
+
     fn visit_call(&mut self, callee: &Expr, args: &[Expr], token: &Token) -> LoxResult {
         let callee = self.evaluate(callee)?;
         let mut evaluated_args = Vec::new();
@@ -100,34 +211,40 @@ impl<'a> Interpreter<'a> {
 
         match callee {
             Val::NativeFunction(fun) => {
-                if args.len() != fun.arity() {
+                if !fun.arity().accepts(args.len()) {
                     return Err(Spanned {
                         value: RuntimeError::ArityMismatch(fun.arity(), args.len()),
                         span: token.span,
                     });
                 }
 
-                fun.call(self, &evaluated_args)
+                fun.call(self, &evaluated_args, token.span)
             },
             Val::Function(fun) => {
-                if args.len() != fun.arity() {
+                if !fun.arity().accepts(args.len()) {
                     return Err(Spanned {
                         value: RuntimeError::ArityMismatch(fun.arity(), args.len()),
                         span: token.span,
                     });
                 }
 
-                fun.call(self, &evaluated_args)
+                let name = fun.name.as_ref().map(Token::name);
+                self.traced_call(name, token.span, |interpreter| fun.call(interpreter, &evaluated_args, token.span))
             },
             Val::Class(fun) => {
-                if args.len() != fun.arity() {
+                if !fun.arity().accepts(args.len()) {
                     return Err(Spanned {
                         value: RuntimeError::ArityMismatch(fun.arity(), args.len()),
                         span: token.span,
                     });
                 }
 
-                fun.call(self, &evaluated_args)
+                // Only the initializer runs any code that could fail
+                if fun.find_method(Symbol::INIT).is_some() {
+                    self.traced_call(Some(Symbol::INIT), token.span, |interpreter| fun.call(interpreter, &evaluated_args, token.span))
+                } else {
+                    fun.call(self, &evaluated_args, token.span)
+                }
             },
             _ => {
                 Err(Spanned {
@@ -185,7 +302,7 @@ impl<'a> Interpreter<'a> {
                 if let (Val::Num(left), Val::Num(right)) = (&left, &right) {
                     Ok(Val::Num(left + right))
                 } else if let (Val::Str(left), Val::Str(right)) = (left, right) {
-                    Ok(Val::Str(Rc::new(format!("{left}{right}"))))
+                    Ok(Val::Str(format!("{left}{right}").into()))
                 } else {
                     Err(Spanned {
                         value: RuntimeError::MultiTypeError("string or number"),
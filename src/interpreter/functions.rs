@@ -3,123 +3,206 @@ use std::fmt::Debug;
 
 use super::environment::Env;
 use super::class::Instance;
-use super::RuntimeError;
+use super::{RuntimeError, Unwind};
 use crate::interpreter::value::LoxValue;
 use crate::syntax::tokens::Token;
-use crate::span::Spanned;
+use crate::gc::{Heap, Trace, Tracer};
+use crate::span::{Span, Spanned};
 use crate::interpreter::Interpreter;
 use crate::syntax::ast::Stmt;
 
 pub trait Call: Display + Debug {
+    /// Call the function. Errors that aren't tied to anything more specific
+    /// get reported at `call_site`.
     fn call(
         &self,
         interpreter: &mut Interpreter,
         args: &[LoxValue],
+        call_site: Span,
     ) -> Result<LoxValue, Spanned<RuntimeError>>;
 
-    fn arity(&self) -> usize;
+    fn arity(&self) -> Arity;
 }
 
-#[derive(Clone)]
-pub struct LoxFunction {
-    pub name: Token,
-    pub params: Vec<Token>,
-    pub body: Vec<Stmt>,
-    pub env: Rc<Env>,
-}
+/// The number of arguments a function accepts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Arity {
+    Exactly(usize),
 
-impl Call for LoxFunction {
-    fn call(
-        &self,
-        interpreter: &mut Interpreter,
-        args: &[LoxValue],
-    ) -> Result<LoxValue, Spanned<RuntimeError>> {
-        let local_scope = Rc::new(Env::new(self.env.clone()));
+    /// Any number of arguments, as long as there are at least this many
+    AtLeast(usize),
+}
 
-        for (param, arg) in self.params.iter().zip(args) {
-            local_scope.define(param.lexeme.clone(), arg.clone())
+impl Arity {
+    pub fn accepts(self, argc: usize) -> bool {
+        match self {
+            Arity::Exactly(arity) => argc == arity,
+            Arity::AtLeast(min) => argc >= min,
         }
+    }
+}
 
-        // Catch any return statements that are bubbled up by throwing an error
-        match interpreter.exec_block_with_env(&self.body, local_scope) {
-            Err(Spanned {
-                value: RuntimeError::Return(value),
-                ..
-            }) => Ok(value),
+impl From<usize> for Arity {
+    fn from(arity: usize) -> Self {
+        Arity::Exactly(arity)
+    }
+}
 
-            // Just pass through anything else
-            val => val,
+impl Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Arity::Exactly(arity) => write!(f, "{arity}"),
+            Arity::AtLeast(min) => write!(f, "at least {min}"),
         }
     }
+}
+
+type NativeFn = dyn Fn(&[LoxValue]) -> Result<LoxValue, RuntimeError>;
+
+/// A function implemented in Rust, by the interpreter or by the application
+/// that embeds it. Both backends call the same closure.
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: Arity,
+    fun: Box<NativeFn>,
+}
+
+impl NativeFunction {
+    pub fn new(
+        name: &str,
+        arity: impl Into<Arity>,
+        fun: impl Fn(&[LoxValue]) -> Result<LoxValue, RuntimeError> + 'static,
+    ) -> Self {
+        Self { name: name.to_owned(), arity: arity.into(), fun: Box::new(fun) }
+    }
 
-    fn arity(&self) -> usize {
-        self.params.len()
+    /// Run the function, assuming the number of arguments was checked.
+    pub fn call_native(&self, args: &[LoxValue]) -> Result<LoxValue, RuntimeError> {
+        (self.fun)(args)
     }
 }
 
-impl LoxFunction {
-    pub fn bind(mut self, instance: &Instance) -> LoxFunction {
-        self.env = Rc::new(Env::new(self.env));
-        self.env.define(format!("this"), LoxValue::Instance(instance.clone()));
-        self
+impl Call for NativeFunction {
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        args: &[LoxValue],
+        call_site: Span,
+    ) -> Result<LoxValue, Spanned<RuntimeError>> {
+        let result = self.call_native(args).map_err(|value| Spanned { value, span: call_site })?;
+        result.register(interpreter.heap_mut());
+        Ok(result)
+    }
+
+    fn arity(&self) -> Arity {
+        self.arity
     }
 }
 
-impl Display for LoxFunction {
+impl Display for NativeFunction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "<function {name}>", name = self.name)
+        write!(f, "<native fn: {}>", self.name)
     }
 }
 
-impl Debug for LoxFunction {
+impl Debug for NativeFunction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("LoxFunction").field("name", &self.name).field("params", &self.params).field("body", &self.body).finish()
+        f.debug_struct("NativeFunction").field("name", &self.name).field("arity", &self.arity).finish()
     }
 }
 
-// Globals
-pub mod globals {
-    use std::fmt::Display;
-    use std::fmt::Debug;
+/// The global `clock` function, which returns the number of seconds since the
+/// Unix epoch.
+pub fn clock() -> NativeFunction {
+    NativeFunction::new("clock", 0, |_| {
+        use std::time::{SystemTime, UNIX_EPOCH};
 
-    use crate::interpreter::value::LoxValue;
-    use crate::{interpreter::Interpreter, span::Spanned};
-    use super::RuntimeError;
+        let epoch_millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as f64;
 
-    use super::Call;
+        Ok(LoxValue::Num(epoch_millis / 1000.0))
+    })
+}
 
-    pub struct Clock;
+#[derive(Clone)]
+pub struct LoxFunction {
+    /// The name of the function, or `None` for anonymous functions
+    pub name: Option<Token>,
+    pub params: Rc<[Token]>,
+    pub body: Rc<[Stmt]>,
+    pub env: Rc<Env>,
+    pub is_initializer: bool,
+}
 
-    impl Call for Clock {
-        fn arity(&self) -> usize {
-            0
+impl Call for LoxFunction {
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        args: &[LoxValue],
+        _call_site: Span,
+    ) -> Result<LoxValue, Spanned<RuntimeError>> {
+        let local_scope = interpreter.heap.alloc(Env::new(self.env.clone()));
+
+        for (slot, arg) in args.iter().enumerate() {
+            local_scope.define(slot, arg.clone())
         }
 
-        fn call(
-            &self,
-            _interpreter: &mut Interpreter,
-            _args: &[LoxValue],
-        ) -> Result<LoxValue, Spanned<RuntimeError>> {
-            use std::time::{SystemTime, UNIX_EPOCH};
+        // Catch any return statements that are bubbled up from the body
+        let value = match interpreter.exec_block_with_env(&self.body, local_scope) {
+            Ok(()) => LoxValue::Nil,
+            Err(Unwind::Return(value)) => value,
+            Err(Unwind::Error(err)) => return Err(err),
 
-            let epoch_millis = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as f64;
+            // The resolver rejects loop control outside of a loop, and loops
+            // never span function boundaries.
+            Err(Unwind::Break | Unwind::Continue) => unreachable!(),
+        };
 
-            Ok(LoxValue::Num(epoch_millis / 1000.0))
+        // Initializers always hand back the instance they were bound to
+        if self.is_initializer {
+            return Ok(self.this());
         }
+
+        Ok(value)
     }
 
-    impl Display for Clock {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(f, "<native fn: clock>")
-        }
+    fn arity(&self) -> Arity {
+        Arity::Exactly(self.params.len())
     }
+}
 
-    impl Debug for Clock {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            f.debug_struct("Clock").finish()
+impl LoxFunction {
+    pub fn bind(mut self, instance: &Instance, heap: &mut Heap) -> LoxFunction {
+        self.env = heap.alloc(Env::new(self.env));
+        self.env.define(0, LoxValue::Instance(instance.clone()));
+        self
+    }
+
+    /// Fetch the instance a method was bound to.
+    fn this(&self) -> LoxValue {
+        self.env.values.borrow().first().cloned().unwrap_or(LoxValue::Nil)
+    }
+}
+
+impl Trace for LoxFunction {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.edge(&self.env);
+    }
+}
+
+impl Display for LoxFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<function {name}>"),
+            None => write!(f, "<function>"),
         }
     }
 }
+
+impl Debug for LoxFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoxFunction").field("name", &self.name).field("params", &self.params).field("body", &self.body).finish()
+    }
+}
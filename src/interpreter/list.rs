@@ -0,0 +1,245 @@
+use std::cell::RefCell;
+use std::fmt::{Debug, Display};
+use std::rc::Rc;
+
+use super::functions::{Arity, Call};
+use super::RuntimeError;
+use crate::interpreter::Interpreter;
+use crate::interpreter::value::LoxValue;
+use crate::gc::{Heap, Trace, Tracer};
+use crate::span::{Span, Spanned};
+use crate::syntax::tokens::Token;
+use crate::util::{drop_nested, CycleGuard};
+
+/// A growable list of values. Lists have reference semantics: copies of a
+/// list value all point to the same underlying storage.
+#[derive(Debug, Clone, Default)]
+pub struct List(pub Rc<RefCell<Vec<LoxValue>>>);
+
+impl List {
+    pub fn new(values: Vec<LoxValue>, heap: &mut Heap) -> Self {
+        Self(heap.alloc(RefCell::new(values)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
+    /// Read the element at `index`, where the bracket token is used to report
+    /// errors.
+    pub fn get(&self, index: LoxValue, bracket: &Token) -> Result<LoxValue, Spanned<RuntimeError>> {
+        let idx = self.checked_index(index, bracket.span)?;
+        Ok(self.0.borrow()[idx].clone())
+    }
+
+    /// Overwrite the element at `index`, where the bracket token is used to
+    /// report errors.
+    pub fn set(
+        &self,
+        index: LoxValue,
+        value: LoxValue,
+        bracket: &Token
+    ) -> Result<(), Spanned<RuntimeError>> {
+        let idx = self.checked_index(index, bracket.span)?;
+        self.0.borrow_mut()[idx] = value;
+        Ok(())
+    }
+
+    /// Look up one of the built-in list methods, bound to this list.
+    pub fn get_method(&self, name: &Token, heap: &mut Heap) -> Result<LoxValue, Spanned<RuntimeError>> {
+        let method = match name.name().as_str() {
+            "push" => ListMethod::Push,
+            "pop" => ListMethod::Pop,
+            "len" => ListMethod::Len,
+            "insert" => ListMethod::Insert,
+            "remove" => ListMethod::Remove,
+            _ => return Err(Spanned {
+                value: RuntimeError::UndefinedProperty(name.to_string()),
+                span: name.span,
+            }),
+        };
+
+        Ok(LoxValue::NativeFunction(heap.alloc(BoundListMethod {
+            list: self.clone(),
+            method,
+            span: name.span,
+        })))
+    }
+
+    /// Check that a value is a valid index into the list.
+    fn checked_index(&self, index: LoxValue, span: Span) -> Result<usize, Spanned<RuntimeError>> {
+        self.checked_position(index, span, self.len())
+    }
+
+    /// Check that a value is a whole number that is less than `max`.
+    fn checked_position(
+        &self,
+        index: LoxValue,
+        span: Span,
+        max: usize,
+    ) -> Result<usize, Spanned<RuntimeError>> {
+        let LoxValue::Num(num) = index else {
+            return Err(Spanned { value: RuntimeError::TypeError("number"), span });
+        };
+
+        if num.fract() != 0.0 {
+            return Err(Spanned { value: RuntimeError::NonIntegerIndex(num), span });
+        }
+
+        if num < 0.0 || num as usize >= max {
+            return Err(Spanned {
+                value: RuntimeError::IndexOutOfBounds(num, self.len()),
+                span
+            });
+        }
+
+        Ok(num as usize)
+    }
+}
+
+impl Trace for RefCell<Vec<LoxValue>> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let Ok(values) = self.try_borrow() else {
+            return tracer.busy();
+        };
+
+        for value in values.iter() {
+            value.trace(tracer);
+        }
+    }
+
+    fn clear(&self) {
+        let values = std::mem::take(&mut *self.borrow_mut());
+        drop(values);
+    }
+}
+
+/// Lists nested too deeply in each other get dropped without recursing. See
+/// `drop_nested`.
+impl Drop for List {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.0) > 1 {
+            return;
+        }
+
+        if let Ok(mut values) = self.0.try_borrow_mut() {
+            drop_nested(std::mem::take(&mut *values));
+        }
+    }
+}
+
+/// A list that contains itself shows up as `[...]` inside itself, as does one
+/// nested too deeply to print.
+impl Display for List {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Ok(_guard) = CycleGuard::enter(Rc::as_ptr(&self.0)) else {
+            return write!(f, "[...]");
+        };
+
+        write!(f, "[")?;
+
+        for (idx, value) in self.0.borrow().iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{}", value.repr())?;
+        }
+
+        write!(f, "]")
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum ListMethod {
+    Push,
+    Pop,
+    Len,
+    Insert,
+    Remove,
+}
+
+impl ListMethod {
+    fn name(&self) -> &'static str {
+        match self {
+            ListMethod::Push => "push",
+            ListMethod::Pop => "pop",
+            ListMethod::Len => "len",
+            ListMethod::Insert => "insert",
+            ListMethod::Remove => "remove",
+        }
+    }
+}
+
+/// A built-in list method, together with the list it was accessed on.
+#[derive(Debug)]
+struct BoundListMethod {
+    list: List,
+    method: ListMethod,
+
+    /// The span of the method name, used for reporting errors
+    span: Span,
+}
+
+impl Call for BoundListMethod {
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        args: &[LoxValue],
+        _call_site: Span,
+    ) -> Result<LoxValue, Spanned<RuntimeError>> {
+        let list = &self.list;
+
+        match self.method {
+            ListMethod::Push => {
+                list.0.borrow_mut().push(args[0].clone());
+                Ok(LoxValue::Nil)
+            },
+
+            ListMethod::Pop => {
+                list.0.borrow_mut().pop().ok_or(Spanned {
+                    value: RuntimeError::EmptyList,
+                    span: self.span,
+                })
+            },
+
+            ListMethod::Len => Ok(LoxValue::Num(list.len() as f64)),
+
+            ListMethod::Insert => {
+                // Inserting right after the last element is allowed
+                let idx = list.checked_position(args[0].clone(), self.span, list.len() + 1)?;
+                list.0.borrow_mut().insert(idx, args[1].clone());
+                Ok(LoxValue::Nil)
+            },
+
+            ListMethod::Remove => {
+                let idx = list.checked_index(args[0].clone(), self.span)?;
+                Ok(list.0.borrow_mut().remove(idx))
+            },
+        }
+    }
+
+    fn arity(&self) -> Arity {
+        match self.method {
+            ListMethod::Pop | ListMethod::Len => Arity::Exactly(0),
+            ListMethod::Push | ListMethod::Remove => Arity::Exactly(1),
+            ListMethod::Insert => Arity::Exactly(2),
+        }
+    }
+}
+
+impl Trace for BoundListMethod {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.edge(&self.list.0);
+    }
+}
+
+impl Display for BoundListMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn: {}>", self.method.name())
+    }
+}
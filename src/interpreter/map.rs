@@ -0,0 +1,397 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+use std::rc::Rc;
+
+use super::functions::{Arity, Call};
+use super::list::List;
+use super::RuntimeError;
+use crate::interpreter::Interpreter;
+use crate::interpreter::value::LoxValue;
+use crate::gc::{Heap, Trace, Tracer};
+use crate::span::{Span, Spanned};
+use crate::syntax::tokens::Token;
+use crate::util::{drop_nested, CycleGuard};
+
+/// A value that can be used as a map key.
+///
+/// Only values with value semantics (nil, booleans, numbers and strings) are
+/// hashable. Lists, maps and instances can be mutated after being inserted,
+/// and functions and classes only compare by identity, so none of those are
+/// allowed as keys.
+#[derive(Debug, Clone)]
+pub struct HashKey(LoxValue);
+
+impl HashKey {
+    pub fn new(value: LoxValue, span: Span) -> Result<Self, Spanned<RuntimeError>> {
+        Self::try_from(value).map_err(|value| Spanned { value, span })
+    }
+
+    pub fn value(&self) -> &LoxValue {
+        &self.0
+    }
+
+    /// Normalize numbers so that keys that compare equal also hash the same:
+    /// `0` and `-0` are the same key, and so are all NaNs.
+    pub(crate) fn num_bits(num: f64) -> u64 {
+        if num == 0.0 {
+            0.0f64.to_bits()
+        } else if num.is_nan() {
+            f64::NAN.to_bits()
+        } else {
+            num.to_bits()
+        }
+    }
+}
+
+impl TryFrom<LoxValue> for HashKey {
+    type Error = RuntimeError;
+
+    fn try_from(value: LoxValue) -> Result<Self, RuntimeError> {
+        match value {
+            LoxValue::Nil | LoxValue::Bool(_) | LoxValue::Num(_) => Ok(Self(value)),
+
+            // Keys get interned, so looking them up mostly compares pointers
+            LoxValue::Str(string) => Ok(Self(LoxValue::Str(string.key()))),
+
+            _ => Err(RuntimeError::UnhashableKey(value.type_name())),
+        }
+    }
+}
+
+impl Hash for HashKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        core::mem::discriminant(&self.0).hash(state);
+
+        match &self.0 {
+            LoxValue::Bool(val) => val.hash(state),
+            LoxValue::Num(val) => Self::num_bits(*val).hash(state),
+            LoxValue::Str(val) => val.hash(state),
+            _ => {}
+        }
+    }
+}
+
+impl PartialEq for HashKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (LoxValue::Num(left), LoxValue::Num(right)) => {
+                Self::num_bits(*left) == Self::num_bits(*right)
+            },
+            (left, right) => left == right,
+        }
+    }
+}
+
+impl Eq for HashKey {}
+
+/// The entries of a map, kept in insertion order.
+///
+/// Generic over the key and value types, so the bytecode VM can store its own
+/// values in the same kind of map.
+#[derive(Debug)]
+pub struct Entries<K = HashKey, V = LoxValue> {
+    entries: Vec<(K, V)>,
+    indices: HashMap<K, usize>,
+}
+
+impl<K, V> Default for Entries<K, V> {
+    fn default() -> Self {
+        Self { entries: Vec::new(), indices: HashMap::new() }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> Entries<K, V> {
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.indices.get(key).map(|&idx| &self.entries[idx].1)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if let Some(&idx) = self.indices.get(&key) {
+            self.entries[idx].1 = value;
+        } else {
+            self.indices.insert(key.clone(), self.entries.len());
+            self.entries.push((key, value));
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let idx = self.indices.remove(key)?;
+        let (_, value) = self.entries.remove(idx);
+
+        // Everything after the removed entry moved one place to the left
+        for (key, _) in &self.entries[idx..] {
+            *self.indices.get_mut(key).unwrap() -= 1;
+        }
+
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    pub fn into_values(self) -> impl Iterator<Item = V> {
+        self.entries.into_iter().map(|(_, value)| value)
+    }
+}
+
+/// A hash map from keys to values. Like lists, maps have reference semantics.
+#[derive(Debug, Clone, Default)]
+pub struct Map(pub Rc<RefCell<Entries>>);
+
+impl Map {
+    pub fn new(entries: Entries, heap: &mut Heap) -> Self {
+        Self(heap.alloc(RefCell::new(entries)))
+    }
+
+    /// Read the value stored under `key`, where the bracket token is used to
+    /// report errors.
+    pub fn get(&self, key: LoxValue, bracket: &Token) -> Result<LoxValue, Spanned<RuntimeError>> {
+        let key = HashKey::new(key, bracket.span)?;
+
+        self.0.borrow().get(&key).cloned().ok_or_else(|| Spanned {
+            value: RuntimeError::UndefinedKey(key.value().repr()),
+            span: bracket.span,
+        })
+    }
+
+    /// Store a value under `key`, where the bracket token is used to report
+    /// errors.
+    pub fn set(
+        &self,
+        key: LoxValue,
+        value: LoxValue,
+        bracket: &Token
+    ) -> Result<(), Spanned<RuntimeError>> {
+        let key = HashKey::new(key, bracket.span)?;
+        self.0.borrow_mut().insert(key, value);
+        Ok(())
+    }
+
+    /// Look up one of the built-in map methods, bound to this map.
+    pub fn get_method(&self, name: &Token, heap: &mut Heap) -> Result<LoxValue, Spanned<RuntimeError>> {
+        let method = match name.name().as_str() {
+            "keys" => MapMethod::Keys,
+            "values" => MapMethod::Values,
+            "has" => MapMethod::Has,
+            "remove" => MapMethod::Remove,
+            "len" => MapMethod::Len,
+            _ => return Err(Spanned {
+                value: RuntimeError::UndefinedProperty(name.to_string()),
+                span: name.span,
+            }),
+        };
+
+        Ok(LoxValue::NativeFunction(heap.alloc(BoundMapMethod {
+            map: self.clone(),
+            method,
+            span: name.span,
+        })))
+    }
+}
+
+impl Trace for RefCell<Entries> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let Ok(entries) = self.try_borrow() else {
+            return tracer.busy();
+        };
+
+        for (key, value) in entries.iter() {
+            key.value().trace(tracer);
+            value.trace(tracer);
+        }
+    }
+
+    fn clear(&self) {
+        let entries = std::mem::take(&mut *self.borrow_mut());
+        drop(entries);
+    }
+}
+
+/// Maps nested too deeply in each other get dropped without recursing. See
+/// `drop_nested`.
+impl Drop for Map {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.0) > 1 {
+            return;
+        }
+
+        if let Ok(mut entries) = self.0.try_borrow_mut() {
+            drop_nested(std::mem::take(&mut *entries).into_values().collect());
+        }
+    }
+}
+
+/// A map that contains itself shows up as `{...}` inside itself, as does one
+/// nested too deeply to print.
+impl Display for Map {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Ok(_guard) = CycleGuard::enter(Rc::as_ptr(&self.0)) else {
+            return write!(f, "{{...}}");
+        };
+
+        write!(f, "{{")?;
+
+        for (idx, (key, value)) in self.0.borrow().iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{}: {}", key.value().repr(), value.repr())?;
+        }
+
+        write!(f, "}}")
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum MapMethod {
+    Keys,
+    Values,
+    Has,
+    Remove,
+    Len,
+}
+
+impl MapMethod {
+    fn name(&self) -> &'static str {
+        match self {
+            MapMethod::Keys => "keys",
+            MapMethod::Values => "values",
+            MapMethod::Has => "has",
+            MapMethod::Remove => "remove",
+            MapMethod::Len => "len",
+        }
+    }
+}
+
+/// A built-in map method, together with the map it was accessed on.
+#[derive(Debug)]
+struct BoundMapMethod {
+    map: Map,
+    method: MapMethod,
+
+    /// The span of the method name, used for reporting errors
+    span: Span,
+}
+
+impl Call for BoundMapMethod {
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        args: &[LoxValue],
+        _call_site: Span,
+    ) -> Result<LoxValue, Spanned<RuntimeError>> {
+        let entries = &self.map.0;
+
+        match self.method {
+            MapMethod::Keys => {
+                let keys = entries.borrow().iter().map(|(key, _)| key.value().clone()).collect();
+                Ok(LoxValue::List(List::new(keys, &mut interpreter.heap)))
+            },
+
+            MapMethod::Values => {
+                let values = entries.borrow().iter().map(|(_, value)| value.clone()).collect();
+                Ok(LoxValue::List(List::new(values, &mut interpreter.heap)))
+            },
+
+            MapMethod::Has => {
+                let key = HashKey::new(args[0].clone(), self.span)?;
+                Ok(LoxValue::Bool(entries.borrow().get(&key).is_some()))
+            },
+
+            MapMethod::Remove => {
+                let key = HashKey::new(args[0].clone(), self.span)?;
+                Ok(entries.borrow_mut().remove(&key).unwrap_or(LoxValue::Nil))
+            },
+
+            MapMethod::Len => Ok(LoxValue::Num(entries.borrow().len() as f64)),
+        }
+    }
+
+    fn arity(&self) -> Arity {
+        match self.method {
+            MapMethod::Keys | MapMethod::Values | MapMethod::Len => Arity::Exactly(0),
+            MapMethod::Has | MapMethod::Remove => Arity::Exactly(1),
+        }
+    }
+}
+
+impl Trace for BoundMapMethod {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.edge(&self.map.0);
+    }
+}
+
+impl Display for BoundMapMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn: {}>", self.method.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    use super::*;
+    use crate::symbol::Symbol;
+
+    fn key(value: LoxValue) -> HashKey {
+        HashKey::new(value, Span::new()).ok().unwrap()
+    }
+
+    #[test]
+    fn keys_compare_by_value() {
+        let mut entries = Entries::default();
+        entries.insert(key(LoxValue::Str("a".into())), LoxValue::Num(1.0));
+        entries.insert(key(LoxValue::Num(0.0)), LoxValue::Num(2.0));
+
+        assert!(entries.get(&key(LoxValue::Str(Symbol::intern("a").into()))).is_some());
+        assert!(entries.get(&key(LoxValue::Num(-0.0))).is_some());
+        assert!(entries.get(&key(LoxValue::Bool(false))).is_none());
+        assert!(entries.get(&key(LoxValue::Nil)).is_none());
+    }
+
+    #[test]
+    fn equal_numbers_hash_the_same() {
+        let hash = |value: LoxValue| {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        assert_eq!(hash(LoxValue::Num(0.0)), hash(LoxValue::Num(-0.0)));
+    }
+
+    #[test]
+    fn removal_keeps_insertion_order() {
+        let mut entries = Entries::default();
+
+        for n in 0..4 {
+            entries.insert(key(LoxValue::Num(n as f64)), LoxValue::Nil);
+        }
+
+        entries.remove(&key(LoxValue::Num(1.0)));
+        entries.insert(key(LoxValue::Num(1.0)), LoxValue::Nil);
+
+        let keys: Vec<_> = entries.iter().map(|(key, _)| key.value().to_string()).collect();
+        assert_eq!(keys, ["0", "2", "3", "1"]);
+        assert!(entries.get(&key(LoxValue::Num(3.0))).is_some());
+    }
+
+    #[test]
+    fn reference_types_are_unhashable() {
+        assert!(HashKey::new(LoxValue::List(List::default()), Span::new()).is_err());
+        assert!(HashKey::new(LoxValue::Map(Map::default()), Span::new()).is_err());
+    }
+}
@@ -1,61 +1,149 @@
 use std::collections::HashMap;
 use std::fmt::Display;
 
-use crate::sourcemap::Source;
 use crate::span::Spanned;
-use crate::syntax::ast::{Ast, Expr, Stmt};
+use crate::symbol::Symbol;
+use crate::syntax::ast::{Ast, Expr, NodeId, Stmt};
 use crate::syntax::tokens::Token;
 
 use super::Visitor;
 
-pub struct Resolver<'a> {
-    source: &'a Source<'a>,
-    scopes: Vec<HashMap<String, bool>>,
-    pub locals: HashMap<&'a Expr, usize>,
+/// How a variable reference was resolved.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// A local variable, declared `depth` scopes up from the reference, in
+    /// the given slot of that scope
+    Local { depth: usize, slot: usize },
 
+    /// A global variable, which gets looked up when the code runs
+    Global,
 }
 
+/// A variable declared in a local scope.
+#[derive(Copy, Clone)]
+struct Local {
+    slot: usize,
+
+    /// Whether the variable's initializer has been resolved
+    defined: bool,
+}
+
+#[derive(Default)]
+struct Scope {
+    variables: HashMap<Symbol, Local>,
+
+    /// The number of slots handed out in this scope so far
+    slots: usize,
+}
+
+impl Scope {
+    /// Give a newly declared variable the next slot. Redeclaring a variable
+    /// creates a new one, which shadows the old one.
+    fn declare(&mut self, name: Symbol, defined: bool) -> usize {
+        let slot = self.slots;
+        self.slots += 1;
+        self.variables.insert(name, Local { slot, defined });
+        slot
+    }
+}
+
+/// The results of resolving a chunk of code, keyed by the ids of the nodes
+/// they belong to.
+#[derive(Debug, Default)]
+pub struct Resolutions {
+    /// How every variable reference (including `this` and `super`) resolved
+    pub variables: HashMap<NodeId, Resolution>,
+
+    /// The slots of variables declared in local scopes
+    pub declarations: HashMap<NodeId, usize>,
+}
+
+pub struct Resolver {
+    scopes: Vec<Scope>,
+    resolutions: Resolutions,
+    current_class: ClassType,
+    current_function: FunctionType,
+    loop_depth: usize,
+    errors: Vec<Spanned<ResolutionError>>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
 enum FunctionType {
+    None,
     Function,
     Method,
+    Initializer,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
 }
 
 type ResolutionResult = Result<(), Spanned<ResolutionError>>;
 
-impl<'a> Resolver<'a> {
-    pub fn new(source: &'a Source<'a>) -> Self {
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Self {
         Self {
-            source,
             scopes: Vec::new(),
-            locals: HashMap::new(),
+            resolutions: Resolutions::default(),
+            current_class: ClassType::None,
+            current_function: FunctionType::None,
+            loop_depth: 0,
+            errors: Vec::new(),
         }
     }
 
-    fn error(&self, spanned: Spanned<ResolutionError>) {
-        eprintln!("{}", self.source.annotate(spanned));
+    fn error(&mut self, spanned: Spanned<ResolutionError>) {
+        self.errors.push(spanned);
+    }
+
+    /// Hand over the resolver's results, or every error it ran into.
+    pub fn resolutions(self) -> Result<Resolutions, Vec<Spanned<ResolutionError>>> {
+        if self.errors.is_empty() {
+            Ok(self.resolutions)
+        } else {
+            Err(self.errors)
+        }
     }
 
     fn push_scope(&mut self) {
-        self.scopes.push(HashMap::new());
+        self.scopes.push(Scope::default());
     }
 
     fn pop_scope(&mut self) {
         self.scopes.pop();
     }
 
-    fn declare(&mut self, name: &Token) {
+    /// Declare a variable in the innermost scope. Only declarations with an
+    /// id (i.e., not function parameters) get their slot recorded.
+    fn declare(&mut self, id: Option<NodeId>, name: &Token) {
         if let Some(scope) = self.scopes.last_mut() {
-            scope.insert(name.lexeme.clone(), false);
+            let slot = scope.declare(name.name(), false);
+
+            if let Some(id) = id {
+                self.resolutions.declarations.insert(id, slot);
+            }
         }
     }
 
     fn define(&mut self, name: &Token) {
         if let Some(scope) = self.scopes.last_mut() {
-            scope.insert(name.lexeme.clone(), true);
+            if let Some(local) = scope.variables.get_mut(&name.name()) {
+                local.defined = true;
+            }
         }
     }
 
-    fn resolve_many(&mut self, statements: &'a [Stmt]) -> ResolutionResult {
+    fn resolve_many(&mut self, statements: &[Stmt]) -> ResolutionResult {
         for statement in statements {
             self.visit(statement)?;
         }
@@ -65,45 +153,57 @@ impl<'a> Resolver<'a> {
 
     fn resolve_fun(
         &mut self,
-        _fun_type: FunctionType,
-        _name: &Token,
+        fun_type: FunctionType,
         params: &[Token],
-        body: &'a [Stmt]
+        body: &[Stmt]
     ) -> ResolutionResult {
+        let enclosing_function = self.current_function;
+        self.current_function = fun_type;
+
+        // Loops don't extend into function bodies
+        let enclosing_loop_depth = std::mem::take(&mut self.loop_depth);
+
         self.push_scope();
 
+        // Parameters fill the first slots, in order
         for param in params {
-            self.declare(param);
+            self.declare(None, param);
             self.define(param);
         }
 
         self.resolve_many(body)?;
 
         self.pop_scope();
+        self.current_function = enclosing_function;
+        self.loop_depth = enclosing_loop_depth;
 
         Ok(())
     }
 
-    fn resolve_class(&mut self, name: &Token) -> ResolutionResult {
-        self.declare(name);
+    fn resolve_class(&mut self, id: NodeId, name: &Token) -> ResolutionResult {
+        self.declare(Some(id), name);
         self.define(name);
         Ok(())
     }
 
-    pub fn resolve_local(&mut self, expr: &'a Expr, name: &Token) {
-        for (i, scope) in self.scopes.iter().rev().enumerate() {
-            if scope.contains_key(&name.lexeme) {
-                self.locals.insert(expr, i);
-                break;
-            }
-        }
+    pub fn resolve_local(&mut self, id: NodeId, name: &Token) {
+        let resolution = self.scopes.iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| {
+                let local = scope.variables.get(&name.name())?;
+                Some(Resolution::Local { depth, slot: local.slot })
+            })
+            .unwrap_or(Resolution::Global);
+
+        self.resolutions.variables.insert(id, resolution);
     }
 }
 
-impl<'a> Visitor<&'a Stmt> for Resolver<'a> {
+impl Visitor<&Stmt> for Resolver {
     type Output = Result<(), Spanned<ResolutionError>>;
 
-    fn visit(&mut self, stmt: &'a Stmt) -> ResolutionResult {
+    fn visit(&mut self, stmt: &Stmt) -> ResolutionResult {
         match stmt {
             Stmt::Block { statements } => {
                 self.push_scope();
@@ -111,21 +211,21 @@ impl<'a> Visitor<&'a Stmt> for Resolver<'a> {
                 self.pop_scope();
             },
 
-            Stmt::Var { name, initializer } => {
-                self.declare(&name);
+            Stmt::Var { id, name, initializer } => {
+                self.declare(Some(*id), name);
 
                 if let Some(initializer) = initializer {
                     self.visit(initializer)?;
                 }
 
-                self.define(&name);
+                self.define(name);
             },
 
-            Stmt::Fun { name, params, body } => {
-                self.declare(name);
+            Stmt::Fun { id, name, params, body } => {
+                self.declare(Some(*id), name);
                 self.define(name);
 
-                self.resolve_fun(FunctionType::Function, name, params, body)?;
+                self.resolve_fun(FunctionType::Function, params, body)?;
             },
 
             Stmt::Expression { expr } => {
@@ -144,31 +244,102 @@ impl<'a> Visitor<&'a Stmt> for Resolver<'a> {
                 self.visit(expr)?;
             },
 
-            Stmt::Return { expr, .. } => {
+            Stmt::Return { keyword, expr } => {
+                if self.current_function == FunctionType::None {
+                    self.error(Spanned {
+                        value: ResolutionError::TopLevelReturn,
+                        span: keyword.span,
+                    });
+                }
+
                 if let Some(expr) = expr {
+                    if self.current_function == FunctionType::Initializer {
+                        self.error(Spanned {
+                            value: ResolutionError::ReturnFromInitializer,
+                            span: keyword.span,
+                        });
+                    }
+
                     self.visit(expr)?;
                 }
             },
 
-            Stmt::While { condition, body } => {
+            Stmt::While { condition, body, increment } => {
                 self.visit(condition)?;
+
+                self.loop_depth += 1;
                 self.visit(body.as_ref())?;
+                self.loop_depth -= 1;
+
+                if let Some(increment) = increment {
+                    self.visit(increment)?;
+                }
+            },
+
+            Stmt::Break { keyword } => {
+                if self.loop_depth == 0 {
+                    self.error(Spanned {
+                        value: ResolutionError::LoopControlOutsideLoop("break"),
+                        span: keyword.span,
+                    });
+                }
+            },
+
+            Stmt::Continue { keyword } => {
+                if self.loop_depth == 0 {
+                    self.error(Spanned {
+                        value: ResolutionError::LoopControlOutsideLoop("continue"),
+                        span: keyword.span,
+                    });
+                }
             },
 
-            Stmt::Class { name, methods } => {
-                self.resolve_class(name)?;
+            Stmt::Class { id, name, superclass, methods } => {
+                let enclosing_class = self.current_class;
+                self.current_class = ClassType::Class;
+
+                self.resolve_class(*id, name)?;
+
+                if let Some(superclass) = superclass {
+                    let Expr::Variable { name: super_name, .. } = superclass else { unreachable!() };
+
+                    if super_name.name() == name.name() {
+                        self.error(Spanned {
+                            value: ResolutionError::SelfInheritance,
+                            span: super_name.span,
+                        });
+                    }
+
+                    self.current_class = ClassType::Subclass;
+                    self.visit(superclass)?;
+
+                    self.push_scope();
+                    self.scopes.last_mut().unwrap().declare(Symbol::SUPER, true);
+                }
 
                 self.push_scope();
 
-                self.scopes.last_mut().unwrap().insert("this".to_owned(), true);
+                self.scopes.last_mut().unwrap().declare(Symbol::THIS, true);
 
                 for method in methods {
-                    if let Stmt::Fun { name, params, body } = method {
-                        self.resolve_fun(FunctionType::Method, name, params, body)?;
+                    if let Stmt::Fun { name, params, body, .. } = method {
+                        let fun_type = if name.name() == Symbol::INIT {
+                            FunctionType::Initializer
+                        } else {
+                            FunctionType::Method
+                        };
+
+                        self.resolve_fun(fun_type, params, body)?;
                     }
                 }
 
                 self.pop_scope();
+
+                if superclass.is_some() {
+                    self.pop_scope();
+                }
+
+                self.current_class = enclosing_class;
             }
         }
 
@@ -176,14 +347,14 @@ impl<'a> Visitor<&'a Stmt> for Resolver<'a> {
     }
 }
 
-impl<'a> Visitor<&'a Expr> for Resolver<'a> {
+impl Visitor<&Expr> for Resolver {
     type Output = Result<(), Spanned<ResolutionError>>;
 
-    fn visit(&mut self, expr: &'a Expr) -> ResolutionResult {
+    fn visit(&mut self, expr: &Expr) -> ResolutionResult {
         match expr {
-            Expr::Variable { name } => {
+            Expr::Variable { id, name } => {
                 if let Some(scope) = self.scopes.last() {
-                    if scope.get(&name.lexeme).is_some_and(|v| !v) {
+                    if scope.variables.get(&name.name()).is_some_and(|local| !local.defined) {
                         self.error(Spanned {
                             value: ResolutionError::RecursiveVarDecl,
                             span: name.span,
@@ -191,12 +362,12 @@ impl<'a> Visitor<&'a Expr> for Resolver<'a> {
                     }
                 }
 
-                self.resolve_local(&expr, name);
+                self.resolve_local(*id, name);
             },
 
-            Expr::Assignment { name, value } => {
+            Expr::Assignment { id, name, value } => {
                 self.visit(value.as_ref())?;
-                self.resolve_local(expr, name);
+                self.resolve_local(*id, name);
             },
 
             Expr::Binary { left, right, .. } => {
@@ -218,6 +389,40 @@ impl<'a> Visitor<&'a Expr> for Resolver<'a> {
 
             Expr::Literal { .. } => {},
 
+            Expr::Interpolation { parts, .. } => {
+                for part in parts {
+                    self.visit(part)?;
+                }
+            },
+
+            Expr::List { elements, .. } => {
+                for element in elements {
+                    self.visit(element)?;
+                }
+            },
+
+            Expr::Lambda { params, body, .. } => {
+                self.resolve_fun(FunctionType::Function, params, body)?;
+            },
+
+            Expr::Map { entries, .. } => {
+                for (key, value) in entries {
+                    self.visit(key)?;
+                    self.visit(value)?;
+                }
+            },
+
+            Expr::Index { object, index, .. } => {
+                self.visit(object.as_ref())?;
+                self.visit(index.as_ref())?;
+            },
+
+            Expr::SetIndex { object, index, value, .. } => {
+                self.visit(object.as_ref())?;
+                self.visit(index.as_ref())?;
+                self.visit(value.as_ref())?;
+            },
+
             Expr::Logical { left, right, .. } => {
                 self.visit(left.as_ref())?;
                 self.visit(right.as_ref())?;
@@ -235,8 +440,24 @@ impl<'a> Visitor<&'a Expr> for Resolver<'a> {
                 self.visit(object.as_ref())?;
             },
 
-            Expr::This { keyword  } => {
-                self.resolve_local(expr, keyword);
+            Expr::This { id, keyword } => {
+                self.resolve_local(*id, keyword);
+            }
+
+            Expr::Super { id, keyword, .. } => {
+                match self.current_class {
+                    ClassType::None => self.error(Spanned {
+                        value: ResolutionError::SuperOutsideClass,
+                        span: keyword.span,
+                    }),
+                    ClassType::Class => self.error(Spanned {
+                        value: ResolutionError::SuperWithoutSuperclass,
+                        span: keyword.span,
+                    }),
+                    ClassType::Subclass => {},
+                }
+
+                self.resolve_local(*id, keyword);
             }
         }
 
@@ -244,25 +465,132 @@ impl<'a> Visitor<&'a Expr> for Resolver<'a> {
     }
 }
 
-impl<'a> Visitor<&'a Ast> for Resolver<'a> {
+impl Visitor<&Ast> for Resolver {
     type Output = ResolutionResult;
 
-    fn visit(&mut self, ast: &'a Ast) -> ResolutionResult {
+    fn visit(&mut self, ast: &Ast) -> ResolutionResult {
         self.resolve_many(ast)?;
 
         Ok(())
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub enum ResolutionError {
     RecursiveVarDecl,
+    SelfInheritance,
+    SuperOutsideClass,
+    SuperWithoutSuperclass,
+    ReturnFromInitializer,
+    TopLevelReturn,
+    LoopControlOutsideLoop(&'static str),
 }
 
 impl Display for ResolutionError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ResolutionError::RecursiveVarDecl => write!(f, "Can't read local variable in its own initializer"),
+            ResolutionError::SelfInheritance => write!(f, "A class can't inherit from itself"),
+            ResolutionError::SuperOutsideClass => write!(f, "Can't use 'super' outside of a class"),
+            ResolutionError::SuperWithoutSuperclass => write!(f, "Can't use 'super' in a class with no superclass"),
+            ResolutionError::ReturnFromInitializer => write!(f, "Can't return a value from an initializer"),
+            ResolutionError::TopLevelReturn => write!(f, "Can't return from top-level code"),
+            ResolutionError::LoopControlOutsideLoop(keyword) => write!(f, "Can't use '{keyword}' outside of a loop"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sourcemap::Source;
+    use crate::syntax::parser::Parser;
+    use crate::syntax::tokenizer::Scanner;
+
+    use super::*;
+    use super::Resolution::{Global, Local};
+
+    /// Resolve a program, and list how every variable reference resolved, in
+    /// the order they appear in the source.
+    fn resolve(input: &str) -> Vec<Resolution> {
+        let source = Source::new(input);
+        let mut scanner = Scanner::new(&source);
+        let ast = Parser::new(&source, &mut scanner).parse().unwrap();
+
+        let mut resolver = Resolver::new();
+        let _ = resolver.visit(&ast);
+        let Ok(resolutions) = resolver.resolutions() else { panic!("failed to resolve") };
+
+        let mut variables: Vec<_> = resolutions.variables.into_iter().collect();
+        variables.sort_by_key(|&(id, _)| id);
+        variables.into_iter().map(|(_, resolution)| resolution).collect()
+    }
+
+    #[test]
+    fn same_name_at_different_depths() {
+        let resolutions = resolve("{ var a = 1; { var b = 2; print a; } print a; }");
+        assert_eq!(resolutions, vec![Local { depth: 1, slot: 0 }, Local { depth: 0, slot: 0 }]);
+    }
+
+    #[test]
+    fn closures_resolve_before_shadowing() {
+        let resolutions = resolve("
+            var a = 1;
+            {
+                fun show() { print a; }
+                var a = 2;
+                print a;
+            }
+        ");
+
+        assert_eq!(resolutions, vec![Global, Local { depth: 0, slot: 1 }]);
+    }
+
+    #[test]
+    fn redeclarations_shadow_in_a_new_slot() {
+        let resolutions = resolve("{ var a = 1; print a; var a = 2; print a; }");
+        assert_eq!(resolutions, vec![Local { depth: 0, slot: 0 }, Local { depth: 0, slot: 1 }]);
+    }
+
+    #[test]
+    fn skipped_declarations_keep_their_slot() {
+        let resolutions = resolve("{ if (false) fun f() {} var a = 1; print a; }");
+        assert_eq!(resolutions, vec![Local { depth: 0, slot: 1 }]);
+    }
+
+    #[test]
+    fn this_and_super() {
+        let resolutions = resolve("
+            class A { m() {} }
+            class B < A { m() { this; super.m(); } }
+        ");
+
+        assert_eq!(resolutions, vec![
+            Global,
+            Local { depth: 1, slot: 0 },
+            Local { depth: 2, slot: 0 },
+        ]);
+    }
+
+    #[test]
+    fn identical_chunks_get_distinct_ids() {
+        let mut source = Source::default();
+        let mut next_id = 0;
+        let mut ids = Vec::new();
+
+        for _ in 0..2 {
+            let offset = source.push("{ var a; a; }");
+            let mut scanner = Scanner::new_at(&source, offset);
+            let mut parser = Parser::new(&source, &mut scanner).with_first_id(next_id);
+            let ast = parser.parse().unwrap();
+            next_id = parser.next_id();
+
+            let mut resolver = Resolver::new();
+            let _ = resolver.visit(&ast);
+            let Ok(resolutions) = resolver.resolutions() else { panic!("failed to resolve") };
+            ids.extend(resolutions.variables.into_keys());
         }
+
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
     }
 }
@@ -1,23 +1,23 @@
 use std::{collections::HashMap, rc::Rc};
 
-use crate::syntax::ast::Stmt;
+use crate::syntax::ast::{Expr, Stmt};
 use crate::span::Spanned;
-use crate::span::Span;
+use crate::symbol::Symbol;
 use super::functions::LoxFunction;
 use super::environment::Env;
 use super::class::Class;
 use super::value::LoxValue;
 
-use super::{Interpreter, LoxResult, Visitor, RuntimeError};
+use super::{Interpreter, ExecResult, Visitor, RuntimeError, Unwind, STACK_RED_ZONE, STACK_SEGMENT_SIZE};
 
-impl<'a> Visitor<&Stmt> for Interpreter<'a> {
-    type Output = LoxResult;
+impl Visitor<&Stmt> for Interpreter {
+    type Output = ExecResult;
 
-    fn visit(&mut self, statement: &Stmt) -> LoxResult {
+    fn visit(&mut self, statement: &Stmt) -> ExecResult {
         match statement {
             Stmt::Print { expr } => {
                 let val = self.evaluate(expr)?;
-                println!("{val}");
+                self.print(&val, expr.span())?;
             }
 
             Stmt::Return { expr, .. } => {
@@ -27,12 +27,13 @@ impl<'a> Visitor<&Stmt> for Interpreter<'a> {
                     LoxValue::Nil
                 };
 
-                Err(Spanned {
-                    value: RuntimeError::Return(value),
-                    span: Span::new(),
-                })?;
+                return Err(Unwind::Return(value));
             }
 
+            Stmt::Break { .. } => return Err(Unwind::Break),
+
+            Stmt::Continue { .. } => return Err(Unwind::Continue),
+
             Stmt::If { condition, then_branch, else_branch } => {
                 if self.evaluate(condition)?.is_truthy() {
                     self.execute(then_branch)?;
@@ -41,9 +42,17 @@ impl<'a> Visitor<&Stmt> for Interpreter<'a> {
                 }
             }
 
-            Stmt::While { condition, body } => {
+            Stmt::While { condition, body, increment } => {
                 while self.evaluate(condition)?.is_truthy() {
-                    self.execute(body)?;
+                    match self.execute(body) {
+                        Err(Unwind::Break) => break,
+                        Err(Unwind::Continue) | Ok(()) => {},
+                        Err(unwind) => return Err(unwind),
+                    }
+
+                    if let Some(increment) = increment {
+                        self.evaluate(increment)?;
+                    }
                 }
             }
 
@@ -51,65 +60,93 @@ impl<'a> Visitor<&Stmt> for Interpreter<'a> {
                self.evaluate(expr)?;
             }
 
-            Stmt::Var { name, initializer } => {
+            Stmt::Var { id, name, initializer } => {
                 let value = if let Some(expr) = initializer {
                     self.evaluate(expr)?
                 } else {
                     LoxValue::Nil
                 };
 
-                self.env.define(name.lexeme.clone(), value);
+                self.define(*id, name, value);
             }
 
             Stmt::Block { statements } => {
                 self.exec_block(statements)?;
             }
 
-            Stmt::Fun { name, params, body } => {
+            Stmt::Fun { id, name, params, body } => {
                 let function = LoxFunction {
-                    name: name.clone(),
+                    name: Some(name.clone()),
                     params: params.clone(),
                     body: body.clone(),
                     env: self.env.clone(),
+                    is_initializer: false,
                 };
 
-                self.env.define(name.lexeme.clone(), LoxValue::Function(Rc::new(function)));
+                let function = self.heap.alloc(function);
+                self.define(*id, name, LoxValue::Function(function));
             },
 
-            Stmt::Class { name, methods } => {
-                self.env.define(name.lexeme.clone(), LoxValue::Nil);
+            Stmt::Class { id, name, superclass, methods } => {
+                let superclass = if let Some(superclass) = superclass {
+                    let Expr::Variable { name: super_name, .. } = superclass else { unreachable!() };
+
+                    match self.evaluate(superclass)? {
+                        LoxValue::Class(class) => Some(class),
+                        _ => Err(Spanned {
+                            value: RuntimeError::SuperclassNotClass,
+                            span: super_name.span,
+                        })?,
+                    }
+                } else {
+                    None
+                };
+
+                // Methods of a subclass close over an extra scope that holds
+                // the superclass, so `super` can be resolved like any other
+                // local.
+                if let Some(superclass) = &superclass {
+                    self.push_scope();
+                    self.env.define(0, LoxValue::Class(superclass.clone()));
+                }
 
                 let mut methods_map = HashMap::new();
 
                 for method in methods {
-                    let Stmt::Fun { name, params, body } = method else { panic!() };
+                    let Stmt::Fun { name, params, body, .. } = method else { panic!() };
 
                     let function = LoxFunction {
-                        name: name.clone(),
+                        name: Some(name.clone()),
                         params: params.clone(),
                         body: body.clone(),
                         env: self.env.clone(),
+                        is_initializer: name.name() == Symbol::INIT,
                     };
 
-                    methods_map.insert(name.lexeme.clone(), Rc::new(function));
+                    methods_map.insert(name.name(), self.heap.alloc(function));
+                }
+
+                if superclass.is_some() {
+                    self.pop_scope();
                 }
 
-                let class = Class { name: name.clone(), methods: methods_map };
-                self.env.assign(name, LoxValue::Class(Rc::new(class)))?;
+                let class = Class { name: name.clone(), superclass, methods: methods_map };
+                let class = self.heap.alloc(class);
+                self.define(*id, name, LoxValue::Class(class));
             }
         };
 
-        Ok(LoxValue::Nil)
+        Ok(())
     }
 
 }
 
-impl<'a> Interpreter<'a> {
-    fn execute(&mut self, statement: &Stmt) -> LoxResult {
-        self.visit(statement)
+impl Interpreter {
+    fn execute(&mut self, statement: &Stmt) -> ExecResult {
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || self.visit(statement))
     }
 
-    fn exec_block(&mut self, statements: &Vec<Stmt>) -> LoxResult {
+    fn exec_block(&mut self, statements: &[Stmt]) -> ExecResult {
         self.push_scope();
 
         for statement in statements.iter() {
@@ -120,11 +157,11 @@ impl<'a> Interpreter<'a> {
         }
 
         self.pop_scope();
-        Ok(LoxValue::Nil)
+        Ok(())
     }
 
     // Additional helper that allows us to execute a block with a given environment.
-    pub fn exec_block_with_env(&mut self, statements: &Vec<Stmt>, env: Rc<Env>) -> LoxResult {
+    pub fn exec_block_with_env(&mut self, statements: &[Stmt], env: Rc<Env>) -> ExecResult {
         let prev_env = std::mem::replace(&mut self.env, env);
 
         for statement in statements.iter() {
@@ -135,6 +172,6 @@ impl<'a> Interpreter<'a> {
         }
 
         self.env = prev_env;
-        Ok(LoxValue::Nil)
+        Ok(())
     }
 }
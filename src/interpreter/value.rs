@@ -2,23 +2,30 @@ use std::{fmt::Display, rc::Rc};
 use std::hash::Hash;
 
 use super::RuntimeError;
+use crate::gc::Tracer;
 use crate::span::Spanned;
+use crate::symbol::LoxStr;
 use crate::syntax::ast::Literal;
 use crate::syntax::tokens::Token;
+use crate::util::Nested;
 use super::functions::LoxFunction;
 use super::functions::Call;
 use super::class::{Class, Instance};
+use super::list::List;
+use super::map::{HashKey, Map};
 
 #[derive(Debug, Clone)]
 pub enum LoxValue {
     Nil,
     Bool(bool),
     Num(f64),
-    Str(Rc<String>),
+    Str(LoxStr),
     NativeFunction(Rc<dyn Call>),
     Function(Rc<LoxFunction>),
     Class(Rc<Class>),
     Instance(Instance),
+    List(List),
+    Map(Map),
 }
 
 impl PartialEq for LoxValue {
@@ -55,15 +62,62 @@ impl PartialEq for LoxValue {
             return Rc::ptr_eq(left, right);
         }
 
+        if let (Self::List(left), Self::List(right)) = (&self, &other) {
+            return Rc::ptr_eq(&left.0, &right.0);
+        }
+
+        if let (Self::Map(left), Self::Map(right)) = (&self, &other) {
+            return Rc::ptr_eq(&left.0, &right.0);
+        }
+
+        if let (Self::Instance(left), Self::Instance(right)) = (&self, &other) {
+            return Rc::ptr_eq(&left.0, &right.0);
+        }
+
         false
     }
 }
 
 impl Eq for LoxValue {}
 
+/// Hashing is consistent with equality: primitive values hash their contents,
+/// while everything else hashes the identity of the object it points to.
 impl Hash for LoxValue {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        core::mem::discriminant(self).hash(state)
+        core::mem::discriminant(self).hash(state);
+
+        match self {
+            LoxValue::Nil => {},
+            LoxValue::Bool(val) => val.hash(state),
+            LoxValue::Num(val) => HashKey::num_bits(*val).hash(state),
+            LoxValue::Str(val) => val.hash(state),
+            LoxValue::NativeFunction(val) => Rc::as_ptr(val).cast::<()>().hash(state),
+            LoxValue::Function(val) => Rc::as_ptr(val).hash(state),
+            LoxValue::Class(val) => Rc::as_ptr(val).hash(state),
+            LoxValue::Instance(val) => Rc::as_ptr(&val.0).hash(state),
+            LoxValue::List(val) => Rc::as_ptr(&val.0).hash(state),
+            LoxValue::Map(val) => Rc::as_ptr(&val.0).hash(state),
+        }
+    }
+}
+
+impl Nested for LoxValue {
+    fn take_contents(&self, into: &mut Vec<Self>) {
+        match self {
+            LoxValue::List(list) if Rc::strong_count(&list.0) == 1 => {
+                if let Ok(mut values) = list.0.try_borrow_mut() {
+                    into.append(&mut values);
+                }
+            },
+
+            LoxValue::Map(map) if Rc::strong_count(&map.0) == 1 => {
+                if let Ok(mut entries) = map.0.try_borrow_mut() {
+                    into.extend(std::mem::take(&mut *entries).into_values());
+                }
+            },
+
+            _ => {},
+        }
     }
 }
 
@@ -96,6 +150,44 @@ impl LoxValue {
         }
     }
 
+    /// A human-readable name for the type of the value, for use in error
+    /// messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LoxValue::Nil => "nil",
+            LoxValue::Bool(_) => "bool",
+            LoxValue::Num(_) => "number",
+            LoxValue::Str(_) => "string",
+            LoxValue::NativeFunction(_) | LoxValue::Function(_) => "function",
+            LoxValue::Class(_) => "class",
+            LoxValue::Instance(_) => "instance",
+            LoxValue::List(_) => "list",
+            LoxValue::Map(_) => "map",
+        }
+    }
+
+    /// Report the heap object the value refers to, if any.
+    pub fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            LoxValue::NativeFunction(val) => tracer.edge(val),
+            LoxValue::Function(val) => tracer.edge(val),
+            LoxValue::Class(val) => tracer.edge(val),
+            LoxValue::Instance(val) => tracer.edge(&val.0),
+            LoxValue::List(val) => tracer.edge(&val.0),
+            LoxValue::Map(val) => tracer.edge(&val.0),
+            LoxValue::Nil | LoxValue::Bool(_) | LoxValue::Num(_) | LoxValue::Str(_) => {},
+        }
+    }
+
+    /// Format the value the way it should show up inside of a collection,
+    /// i.e., with strings quoted.
+    pub fn repr(&self) -> String {
+        match self {
+            LoxValue::Str(val) => format!("\"{val}\""),
+            _ => format!("{self}"),
+        }
+    }
+
     pub fn is_truthy(self: &LoxValue) -> bool {
         match self {
             LoxValue::Nil => false,
@@ -104,7 +196,7 @@ impl LoxValue {
         }
     }
 
-    pub fn assert_str(self: LoxValue, op: &Token) -> Result<Rc<String>, Spanned<RuntimeError>> {
+    pub fn assert_str(self: LoxValue, op: &Token) -> Result<LoxStr, Spanned<RuntimeError>> {
         if let LoxValue::Str(str) = self {
         Ok(str)
         } else {
@@ -127,6 +219,33 @@ impl LoxValue {
             Err(Spanned { value: RuntimeError::TypeError("bool"), span: op.span })
         }
     }
+
+    /// The number inside the value, for native functions that expect a
+    /// number argument.
+    pub fn as_num(&self) -> Result<f64, RuntimeError> {
+        match self {
+            LoxValue::Num(num) => Ok(*num),
+            _ => Err(RuntimeError::ArgumentType("a number", self.type_name())),
+        }
+    }
+
+    /// The string inside the value, for native functions that expect a
+    /// string argument.
+    pub fn as_str(&self) -> Result<&str, RuntimeError> {
+        match self {
+            LoxValue::Str(string) => Ok(string.as_str()),
+            _ => Err(RuntimeError::ArgumentType("a string", self.type_name())),
+        }
+    }
+
+    /// The boolean inside the value, for native functions that expect a
+    /// boolean argument. Use `is_truthy` to accept any value instead.
+    pub fn as_bool(&self) -> Result<bool, RuntimeError> {
+        match self {
+            LoxValue::Bool(boolean) => Ok(*boolean),
+            _ => Err(RuntimeError::ArgumentType("a bool", self.type_name())),
+        }
+    }
 }
 
 impl Display for LoxValue {
@@ -140,6 +259,8 @@ impl Display for LoxValue {
             LoxValue::NativeFunction(val) => write!(f, "{val}"),
             LoxValue::Class(val) => write!(f, "{val}"),
             LoxValue::Instance(instance) => write!(f, "{}", instance),
+            LoxValue::List(list) => write!(f, "{list}"),
+            LoxValue::Map(map) => write!(f, "{map}"),
         }
     }
 }
@@ -150,7 +271,7 @@ impl From<Literal> for LoxValue {
             Literal::Nil => Self::Nil,
             Literal::Num(val) => Self::Num(val),
             Literal::Bool(val) => Self::Bool(val),
-            Literal::Str(val) => Self::Str(val),
+            Literal::Str(val) => Self::Str(val.into()),
         }
     }
 }
@@ -0,0 +1,36 @@
+//! An interpreter for the Lox language, with a tree-walking and a bytecode
+//! backend.
+//!
+//! Embed it by creating an `Engine` and feeding it code:
+//!
+//! ```
+//! use loxide::{Engine, LoxValue};
+//!
+//! let mut engine = Engine::new();
+//! engine.eval("var answer = 6 * 7;")?;
+//! assert_eq!(engine.global("answer")?, Some(LoxValue::Num(42.0)));
+//! # Ok::<(), loxide::Error>(())
+//! ```
+//!
+//! `LoxValue` converts to and from plain Rust types with `From` and
+//! `TryFrom`. With the `serde` feature, it also implements `Serialize` and
+//! `Deserialize`.
+
+pub mod colors;
+pub mod span;
+pub mod sourcemap;
+pub mod util;
+pub mod interpreter;
+pub mod syntax;
+pub mod vm;
+pub mod gc;
+pub mod symbol;
+pub mod engine;
+pub mod output;
+
+pub use engine::{Backend, Engine, Error};
+pub use gc::GcConfig;
+pub use interpreter::{RuntimeError, StackFrame, UncaughtError};
+pub use interpreter::class::Instance;
+pub use interpreter::functions::{Arity, NativeFunction};
+pub use interpreter::value::LoxValue;
@@ -1,56 +1,88 @@
 use std::{env, error::Error};
 use std::fmt::Display;
-use std::io::Write;
-use std::path::PathBuf;
-
-use colors::{NORMAL, RED};
-use interpreter::{Interpreter, Visitor};
-use interpreter::resolver::Resolver;
-use sourcemap::Source;
-use syntax::tokenizer::Scanner;
-use syntax::parser::Parser;
-
-pub mod colors;
-pub mod span;
-pub mod sourcemap;
-pub mod util;
-pub mod interpreter;
-pub mod syntax;
+use std::io::{ErrorKind, Write};
+
+use cli::{Options, USAGE};
+use loxide::{Backend, Engine, Error as LoxError};
+use loxide::vm::serialize::is_bytecode;
+use loxide::colors::{NORMAL, RED};
+use repl::{Command, History, CONTINUATION_PROMPT, HELP, PROMPT};
+
+mod cli;
+mod repl;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut interpreter = Loxide::new();
-
-    if args.len() > 2 {
-        println!("Usage: loxide [script]");
-        std::process::exit(64);
-    } else if args.len() == 2 {
-        interpreter.run_file(&args[1]);
+    let mut options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("[{RED}ERR{NORMAL}] {err}");
+            println!("{USAGE}");
+            std::process::exit(64);
+        }
+    };
+
+    // Compiled scripts always run on the VM, since there's no tree to walk
+    if let Some(script) = &options.script {
+        if std::fs::read(script).is_ok_and(|bytes| is_bytecode(&bytes)) {
+            options.backend = Backend::Vm;
+        }
+    }
+
+    let mut loxide = Loxide::new(options.clone());
+
+    if let (true, Some(script), Some(output)) = (options.compile, &options.script, &options.output) {
+        loxide.compile_file(script, output);
+    } else if let Some(script) = &options.script {
+        loxide.run_file(script);
     } else {
-        interpreter.run_prompt();
+        loxide.run_prompt();
     }
 }
 
+/// The command line client: runs scripts and the REPL on an `Engine`, and
+/// reports errors and exit codes.
 struct Loxide {
+    engine: Engine,
     static_error: bool,
     runtime_error: bool,
 }
 
 impl Loxide {
-    pub fn new() -> Self {
+    pub fn new(options: Options) -> Self {
+        let mut engine = Engine::new()
+            .with_backend(options.backend)
+            .with_gc(options.gc)
+            .with_max_call_depth(options.max_call_depth);
+
+        if options.trace {
+            engine = engine.with_trace();
+        }
+
+        if options.dump_bytecode {
+            engine = engine.with_dump_bytecode();
+        }
+
         Self {
+            engine,
             static_error: false,
             runtime_error: false,
         }
     }
 
     pub fn run_file(&mut self, file: &str) {
-        let Ok(input) = std::fs::read_to_string(PathBuf::from(file)) else {
-            eprintln!("[{RED}ERR{NORMAL}]: File not found: {file}");
-            return;
-        };
+        match self.engine.run_file(file) {
+            Err(LoxError::Io(err)) if err.kind() == ErrorKind::NotFound => {
+                eprintln!("[{RED}ERR{NORMAL}]: File not found: {file}");
+                return;
+            },
 
-        self.run(&input);
+            Err(LoxError::Io(err)) if err.kind() == ErrorKind::InvalidData => {
+                eprintln!("[{RED}ERR{NORMAL}] {file} is not valid UTF-8");
+                std::process::exit(65);
+            },
+
+            result => self.report(result),
+        }
 
         if self.static_error {
             std::process::exit(65);
@@ -61,63 +93,135 @@ impl Loxide {
         }
     }
 
+    /// Compile a script to a bytecode file, without running it.
+    pub fn compile_file(&mut self, file: &str, output: &str) {
+        let Ok(input) = std::fs::read_to_string(file) else {
+            eprintln!("[{RED}ERR{NORMAL}]: File not found: {file}");
+            std::process::exit(66);
+        };
+
+        let bytecode = match self.engine.compile(&input) {
+            Ok(bytecode) => bytecode,
+            Err(error) => {
+                self.engine.report_error(&error);
+                std::process::exit(65);
+            }
+        };
+
+        if let Err(err) = std::fs::write(output, bytecode) {
+            eprintln!("[{RED}ERR{NORMAL}] Failed to write {output}: {err}");
+            std::process::exit(74);
+        }
+    }
+
     pub fn run_prompt(&mut self) {
-        print_prompt();
+        let mut history = History::load();
+        let mut buffer = String::new();
 
-        for line in std::io::stdin().lines() {
-            self.static_error = false;
-            self.runtime_error = false;
+        print_prompt(PROMPT);
 
+        for line in std::io::stdin().lines() {
             let Ok(line) = line else {
                 eprintln!("[{RED}ERR{NORMAL}] Failed to read input");
-                print_prompt();
+                buffer.clear();
+                print_prompt(PROMPT);
                 continue;
             };
 
+            if !buffer.is_empty() {
+                buffer.push('\n');
+            }
 
-            self.run(&line);
+            buffer.push_str(&line);
 
-            print_prompt();
-        }
-    }
+            // Keep reading lines until all delimiters are balanced
+            if !repl::is_complete(&buffer) {
+                print_prompt(CONTINUATION_PROMPT);
+                continue;
+            }
 
-    pub fn run(&mut self, input: &str) {
-        let source = Source::new(input);
+            let input = std::mem::take(&mut buffer);
 
-        // Tokenizing
-        let mut scanner = Scanner::new(&source);
+            if input.trim().is_empty() {
+                print_prompt(PROMPT);
+                continue;
+            }
 
-        // Parsing
-        let mut parser = Parser::new(&source, &mut scanner);
-        let parsed = parser.parse();
+            history.push(&input);
 
-        let ast = match parsed {
-            Ok(ast) => ast,
-            Err(_) => {
-                return;
+            if let Some(command) = input.trim().strip_prefix(':') {
+                match Command::parse(command) {
+                    Ok(command) => self.run_command(command, &history),
+                    Err(err) => eprintln!("[{RED}ERR{NORMAL}] {err}"),
+                }
+            } else {
+                let result = self.engine.eval_repl(&input);
+                self.report(result);
             }
-        };
 
-        // Variable resolution
-        let mut resolver = Resolver::new(&source);
-        let _ = resolver.visit(&ast);
+            print_prompt(PROMPT);
+        }
 
-        // Interpreting
-        let mut interpreter = Interpreter::new(&source, resolver.locals);
+        // Don't leave the user's shell prompt dangling after an EOF
+        println!();
+    }
 
-        match interpreter.visit(&ast) {
-            Ok(lit) => println!("{lit}"),
-            Err(error) => {
-                self.runtime_error = true;
-                let annotated = source.annotate(error);
-                eprintln!("{}", annotated);
-            }
+    fn run_command(&mut self, command: Command, history: &History) {
+        match command {
+            Command::Load(path) => {
+                let Ok(input) = std::fs::read_to_string(path) else {
+                    eprintln!("[{RED}ERR{NORMAL}]: File not found: {path}");
+                    return;
+                };
+
+                let result = self.engine.eval(&input);
+                self.report(result);
+            },
+
+            Command::Reset => self.engine.reset(),
+
+            Command::Env => {
+                for (name, value) in self.engine.globals() {
+                    println!("{name} = {value}");
+                }
+            },
+
+            Command::Ast(input) => {
+                match self.engine.parse_expression(input) {
+                    Ok(expr) => println!("{expr}"),
+                    Err(err) => self.engine.report_error(&err),
+                }
+            },
+
+            Command::History => {
+                for (idx, entry) in history.entries.iter().enumerate() {
+                    println!("{idx:>4}  {entry}");
+                }
+            },
+
+            Command::Help => println!("{HELP}"),
+        }
+    }
+
+    /// Print the error a chunk of code ran into, if any, and keep track of
+    /// what kind of error it was for the exit code.
+    fn report(&mut self, result: Result<(), LoxError>) {
+        let Err(error) = result else {
+            return;
+        };
+
+        if error.is_static() {
+            self.static_error = true;
+        } else {
+            self.runtime_error = true;
         }
+
+        self.engine.report_error(&error);
     }
 }
 
-fn print_prompt() {
-    print!("> ");
+fn print_prompt(prompt: &str) {
+    print!("{prompt}");
     std::io::stdout().flush().unwrap();
 }
 
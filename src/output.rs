@@ -0,0 +1,77 @@
+//! Where a session writes to.
+//!
+//! Program output (everything `print` prints) and diagnostics (error reports
+//! and the VM's execution trace) go to separate sinks, which default to stdout
+//! and stderr. Either one can be pointed at any `Write`, like a `SharedBuffer`
+//! to capture what gets written.
+
+use std::cell::RefCell;
+use std::fmt::{Arguments, Debug};
+use std::io::Write;
+use std::rc::Rc;
+
+/// A writer that can be shared between an `Engine` and the backend it runs
+/// on.
+#[derive(Clone)]
+pub struct Sink(Rc<RefCell<dyn Write>>);
+
+impl Sink {
+    pub fn new(writer: impl Write + 'static) -> Self {
+        Self(Rc::new(RefCell::new(writer)))
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(std::io::stdout())
+    }
+
+    pub fn stderr() -> Self {
+        Self::new(std::io::stderr())
+    }
+
+    /// Write formatted text, as produced by `format_args!`.
+    pub fn write_fmt(&self, args: Arguments) -> std::io::Result<()> {
+        self.0.borrow_mut().write_fmt(args)
+    }
+
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+impl Debug for Sink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Sink")
+    }
+}
+
+/// An in-memory buffer that keeps its contents when it gets cloned, so one
+/// clone can be handed to an `Engine` while the other reads what got written.
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything written so far, with invalid UTF-8 replaced.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+
+    /// Everything written so far, leaving the buffer empty.
+    pub fn take(&self) -> String {
+        let bytes = std::mem::take(&mut *self.0.borrow_mut());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+//! Helpers for the interactive prompt: detecting incomplete input, keeping
+//! track of history and parsing meta-commands.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use loxide::sourcemap::Source;
+use loxide::syntax::tokenizer::{LexError, Scanner};
+use loxide::syntax::tokens::TokenType;
+
+pub const PROMPT: &str = "> ";
+pub const CONTINUATION_PROMPT: &str = "... ";
+
+/// Check whether a chunk of input is ready to be run, i.e., all of its
+/// parentheses, braces and brackets are balanced and it doesn't end inside of
+/// a string literal or an interpolated expression.
+///
+/// The input gets run through the scanner, so strings and comments are
+/// skipped the same way they are when the input is actually run. Input with
+/// more closing than opening delimiters is considered complete, so the parser
+/// gets to report the error.
+pub fn is_complete(input: &str) -> bool {
+    use TokenType::*;
+
+    let source = Source::new(input);
+    let mut scanner = Scanner::new(&source);
+    let mut depth: isize = 0;
+
+    for token in scanner.by_ref() {
+        match token.token_type {
+            LeftParen | LeftBrace | LeftBracket => depth += 1,
+            RightParen | RightBrace | RightBracket => depth -= 1,
+            _ => {}
+        }
+    }
+
+    // Strings can span lines, so one that's unterminated is still being typed
+    let unterminated = scanner.take_errors()
+        .iter()
+        .any(|error| matches!(error.value, LexError::UnterminatedString));
+
+    !unterminated && !scanner.in_interpolation() && depth <= 0
+}
+
+/// Meta-commands that can be entered at the prompt, prefixed with a `:`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command<'a> {
+    /// Run a file in the current session
+    Load(&'a str),
+
+    /// Throw away all state and start a fresh session
+    Reset,
+
+    /// List all global bindings
+    Env,
+
+    /// Print the syntax tree of an expression
+    Ast(&'a str),
+
+    /// List the inputs entered so far
+    History,
+
+    /// List the available commands
+    Help,
+}
+
+impl<'a> Command<'a> {
+    /// Parse a meta-command, with the leading `:` already stripped off.
+    pub fn parse(input: &'a str) -> Result<Self, ReplError> {
+        let input = input.trim();
+        let (name, arg) = input
+            .split_once(char::is_whitespace)
+            .map(|(name, arg)| (name, arg.trim()))
+            .unwrap_or((input, ""));
+
+        match (name, arg) {
+            ("load", "") => Err(ReplError::MissingArgument("load")),
+            ("load", path) => Ok(Command::Load(path)),
+            ("reset", _) => Ok(Command::Reset),
+            ("env", _) => Ok(Command::Env),
+            ("ast", "") => Err(ReplError::MissingArgument("ast")),
+            ("ast", expr) => Ok(Command::Ast(expr)),
+            ("history", _) => Ok(Command::History),
+            ("help", _) => Ok(Command::Help),
+            _ => Err(ReplError::UnknownCommand(name.to_owned())),
+        }
+    }
+}
+
+pub const HELP: &str = "\
+:load <file>   Run a file in the current session
+:reset         Clear all globals and start over
+:env           List all global bindings
+:ast <expr>    Print the syntax tree for an expression
+:history       List previous inputs
+:help          Show this message";
+
+/// The inputs entered in the REPL, mirrored to a history file on disk when
+/// one is available.
+///
+/// Entries are stored one per line, with backslashes and newlines escaped so
+/// multi-line inputs survive the round trip.
+#[derive(Debug, Default)]
+pub struct History {
+    pub entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+impl History {
+    /// Load the history from `$HOME/.loxide_history`, if it exists.
+    pub fn load() -> Self {
+        let Some(home) = std::env::var_os("HOME") else {
+            return Self::default();
+        };
+
+        Self::load_from(PathBuf::from(home).join(".loxide_history"))
+    }
+
+    pub fn load_from(path: PathBuf) -> Self {
+        let entries = std::fs::read_to_string(&path)
+            .map(|contents| contents.lines().map(unescape).collect())
+            .unwrap_or_default();
+
+        Self { entries, path: Some(path) }
+    }
+
+    /// Add an entry to the history, appending it to the history file.
+    ///
+    /// Failing to write the history file is not worth interrupting the
+    /// session over, so write errors are ignored.
+    pub fn push(&mut self, entry: &str) {
+        if let Some(path) = &self.path {
+            let file = OpenOptions::new().create(true).append(true).open(path);
+
+            if let Ok(mut file) = file {
+                let _ = writeln!(file, "{}", escape(entry));
+            }
+        }
+
+        self.entries.push(entry.to_owned());
+    }
+}
+
+fn escape(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut entry = String::with_capacity(line.len());
+    let mut chars = line.chars();
+
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            match chars.next() {
+                Some('n') => entry.push('\n'),
+                Some(other) => entry.push(other),
+                None => entry.push('\\'),
+            }
+        } else {
+            entry.push(ch);
+        }
+    }
+
+    entry
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplError {
+    UnknownCommand(String),
+    MissingArgument(&'static str),
+}
+
+impl std::fmt::Display for ReplError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplError::UnknownCommand(name) => write!(f, "Unknown command ':{name}' (try :help)"),
+            ReplError::MissingArgument(name) => write!(f, "Command ':{name}' expects an argument"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balanced_input() {
+        assert!(is_complete("print 1;"));
+        assert!(is_complete("fun f() { return (1); }"));
+        assert!(!is_complete("fun f() {"));
+        assert!(!is_complete("print (1 +"));
+        assert!(is_complete("print \"{\";"));
+        assert!(!is_complete("print \"unterminated"));
+        assert!(is_complete("print \"\\\"(\";"));
+        assert!(!is_complete("print \"\"\"multi\nline"));
+        assert!(is_complete("print \"\"\"a \" quote\"\"\";"));
+        assert!(is_complete("print \"\"\"(\"\"\";"));
+        assert!(!is_complete("print \"\"\"one \" quote\n"));
+        assert!(is_complete("print \"${ \"(\" }\";"));
+        assert!(is_complete("print \"${ {\"a\": 1}[\"a\"] }\";"));
+        assert!(!is_complete("print \"${ 1 +"));
+        assert!(!is_complete("print \"${ 1 } and ${ 2"));
+        assert!(is_complete("{ // }\n}"));
+        assert!(is_complete("}"));
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(Command::parse("load foo.lox"), Ok(Command::Load("foo.lox")));
+        assert_eq!(Command::parse(" reset "), Ok(Command::Reset));
+        assert_eq!(Command::parse("ast 1 + 2"), Ok(Command::Ast("1 + 2")));
+        assert!(Command::parse("load").is_err());
+        assert!(Command::parse("frobnicate").is_err());
+    }
+
+    #[test]
+    fn history_escaping() {
+        let entry = "fun f() {\n  print \"a\\b\";\n}";
+        assert!(!escape(entry).contains('\n'));
+        assert_eq!(unescape(&escape(entry)), entry);
+    }
+}
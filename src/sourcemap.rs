@@ -1,23 +1,69 @@
 use crate::span::{Span, Spanned, Annotated};
 
-pub struct Source<'a> {
-    pub source: &'a str,
-    lines: Vec<&'a str>,
+/// The source text for an interpreter session.
+///
+/// A `Source` owns all the code that was fed to it, so spans produced for
+/// earlier chunks (e.g., previous REPL lines) stay valid when new code gets
+/// appended. Line numbers are counted from the start of the chunk a span is
+/// in, so an error on the first line of a REPL input is reported on line 1.
+#[derive(Debug)]
+pub struct Source {
+    pub source: String,
+
+    /// The offset at which every line starts
     offsets: Vec<usize>,
+
+    /// The index of the line at which every chunk starts
+    chunks: Vec<usize>,
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Self { source: String::new(), offsets: vec![0], chunks: Vec::new() }
+    }
 }
 
-impl<'a> Source<'a> {
-    pub fn new(source: &'a str) -> Self {
-        let lines: Vec<&str> = source.lines().collect();
-        let mut offsets = vec![0];
+impl Source {
+    pub fn new(source: &str) -> Self {
+        let mut new = Self::default();
+        new.push(source);
+        new
+    }
 
-        for (idx, ch) in source.char_indices() {
+    /// Append a chunk of code to the source, and return the offset at which
+    /// the chunk starts.
+    pub fn push(&mut self, chunk: &str) -> usize {
+        // Make sure every chunk starts on a fresh line
+        if !self.source.is_empty() && !self.source.ends_with('\n') {
+            self.source.push('\n');
+            self.offsets.push(self.source.len());
+        }
+
+        let start = self.source.len();
+        self.source.push_str(chunk);
+        self.chunks.push(self.offsets.len() - 1);
+
+        for (idx, ch) in chunk.char_indices() {
             if ch == '\n' {
-                offsets.push(idx + 1);
+                self.offsets.push(start + idx + 1);
             }
         }
 
-        Self { source, lines, offsets }
+        start
+    }
+
+    /// Return the source text covered by a span.
+    pub fn text(&self, span: Span) -> &str {
+        &self.source[span.range()]
+    }
+
+    /// Return the source text of the line at the given (zero-based) index,
+    /// without its line ending.
+    fn line(&self, line_idx: usize) -> &str {
+        let start = self.offsets[line_idx];
+        let end = self.offsets.get(line_idx + 1).copied().unwrap_or(self.source.len());
+
+        self.source[start..end].trim_end_matches(['\n', '\r'])
     }
 
     /// Given a span, return the line, column, and source text of the line
@@ -34,13 +80,40 @@ impl<'a> Source<'a> {
 
 
         let col = span.offset - line_offset;
-        let source = self.lines[line_idx];
+        let source = self.line(line_idx);
+
+        // Count lines from the start of the chunk that contains the line
+        let chunk_start = self.chunks
+            .iter()
+            .rev()
+            .find(|&&chunk_start| chunk_start <= line_idx)
+            .copied()
+            .unwrap_or(0);
 
-        (line_idx + 1, col, source)
+        (line_idx - chunk_start + 1, col, source)
     }
 
-    pub fn annotate<T>(&self, spanned: Spanned<T>) -> Annotated<T> {
+    pub fn annotate<T>(&self, spanned: Spanned<T>) -> Annotated<'_, T> {
         let (line, col, source) = self.map_span(spanned.span);
         Annotated { value: spanned.value, span: spanned.span, line, col, source }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_count_from_the_start_of_their_chunk() {
+        let mut source = Source::new("var a = 1;\nvar b = 2;");
+        let second = source.push("print a;\nprint c;");
+        let third = source.push("print d;\n");
+        let fourth = source.push("print e;");
+
+        assert_eq!(source.map_span(Span::new_at(11)), (2, 0, "var b = 2;"));
+        assert_eq!(source.map_span(Span::new_at(second)), (1, 0, "print a;"));
+        assert_eq!(source.map_span(Span::new_at(second + 15)), (2, 6, "print c;"));
+        assert_eq!(source.map_span(Span::new_at(third + 6)), (1, 6, "print d;"));
+        assert_eq!(source.map_span(Span::new_at(fourth)), (1, 0, "print e;"));
+    }
+}
@@ -1,6 +1,7 @@
 use std::{fmt::Display, ops::Range};
 use crate::colors::{RED, NORMAL};
 
+#[derive(Debug, Clone)]
 pub struct Spanned<T> {
     pub value: T,
     pub span: Span,
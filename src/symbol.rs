@@ -0,0 +1,288 @@
+//! Interned strings.
+//!
+//! The scanner turns every identifier and string literal into a `Symbol`: a
+//! small handle that can be copied, hashed and compared without touching the
+//! string itself. Each thread keeps its own table of symbols, but the strings
+//! themselves are leaked, so they stay around until the process exits and a
+//! symbol's string can be borrowed for as long as it's needed.
+//!
+//! Strings that are built at runtime (e.g., by concatenation) don't become
+//! symbols, since that would keep every one of them alive for good. They only
+//! get interned once they're used as map keys, and then only for as long as
+//! some key still holds on to them (see `LoxStr::key`). A `LoxStr` holds
+//! either kind.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+use std::ops::Deref;
+use std::rc::Rc;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+/// Names that get compared against often enough to deserve a constant. They
+/// get interned up front, in this order.
+const PREDEFINED: [&str; 3] = ["this", "super", "init"];
+
+impl Symbol {
+    pub const THIS: Symbol = Symbol(0);
+    pub const SUPER: Symbol = Symbol(1);
+    pub const INIT: Symbol = Symbol(2);
+
+    /// Look up the symbol for a name, interning the name if it wasn't seen
+    /// before.
+    pub fn intern(name: &str) -> Symbol {
+        INTERNER.with(|interner| interner.borrow_mut().intern(name))
+    }
+
+    /// Look up the symbol for a name without interning it, e.g., to find a
+    /// variable by a name that came from outside of Lox. A name that was
+    /// never interned can't be the name of anything.
+    pub fn lookup(name: &str) -> Option<Symbol> {
+        INTERNER.with(|interner| interner.borrow().symbols.get(name).copied())
+    }
+
+    pub fn as_str(self) -> &'static str {
+        INTERNER.with(|interner| interner.borrow().names[self.0 as usize])
+    }
+
+    /// How many names have been interned on this thread so far.
+    #[cfg(test)]
+    pub(crate) fn count() -> usize {
+        INTERNER.with(|interner| interner.borrow().names.len())
+    }
+}
+
+/// The empty string.
+impl Default for Symbol {
+    fn default() -> Self {
+        Symbol::intern("")
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Symbol({:?})", self.as_str())
+    }
+}
+
+struct Interner {
+    symbols: HashMap<&'static str, Symbol>,
+    names: Vec<&'static str>,
+}
+
+impl Interner {
+    fn new() -> Self {
+        let mut interner = Self { symbols: HashMap::new(), names: Vec::new() };
+
+        for name in PREDEFINED {
+            interner.intern(name);
+        }
+
+        interner
+    }
+
+    fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(&symbol) = self.symbols.get(name) {
+            return symbol;
+        }
+
+        // Leaking the name is what lets `Symbol::as_str` hand out plain
+        // references. Only names from the source code get here, so there's a
+        // limited number of them.
+        let name: &'static str = Box::leak(name.into());
+        let symbol = Symbol(self.names.len() as u32);
+        self.symbols.insert(name, symbol);
+        self.names.push(name);
+        symbol
+    }
+}
+
+/// Strings built at runtime that are in use as map keys. Equal keys share one
+/// allocation, so comparing them usually only compares pointers.
+///
+/// Strings that nothing but the table holds on to anymore get swept out once
+/// it has doubled in size since the last sweep, so it stays in proportion to
+/// the keys that are actually alive.
+struct Keys {
+    strings: HashSet<Rc<str>>,
+    sweep_at: usize,
+}
+
+impl Keys {
+    const MIN_SWEEP: usize = 64;
+
+    fn new() -> Self {
+        Self { strings: HashSet::new(), sweep_at: Self::MIN_SWEEP }
+    }
+
+    fn intern(&mut self, string: &Rc<str>) -> Rc<str> {
+        if let Some(interned) = self.strings.get(string) {
+            return interned.clone();
+        }
+
+        if self.strings.len() >= self.sweep_at {
+            self.strings.retain(|string| Rc::strong_count(string) > 1);
+            self.sweep_at = Self::MIN_SWEEP.max(2 * self.strings.len());
+        }
+
+        self.strings.insert(string.clone());
+        string.clone()
+    }
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::new());
+    static KEYS: RefCell<Keys> = RefCell::new(Keys::new());
+}
+
+/// A string value, as used by both backends.
+#[derive(Clone)]
+pub enum LoxStr {
+    /// A string from the source code
+    Interned(Symbol),
+
+    /// A string that was built at runtime
+    Owned(Rc<str>),
+}
+
+impl LoxStr {
+    pub fn as_str(&self) -> &str {
+        match self {
+            LoxStr::Interned(symbol) => symbol.as_str(),
+            LoxStr::Owned(string) => string,
+        }
+    }
+
+    /// The string as a map key: the symbol with the same name if there is
+    /// one, or else a copy that's shared with every equal key, which lives
+    /// only as long as those keys do.
+    pub fn key(&self) -> LoxStr {
+        match self {
+            LoxStr::Interned(_) => self.clone(),
+            LoxStr::Owned(string) => match Symbol::lookup(string) {
+                Some(symbol) => LoxStr::Interned(symbol),
+                None => LoxStr::Owned(KEYS.with(|keys| keys.borrow_mut().intern(string))),
+            },
+        }
+    }
+}
+
+impl From<Symbol> for LoxStr {
+    fn from(symbol: Symbol) -> Self {
+        LoxStr::Interned(symbol)
+    }
+}
+
+impl From<String> for LoxStr {
+    fn from(string: String) -> Self {
+        LoxStr::Owned(string.into())
+    }
+}
+
+impl From<&str> for LoxStr {
+    fn from(string: &str) -> Self {
+        LoxStr::Owned(string.into())
+    }
+}
+
+impl Deref for LoxStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+/// Two interned strings are equal exactly when their symbols are. Otherwise,
+/// the contents need to be compared.
+impl PartialEq for LoxStr {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LoxStr::Interned(left), LoxStr::Interned(right)) => left == right,
+            (LoxStr::Owned(left), LoxStr::Owned(right)) if Rc::ptr_eq(left, right) => true,
+            _ => self.as_str() == other.as_str(),
+        }
+    }
+}
+
+impl Eq for LoxStr {}
+
+/// Hashes the contents, to stay consistent with equality.
+impl Hash for LoxStr {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state);
+    }
+}
+
+impl Display for LoxStr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Debug for LoxStr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning() {
+        let foo = Symbol::intern("foo");
+        assert_eq!(Symbol::intern("foo"), foo);
+        assert_ne!(Symbol::intern("bar"), foo);
+        assert_eq!(foo.as_str(), "foo");
+    }
+
+    #[test]
+    fn string_equality() {
+        let interned = LoxStr::from(Symbol::intern("foobar"));
+        let owned = LoxStr::from(format!("foo{}", "bar"));
+
+        assert_eq!(interned, owned);
+        assert!(matches!(owned.key(), LoxStr::Interned(symbol) if symbol == Symbol::intern("foobar")));
+        assert_ne!(LoxStr::from("foo"), interned);
+    }
+
+    #[test]
+    fn runtime_keys() {
+        let key = |string: &str| match LoxStr::from(string).key() {
+            LoxStr::Owned(string) => string,
+            LoxStr::Interned(_) => panic!("runtime keys shouldn't become symbols"),
+        };
+
+        let first = key("runtime key");
+        assert!(Rc::ptr_eq(&first, &key("runtime key")));
+        assert!(Symbol::lookup("runtime key").is_none());
+
+        // Once the keys are gone, so is their string
+        let weak = Rc::downgrade(&first);
+        drop(first);
+
+        for n in 0..2 * Keys::MIN_SWEEP {
+            key(&format!("key {n}"));
+        }
+
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn predefined_symbols() {
+        assert_eq!(Symbol::intern("this"), Symbol::THIS);
+        assert_eq!(Symbol::intern("super"), Symbol::SUPER);
+        assert_eq!(Symbol::intern("init"), Symbol::INIT);
+    }
+}
@@ -1,13 +1,15 @@
 use std::hash::Hash;
 use std::{fmt::Display, rc::Rc};
 use super::tokens::Token;
+use crate::span::Span;
+use crate::symbol::Symbol;
 
 #[derive(Debug, Clone)]
 pub enum Literal {
     Nil,
     Bool(bool),
     Num(f64),
-    Str(Rc<String>),
+    Str(Symbol),
 }
 
 impl Hash for Literal {
@@ -83,7 +85,15 @@ impl Display for Literal {
 
 pub type Ast = Vec<Stmt>;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Identifies a node that refers to or declares a variable, so the results
+/// of resolving it can be looked up later on.
+///
+/// Ids are handed out by the parser, and are unique within a session: nodes
+/// that look the same (or are clones of each other) can still be told apart.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub usize);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Expr {
     Grouping {
         expr: Box<Expr>,
@@ -98,9 +108,11 @@ pub enum Expr {
         right: Box<Expr>,
     },
     Variable {
+        id: NodeId,
         name: Token,
     },
     Assignment {
+        id: NodeId,
         name: Token,
         value: Box<Expr>,
     },
@@ -115,7 +127,13 @@ pub enum Expr {
         right: Box<Expr>,
     },
     This {
+        id: NodeId,
+        keyword: Token,
+    },
+    Super {
+        id: NodeId,
         keyword: Token,
+        method: Token,
     },
     Unary {
         op: Token,
@@ -126,12 +144,141 @@ pub enum Expr {
         paren: Token,
         arguments: Vec<Expr>,
     },
+    List {
+        bracket: Token,
+        elements: Vec<Expr>,
+    },
+    Lambda {
+        keyword: Token,
+        params: Rc<[Token]>,
+        body: Rc<[Stmt]>,
+    },
+    Map {
+        brace: Token,
+        entries: Vec<(Expr, Expr)>,
+    },
+    Index {
+        object: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+    },
+    SetIndex {
+        object: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+        value: Box<Expr>,
+    },
+    /// A string with embedded expressions, like `"Hello ${name}!"`, where
+    /// `parts` holds the string segments and expressions in order.
+    Interpolation {
+        token: Token,
+        parts: Vec<Expr>,
+    },
     Literal {
+        token: Token,
         value: Literal,
     },
 }
 
-#[derive(Debug, Clone)]
+impl Expr {
+    /// The span of a token that represents the expression, used to map
+    /// compiled code back to the source.
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Grouping { expr } => expr.span(),
+            Expr::Get { name, .. } => name.span,
+            Expr::Binary { op, .. } => op.span,
+            Expr::Variable { name, .. } => name.span,
+            Expr::Assignment { name, .. } => name.span,
+            Expr::Set { name, .. } => name.span,
+            Expr::Logical { op, .. } => op.span,
+            Expr::This { keyword, .. } => keyword.span,
+            Expr::Super { keyword, .. } => keyword.span,
+            Expr::Unary { op, .. } => op.span,
+            Expr::Call { paren, .. } => paren.span,
+            Expr::List { bracket, .. } => bracket.span,
+            Expr::Lambda { keyword, .. } => keyword.span,
+            Expr::Map { brace, .. } => brace.span,
+            Expr::Index { bracket, .. } => bracket.span,
+            Expr::SetIndex { bracket, .. } => bracket.span,
+            Expr::Interpolation { token, .. } => token.span,
+            Expr::Literal { token, .. } => token.span,
+        }
+    }
+}
+
+/// Print expressions as s-expressions, e.g., `(+ 1 (group (* 2 3)))`.
+impl Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Grouping { expr } => write!(f, "(group {expr})"),
+            Expr::Get { object, name } => write!(f, "(. {object} {name})"),
+            Expr::Binary { op, left, right } => write!(f, "({op} {left} {right})"),
+            Expr::Variable { name, .. } => write!(f, "{name}"),
+            Expr::Assignment { name, value, .. } => write!(f, "(= {name} {value})"),
+            Expr::Set { name, object, value } => write!(f, "(= (. {object} {name}) {value})"),
+            Expr::Logical { op, left, right } => write!(f, "({op} {left} {right})"),
+            Expr::This { .. } => write!(f, "this"),
+            Expr::Super { method, .. } => write!(f, "(super {method})"),
+            Expr::Unary { op, right } => write!(f, "({op} {right})"),
+            Expr::Call { callee, arguments, .. } => {
+                write!(f, "(call {callee}")?;
+
+                for arg in arguments {
+                    write!(f, " {arg}")?;
+                }
+
+                write!(f, ")")
+            },
+            Expr::List { elements, .. } => {
+                write!(f, "(list")?;
+
+                for element in elements {
+                    write!(f, " {element}")?;
+                }
+
+                write!(f, ")")
+            },
+            Expr::Lambda { params, .. } => {
+                write!(f, "(fun (")?;
+
+                for (idx, param) in params.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, " ")?;
+                    }
+
+                    write!(f, "{param}")?;
+                }
+
+                write!(f, ") ...)")
+            },
+            Expr::Map { entries, .. } => {
+                write!(f, "(map")?;
+
+                for (key, value) in entries {
+                    write!(f, " {key} {value}")?;
+                }
+
+                write!(f, ")")
+            },
+            Expr::Index { object, index, .. } => write!(f, "([] {object} {index})"),
+            Expr::SetIndex { object, index, value, .. } => write!(f, "(= ([] {object} {index}) {value})"),
+            Expr::Interpolation { parts, .. } => {
+                write!(f, "(str")?;
+
+                for part in parts {
+                    write!(f, " {part}")?;
+                }
+
+                write!(f, ")")
+            },
+            Expr::Literal { value: Literal::Str(val), .. } => write!(f, "\"{val}\""),
+            Expr::Literal { value, .. } => write!(f, "{value}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Stmt {
     Block {
         statements: Vec<Stmt>,
@@ -147,25 +294,38 @@ pub enum Stmt {
     While {
         condition: Expr,
         body: Box<Stmt>,
+        /// The increment clause of a desugared `for` loop, which has to run
+        /// even when the body is cut short by a `continue`.
+        increment: Option<Expr>,
+    },
+    Break {
+        keyword: Token,
+    },
+    Continue {
+        keyword: Token,
     },
     Print {
         expr: Expr,
     },
     Var {
+        id: NodeId,
         name: Token,
         initializer: Option<Expr>,
     },
     Fun {
+        id: NodeId,
         name: Token,
-        params: Vec<Token>,
-        body: Vec<Stmt>,
+        params: Rc<[Token]>,
+        body: Rc<[Stmt]>,
     },
     Return {
         keyword: Token,
         expr: Option<Expr>,
     },
     Class {
+        id: NodeId,
         name: Token,
+        superclass: Option<Expr>,
         methods: Vec<Stmt>,
     },
 }
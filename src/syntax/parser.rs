@@ -5,12 +5,12 @@
 
 use std::fmt::Display;
 use std::iter::Peekable;
-use std::rc::Rc;
 use crate::sourcemap::Source;
 use crate::span::Span;
 use crate::span::Spanned;
 use super::ast::Ast;
 use super::ast::Literal;
+use super::ast::NodeId;
 use super::ast::Stmt;
 use super::tokenizer::Scanner;
 use super::tokens::Token;
@@ -19,24 +19,55 @@ use super::ast::Expr;
 
 type ParseResult<T> = Result<T, Spanned<ParseError>>;
 
-pub struct Parser<'a> {
-    source: &'a Source<'a>,
-    tokens: Peekable<&'a mut Scanner<'a>>,
+pub struct Parser<'s, 'a> {
+    source: &'a Source,
+    tokens: Peekable<&'s mut Scanner<'a>>,
     span: Span,
-    had_error: bool,
+    errors: Vec<Spanned<ParseError>>,
+    bare_expressions: bool,
 
+    /// The id for the next node that refers to or declares a variable
+    next_id: usize,
 }
 
-impl<'a> Parser<'a> {
-    pub fn new(source: &'a Source<'a>, scanner: &'a mut Scanner<'a> ) -> Self {
+impl<'s, 'a> Parser<'s, 'a> {
+    pub fn new(source: &'a Source, scanner: &'s mut Scanner<'a> ) -> Self {
         Self {
             source,
             tokens: scanner.peekable(),
             span: Span::new(),
-            had_error: false
+            errors: Vec::new(),
+            bare_expressions: false,
+            next_id: 0,
         }
     }
 
+    /// Start handing out node ids at `first_id`. When parsing several chunks
+    /// of code in the same session, every chunk should pick up where the
+    /// previous one left off, so ids stay unique.
+    pub fn with_first_id(mut self, first_id: usize) -> Self {
+        self.next_id = first_id;
+        self
+    }
+
+    /// The first node id that hasn't been handed out yet.
+    pub fn next_id(&self) -> usize {
+        self.next_id
+    }
+
+    fn node_id(&mut self) -> NodeId {
+        let id = NodeId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    /// Allow the input to end in an expression without a trailing semicolon,
+    /// which gets parsed as a print statement. Used by the REPL.
+    pub fn with_bare_expressions(mut self) -> Self {
+        self.bare_expressions = true;
+        self
+    }
+
     pub fn finished(&mut self) -> bool {
         if let Some(next) = self.tokens.peek() {
             next.token_type == TokenType::Eof
@@ -46,7 +77,7 @@ impl<'a> Parser<'a> {
     }
 
     fn spanned_error(&mut self, spanned: Spanned<ParseError>) {
-        eprintln!("{}", self.source.annotate(spanned));
+        self.errors.push(spanned);
     }
 
     /// Checks whether the next token matches the provided type, without
@@ -137,7 +168,8 @@ impl<'a> Parser<'a> {
 
         self.expect(Semicolon, ParseError::ExpectedSemicolon)?;
 
-        Ok(Stmt::Var { name, initializer })
+        let id = self.node_id();
+        Ok(Stmt::Var { id, name, initializer })
     }
 
     pub fn statement(&mut self) -> ParseResult<Stmt> {
@@ -157,6 +189,12 @@ impl<'a> Parser<'a> {
             self.for_statement()
         } else if let Some(_) = self.matches(Print) {
             self.print_statement()
+        } else if let Some(keyword) = self.matches(Break) {
+            self.expect(Semicolon, ParseError::ExpectedSemicolon)?;
+            Ok(Stmt::Break { keyword })
+        } else if let Some(keyword) = self.matches(Continue) {
+            self.expect(Semicolon, ParseError::ExpectedSemicolon)?;
+            Ok(Stmt::Continue { keyword })
         } else if let Some(_) = self.matches(LeftBrace) {
             Ok(Stmt::Block { statements: self.block()? })
         } else {
@@ -167,6 +205,14 @@ impl<'a> Parser<'a> {
     pub fn class(&mut self) -> ParseResult<Stmt> {
         use TokenType::*;
         let name = self.expect(Identifier, ParseError::ExpectedClassName)?;
+
+        let superclass = if self.matches(Less).is_some() {
+            let name = self.expect(Identifier, ParseError::ExpectedSuperclassName)?;
+            Some(Expr::Variable { id: self.node_id(), name })
+        } else {
+            None
+        };
+
         self.expect(LeftBrace, ParseError::ExpectedLeftBrace("before class body"))?;
 
         let mut methods = Vec::new();
@@ -177,7 +223,8 @@ impl<'a> Parser<'a> {
 
         self.expect(RightBrace, ParseError::ExpectedRightBrace("after class body"))?;
 
-        Ok(Stmt::Class { name, methods })
+        let id = self.node_id();
+        Ok(Stmt::Class { id, name, superclass, methods })
     }
 
     pub fn return_statement(&mut self, keyword: Token) -> ParseResult<Stmt> {
@@ -198,7 +245,20 @@ impl<'a> Parser<'a> {
         let name = self.expect(Identifier, ParseError::ExpectedFunName)?;
         self.expect(LeftParen, ParseError::ExpectedLeftParen("after function name"))?;
 
-        // Parse params
+        let params = self.parameters()?;
+
+        // Parse body
+        self.expect(LeftBrace, ParseError::ExpectedLeftBrace("before function body"))?;
+        let body = self.block()?;
+
+        let id = self.node_id();
+        Ok(Stmt::Fun { id, name, params: params.into(), body: body.into() })
+    }
+
+    /// Parse a list of parameter names, up to and including the closing
+    /// parenthesis.
+    fn parameters(&mut self) -> ParseResult<Vec<Token>> {
+        use TokenType::*;
         let mut params = Vec::new();
 
         if !self.check(RightParen) {
@@ -222,11 +282,7 @@ impl<'a> Parser<'a> {
 
         self.expect(RightParen, ParseError::ExpectedRightParen("after parameters"))?;
 
-        // Parse body
-        self.expect(LeftBrace, ParseError::ExpectedLeftBrace("before function body"))?;
-        let body = self.block()?;
-
-        Ok(Stmt::Fun { name, params, body })
+        Ok(params)
     }
 
     pub fn if_statement(&mut self) -> ParseResult<Stmt> {
@@ -254,7 +310,7 @@ impl<'a> Parser<'a> {
         self.expect(RightParen, ParseError::ExpectedRightParen("after while condition"))?;
         let body = Box::new(self.statement()?);
 
-        Ok(Stmt::While { condition, body })
+        Ok(Stmt::While { condition, body, increment: None })
     }
 
     pub fn for_statement(&mut self) -> ParseResult<Stmt> {
@@ -276,7 +332,7 @@ impl<'a> Parser<'a> {
             None
         };
 
-        self.expect(Semicolon, ParseError::ExpectedSemicolon)?;
+        let semicolon = self.expect(Semicolon, ParseError::ExpectedSemicolon)?;
 
         let increment = if !self.check(RightParen) {
             Some(self.expression()?)
@@ -289,16 +345,9 @@ impl<'a> Parser<'a> {
         let mut body = self.statement()?;
 
         // Rewrite into a while-loop based AST
-        if let Some(increment) = increment {
-            body = Stmt::Block { statements: vec![
-                body,
-                Stmt::Expression { expr: increment },
-            ]};
-        }
-
         let condition = condition
-            .unwrap_or(Expr::Literal { value: Literal::Bool(true) });
-        body = Stmt::While { condition, body: Box::new(body) };
+            .unwrap_or(Expr::Literal { token: semicolon, value: Literal::Bool(true) });
+        body = Stmt::While { condition, body: Box::new(body), increment };
 
         if let Some(initializer) = initializer {
             body = Stmt::Block { statements: vec![initializer, body] }
@@ -333,6 +382,10 @@ impl<'a> Parser<'a> {
         use TokenType::*;
         let expr = self.expression()?;
 
+        if self.bare_expressions && self.check(Eof) {
+            return Ok(Stmt::Print { expr });
+        }
+
         self.expect(Semicolon, ParseError::ExpectedSemicolon)?;
 
         Ok(Stmt::Expression { expr })
@@ -349,10 +402,12 @@ impl<'a> Parser<'a> {
         if let Some(_) = self.matches(Equal) {
             let value = self.assignment()?;
 
-            if let Expr::Variable { name } = expr {
-                return Ok(Expr::Assignment { name, value: Box::new(value) });
+            if let Expr::Variable { id, name } = expr {
+                return Ok(Expr::Assignment { id, name, value: Box::new(value) });
             } else if let Expr::Get { name, object } = expr {
                 return Ok(Expr::Set { name, object, value: Box::new(value) });
+            } else if let Expr::Index { object, bracket, index } = expr {
+                return Ok(Expr::SetIndex { object, bracket, index, value: Box::new(value) });
             }
 
             return Err(Spanned {
@@ -460,9 +515,13 @@ impl<'a> Parser<'a> {
         loop {
             if let Some(_) = self.matches(LeftParen) {
                 expr = self.finish_call(expr)?;
-            } if let Some(_) = self.matches(Dot) {
+            } else if let Some(_) = self.matches(Dot) {
                 let name = self.expect(Identifier, ParseError::ExpectedPropertyName("after ."))?;
                 expr = Expr::Get { name, object: Box::new(expr) }
+            } else if let Some(bracket) = self.matches(LeftBracket) {
+                let index = self.expression()?;
+                self.expect(RightBracket, ParseError::ExpectedRightBracket("after index"))?;
+                expr = Expr::Index { object: Box::new(expr), bracket, index: Box::new(index) };
             } else {
                 break;
             }
@@ -503,45 +562,67 @@ impl<'a> Parser<'a> {
         use TokenType::*;
 
         if let Some(keyword) = self.matches(This) {
-            return Ok(Expr::This { keyword });
+            return Ok(Expr::This { id: self.node_id(), keyword });
+        }
+
+        if let Some(keyword) = self.matches(Super) {
+            self.expect(Dot, ParseError::ExpectedDot("after 'super'"))?;
+            let method = self.expect(Identifier, ParseError::ExpectedPropertyName("after 'super.'"))?;
+            return Ok(Expr::Super { id: self.node_id(), keyword, method });
         }
 
-        if let Some(_) = self.matches(False) {
-            return Ok(Expr::Literal { value: Literal::Bool(false) });
+        if let Some(token) = self.matches(False) {
+            return Ok(Expr::Literal { token, value: Literal::Bool(false) });
         }
 
-        if let Some(_) = self.matches(True) {
-            return Ok(Expr::Literal { value: Literal::Bool(true) });
+        if let Some(token) = self.matches(True) {
+            return Ok(Expr::Literal { token, value: Literal::Bool(true) });
         }
 
-        if let Some(_) = self.matches(Nil) {
-            return Ok(Expr::Literal { value: Literal::Nil });
+        if let Some(token) = self.matches(Nil) {
+            return Ok(Expr::Literal { token, value: Literal::Nil });
         }
 
         if let Some(token) = self.matches(TokenType::String) {
-            let value = token.lexeme;
-            let len = value.len();
-            let trimmed = &value[1..len-1];
+            let value = token.literal.unwrap_or_default();
+            return Ok(Expr::Literal { token, value: Literal::Str(value) });
+        }
 
-            return Ok(Expr::Literal { value: Literal::Str(Rc::new(trimmed.to_owned())) });
+        if let Some(token) = self.matches(Interpolation) {
+            return self.interpolation(token);
         }
 
         if let Some(token) = self.matches(Number) {
             // TODO: In theory this could fail? Can it though, if it got
             // tokenized correctly?
-            let value: f64 = token.lexeme.parse().unwrap();
-            return Ok(Expr::Literal { value: Literal::Num(value) });
+            let value: f64 = self.source.text(token.span).parse().unwrap();
+            return Ok(Expr::Literal { token, value: Literal::Num(value) });
         }
 
         if let Some(name) = self.matches(Identifier) {
-           return Ok(Expr::Variable { name });
+           return Ok(Expr::Variable { id: self.node_id(), name });
+        }
+
+        if let Some(keyword) = self.matches(Fun) {
+            self.expect(LeftParen, ParseError::ExpectedLeftParen("after 'fun'"))?;
+            let params = self.parameters()?;
+
+            self.expect(LeftBrace, ParseError::ExpectedLeftBrace("before function body"))?;
+            let body = self.block()?;
+
+            return Ok(Expr::Lambda { keyword, params: params.into(), body: body.into() });
         }
 
         if let Some(_) = self.matches(LeftParen) {
-            let expr = self.expression()?;
-            self.expect(RightParen, ParseError::ExpectedRightParen(""))?;
+            return self.grouping_or_arrow();
+        }
+
+        if let Some(bracket) = self.matches(LeftBracket) {
+            return self.list(bracket);
+        }
 
-            return Ok(Expr::Grouping { expr: Box::new(expr) });
+        if let Some(brace) = self.matches(LeftBrace) {
+            return self.map(brace);
         }
 
         Err(Spanned {
@@ -550,7 +631,132 @@ impl<'a> Parser<'a> {
         })
     }
 
-    pub fn parse(&mut self) -> Result<Ast, ()> {
+    /// Parse either a parenthesized expression, or an arrow function like
+    /// `(a, b) => a + b`.
+    ///
+    /// We can't tell the two apart until we see the `=>`, so we parse the
+    /// contents of the parentheses as a list of expressions, and turn them
+    /// into parameters after the fact.
+    fn grouping_or_arrow(&mut self) -> ParseResult<Expr> {
+        use TokenType::*;
+        let mut exprs = Vec::new();
+
+        if !self.check(RightParen) {
+            exprs.push(self.expression()?);
+
+            while self.matches(Comma).is_some() {
+                exprs.push(self.expression()?);
+            }
+        }
+
+        self.expect(RightParen, ParseError::ExpectedRightParen(""))?;
+
+        if let Some(arrow) = self.matches(Arrow) {
+            let mut params = Vec::new();
+
+            for expr in exprs {
+                let Expr::Variable { name, .. } = expr else {
+                    return Err(Spanned {
+                        value: ParseError::ExpectedParamName("in arrow function"),
+                        span: arrow.span,
+                    });
+                };
+
+                params.push(name);
+            }
+
+            let expr = self.expression()?;
+            let body = vec![Stmt::Return { keyword: arrow.clone(), expr: Some(expr) }];
+
+            return Ok(Expr::Lambda { keyword: arrow, params: params.into(), body: body.into() });
+        }
+
+        // Anything other than a single expression only makes sense as the
+        // parameter list of an arrow function
+        if exprs.len() != 1 {
+            return Err(Spanned {
+                value: ParseError::ExpectedArrow,
+                span: self.span,
+            });
+        }
+
+        Ok(Expr::Grouping { expr: Box::new(exprs.pop().unwrap()) })
+    }
+
+    /// Parse the rest of an interpolated string like `"a ${b} c"`, where the
+    /// scanner hands us the string segments as `Interpolation` tokens
+    /// (followed by the tokens for the embedded expression), ending with an
+    /// `InterpolationEnd` token.
+    fn interpolation(&mut self, token: Token) -> ParseResult<Expr> {
+        use TokenType::*;
+        let mut parts = Vec::new();
+        let mut segment = token.clone();
+
+        loop {
+            let value = segment.literal.unwrap_or_default();
+
+            if !value.as_str().is_empty() {
+                parts.push(Expr::Literal { token: segment.clone(), value: Literal::Str(value) });
+            }
+
+            if segment.token_type == InterpolationEnd {
+                break;
+            }
+
+            parts.push(self.expression()?);
+
+            segment = match self.matches(Interpolation) {
+                Some(segment) => segment,
+                None => self.expect(InterpolationEnd, ParseError::ExpectedRightBrace("after interpolated expression"))?,
+            };
+        }
+
+        Ok(Expr::Interpolation { token, parts })
+    }
+
+    /// Parse the elements of a list literal, allowing for a trailing comma.
+    fn list(&mut self, bracket: Token) -> ParseResult<Expr> {
+        use TokenType::*;
+        let mut elements = Vec::new();
+
+        while !self.check(RightBracket) && !self.finished() {
+            elements.push(self.expression()?);
+
+            if self.matches(Comma).is_none() {
+                break;
+            }
+        }
+
+        self.expect(RightBracket, ParseError::ExpectedRightBracket("after list elements"))?;
+
+        Ok(Expr::List { bracket, elements })
+    }
+
+    /// Parse the `key: value` entries of a map literal, allowing for a
+    /// trailing comma.
+    fn map(&mut self, brace: Token) -> ParseResult<Expr> {
+        use TokenType::*;
+        let mut entries = Vec::new();
+
+        while !self.check(RightBrace) && !self.finished() {
+            let key = self.expression()?;
+            self.expect(Colon, ParseError::ExpectedColon("after map key"))?;
+            let value = self.expression()?;
+            entries.push((key, value));
+
+            if self.matches(Comma).is_none() {
+                break;
+            }
+        }
+
+        self.expect(RightBrace, ParseError::ExpectedRightBrace("after map entries"))?;
+
+        Ok(Expr::Map { brace, entries })
+    }
+
+    /// Parse a whole program. Parsing carries on after an error, so every
+    /// error in the program gets reported.
+    pub fn parse(&mut self) -> Result<Ast, Vec<Spanned<ParseError>>> {
         let mut statements = Vec::new();
 
         while !self.finished() {
@@ -565,15 +771,15 @@ impl<'a> Parser<'a> {
             }
         }
 
-        if !self.had_error {
+        if self.errors.is_empty() {
             Ok(statements)
         } else {
-            Err(())
+            Err(std::mem::take(&mut self.errors))
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub enum ParseError {
     TooManyParams,
     TooManyArgs,
@@ -584,11 +790,16 @@ pub enum ParseError {
     ExpectedRightBrace(&'static str),
     ExpectedLeftParen(&'static str),
     ExpectedRightParen(&'static str),
+    ExpectedRightBracket(&'static str),
     ExpectedParamName(&'static str),
     InvalidAssigTarget,
     ExpectedVarName,
     ExpectedExpression,
     ExpectedClassName,
+    ExpectedSuperclassName,
+    ExpectedDot(&'static str),
+    ExpectedColon(&'static str),
+    ExpectedArrow,
     ExpectedPropertyName(&'static str),
 }
 
@@ -604,11 +815,16 @@ impl Display for ParseError {
             ParseError::ExpectedRightBrace(ctx) => write!(f, "Expected '}}' {ctx}"),
             ParseError::ExpectedLeftParen(ctx) => write!(f, "Expected '(' {ctx}"),
             ParseError::ExpectedRightParen(ctx) => write!(f, "Expected ')' {ctx}"),
+            ParseError::ExpectedRightBracket(ctx) => write!(f, "Expected ']' {ctx}"),
             ParseError::ExpectedParamName(ctx) => write!(f, "Expected parameter name {ctx}"),
             ParseError::InvalidAssigTarget => write!(f, "Invalid assignment target"),
             ParseError::ExpectedVarName => write!(f, "Expected variable name"),
             ParseError::ExpectedExpression => write!(f, "Expected expression"),
             ParseError::ExpectedClassName => write!(f, "Expected class name"),
+            ParseError::ExpectedSuperclassName => write!(f, "Expected superclass name"),
+            ParseError::ExpectedDot(ctx) => write!(f, "Expected '.' {ctx}"),
+            ParseError::ExpectedColon(ctx) => write!(f, "Expected ':' {ctx}"),
+            ParseError::ExpectedArrow => write!(f, "Expected '=>' after arrow function parameters"),
             ParseError::ExpectedPropertyName(ctx) => write!(f, "Expected property name {ctx}"),
         }
     }
@@ -5,33 +5,63 @@ use std::str::Chars;
 use crate::sourcemap::Source;
 use crate::span::Span;
 use crate::span::Spanned;
+use crate::symbol::Symbol;
 use super::tokens::Token;
 use super::tokens::TokenType;
 
 pub struct Scanner<'a> {
-    source: &'a Source<'a>,
+    source: &'a Source,
     finished: bool,
     chars: Peekable<Chars<'a>>,
     span: Span,
-    had_error: bool
+    errors: Vec<Spanned<LexError>>,
+
+    /// The brace depth inside of every interpolated expression we're
+    /// currently in, innermost last. When a `}` is found at depth zero, it
+    /// closes the interpolation and we go back to scanning the string.
+    interpolations: Vec<usize>,
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(source: &'a Source<'a>) -> Self {
+    pub fn new(source: &'a Source) -> Self {
+        Self::new_at(source, 0)
+    }
+
+    /// Create a scanner that starts scanning at the given byte offset in the
+    /// source, so that spans stay relative to the start of the whole source.
+    pub fn new_at(source: &'a Source, offset: usize) -> Self {
         Self {
             source,
             finished: false,
-            chars: source.source.chars().peekable(),
-            span: Span::default(),
-            had_error: false,
+            chars: source.source[offset..].chars().peekable(),
+            span: Span::new_at(offset),
+            errors: Vec::new(),
+            interpolations: Vec::new(),
         }
     }
 
-    /// Push a new LexError to the internal list of encountered errors
+    /// Report a LexError for the token that's currently being scanned
     fn error(&mut self, err: LexError) {
-        let spanned = Spanned { value: err, span: self.span };
-        eprintln!("{}", self.source.annotate(spanned));
-        self.had_error = true;
+        self.error_at(err, self.span);
+    }
+
+    /// Report a LexError at the given span
+    fn error_at(&mut self, err: LexError, span: Span) {
+        self.errors.push(Spanned { value: err, span });
+    }
+
+    pub fn had_error(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    /// Whether the input so far ends inside of an interpolated expression.
+    pub fn in_interpolation(&self) -> bool {
+        !self.interpolations.is_empty()
+    }
+
+    /// Hand over the errors that were reported so far.
+    pub fn take_errors(&mut self) -> Vec<Spanned<LexError>> {
+        std::mem::take(&mut self.errors)
     }
 
     /// Peek two characters ahead without advancing the internal iterator.
@@ -84,15 +114,120 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn string(&mut self) -> bool {
-        self.consume_while(|ch| ch != '"');
+    /// Scan the rest of a string literal, decoding any escape sequences along
+    /// the way. Returns `None` if the string is never terminated.
+    ///
+    /// When we run into a `${`, we stop and return what we have so far as an
+    /// `Interpolation` token, and scan the embedded expression as regular
+    /// tokens. Once the matching `}` comes along, we pick up the rest of the
+    /// string where we left off (`resumed`), ending it with an
+    /// `InterpolationEnd` token instead of a plain string.
+    ///
+    /// Invalid escape sequences get reported, but don't stop us from
+    /// producing a string token, so the parser can keep going.
+    fn string(&mut self, resumed: bool) -> Option<(String, TokenType)> {
+        let mut value = String::new();
 
-        // Check whether it's a correctly terminated string
-        if self.consume_char() == Some('"') {
-            true
-        } else {
-            self.error(LexError::UnterminatedString);
-            false
+        loop {
+            let escape_start = self.span.end();
+
+            match self.consume_char() {
+                Some('"') if resumed => return Some((value, TokenType::InterpolationEnd)),
+
+                Some('"') => return Some((value, TokenType::String)),
+
+                Some('$') if self.consume_if_eq('{').is_some() => {
+                    self.interpolations.push(0);
+                    return Some((value, TokenType::Interpolation));
+                },
+
+                Some('\\') => {
+                    if let Some(ch) = self.escape(escape_start) {
+                        value.push(ch);
+                    }
+                },
+
+                Some(ch) => value.push(ch),
+
+                None => {
+                    self.error(LexError::UnterminatedString);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Decode the escape sequence following a backslash at offset `start`.
+    fn escape(&mut self, start: usize) -> Option<char> {
+        let decoded = match self.consume_char()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '"' => '"',
+            '$' => '$',
+            '\\' => '\\',
+            'u' => return self.unicode_escape(start),
+            ch => {
+                let span = Span { offset: start, len: self.span.end() - start };
+                self.error_at(LexError::InvalidEscape(ch), span);
+                return None;
+            }
+        };
+
+        Some(decoded)
+    }
+
+    /// Decode a unicode escape of the form `\u{1F600}`, with the `\u` already
+    /// consumed.
+    fn unicode_escape(&mut self, start: usize) -> Option<char> {
+        let mut digits = String::new();
+        let opened = self.consume_if_eq('{').is_some();
+
+        while let Some(&ch) = self.chars.peek() {
+            if !opened || !ch.is_ascii_hexdigit() {
+                break;
+            }
+
+            digits.push(ch);
+            self.consume_char();
+        }
+
+        let closed = opened && self.consume_if_eq('}').is_some();
+
+        let decoded = u32::from_str_radix(&digits, 16)
+            .ok()
+            .filter(|_| closed && digits.len() <= 6)
+            .and_then(char::from_u32);
+
+        if decoded.is_none() {
+            let span = Span { offset: start, len: self.span.end() - start };
+            self.error_at(LexError::InvalidUnicodeEscape, span);
+        }
+
+        decoded
+    }
+
+    /// Scan the rest of a triple-quoted string. These can span multiple lines
+    /// and are taken verbatim, without decoding escape sequences.
+    fn raw_string(&mut self) -> Option<String> {
+        let mut value = String::new();
+
+        loop {
+            match self.consume_char() {
+                Some('"') if self.chars.peek() == Some(&'"') && self.peek_next() == Some('"') => {
+                    self.consume_char();
+                    self.consume_char();
+                    return Some(value);
+                },
+
+                Some(ch) => value.push(ch),
+
+                None => {
+                    self.error(LexError::UnterminatedString);
+                    return None;
+                }
+            }
         }
     }
 
@@ -129,6 +264,8 @@ impl<'a> Iterator for Scanner<'a> {
         // Keep trying until we match a token, or return if
         // the characters iterator is exhausted.
         loop {
+            let mut literal = None;
+
             // Start a new span
             self.span = Span::after(self.span);
 
@@ -137,7 +274,8 @@ impl<'a> Iterator for Scanner<'a> {
                 return Some(Token {
                     token_type: Eof,
                     span: self.span,
-                    lexeme: "".to_owned(),
+                    symbol: None,
+                    literal: None,
                 });
             };
 
@@ -145,18 +283,51 @@ impl<'a> Iterator for Scanner<'a> {
                 // Single character tokens
                 '(' => LeftParen,
                 ')' => RightParen,
-                '{' => LeftBrace,
-                '}' => RightBrace,
+                '[' => LeftBracket,
+                ']' => RightBracket,
                 ',' => Comma,
+                ':' => Colon,
                 '.' => Dot,
                 '-' => Minus,
                 '+' => Plus,
                 ';' => Semicolon,
                 '*' => Star,
 
+                // Braces, which might close an interpolated expression
+                '{' => {
+                    if let Some(depth) = self.interpolations.last_mut() {
+                        *depth += 1;
+                    }
+
+                    LeftBrace
+                }
+
+                '}' => match self.interpolations.last_mut() {
+                    Some(0) => {
+                        self.interpolations.pop();
+
+                        let Some((value, token_type)) = self.string(true) else { continue; };
+                        literal = Some(Symbol::intern(&value));
+                        token_type
+                    },
+
+                    Some(depth) => {
+                        *depth -= 1;
+                        RightBrace
+                    },
+
+                    None => RightBrace,
+                },
+
                 // Two character tokens
                 '!' => self.branch('=', BangEqual, Bang),
-                '=' => self.branch('=', EqualEqual, Equal),
+                '=' => {
+                    if self.consume_if_eq('>').is_some() {
+                        Arrow
+                    } else {
+                        self.branch('=', EqualEqual, Equal)
+                    }
+                },
                 '<' => self.branch('=', LessEqual, Less),
                 '>' => self.branch('=', GreaterEqual, Greater),
 
@@ -172,9 +343,21 @@ impl<'a> Iterator for Scanner<'a> {
 
                 // Strings
                 '"' => {
+                    let is_raw = self.chars.peek() == Some(&'"')
+                        && self.peek_next() == Some('"');
+
+                    let scanned = if is_raw {
+                        self.consume_char();
+                        self.consume_char();
+                        self.raw_string().map(|value| (value, TokenType::String))
+                    } else {
+                        self.string(false)
+                    };
+
                     // If it's an illegal string, continue (and exit afterwards)
-                    if !self.string() { continue; }
-                    TokenType::String
+                    let Some((value, token_type)) = scanned else { continue; };
+                    literal = Some(Symbol::intern(&value));
+                    token_type
                 }
 
                 // Numbers
@@ -196,10 +379,16 @@ impl<'a> Iterator for Scanner<'a> {
                 }
             };
 
+            let symbol = match token_type {
+                Identifier | This | Super => Some(Symbol::intern(&self.source.source[self.span.range()])),
+                _ => None,
+            };
+
             return Some(Token {
                 token_type,
                 span: self.span,
-                lexeme: self.source.source[self.span.range()].to_owned(),
+                symbol,
+                literal,
             });
         }
     }
@@ -212,7 +401,9 @@ fn ident_type(s: &str) -> TokenType {
 
     match s {
         "and" => And,
+        "break" => Break,
         "class" => Class,
+        "continue" => Continue,
         "else" => Else,
         "false" => False,
         "for" => For,
@@ -231,10 +422,12 @@ fn ident_type(s: &str) -> TokenType {
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub enum LexError {
     UnexpectedToken,
     UnterminatedString,
+    InvalidEscape(char),
+    InvalidUnicodeEscape,
 }
 
 impl Display for LexError {
@@ -242,6 +435,8 @@ impl Display for LexError {
         match self {
             LexError::UnexpectedToken => write!(f, "Unexpected input"),
             LexError::UnterminatedString => write!(f, "Unterminated string"),
+            LexError::InvalidEscape(ch) => write!(f, "Invalid escape sequence '\\{ch}'"),
+            LexError::InvalidUnicodeEscape => write!(f, "Invalid unicode escape, expected something like '\\u{{1F600}}'"),
         }
     }
 }
@@ -252,6 +447,14 @@ mod tests {
 
     use super::*;
 
+    /// Scan a single string literal and return its decoded value
+    fn scan_string(input: &str) -> (Option<Symbol>, bool) {
+        let source = Source::new(input);
+        let mut scanner = Scanner::new(&source);
+        let literal = scanner.next().and_then(|token| token.literal);
+        (literal, scanner.had_error())
+    }
+
     #[test]
     fn minimal() {
         use TokenType::*;
@@ -264,12 +467,14 @@ mod tests {
                 Token {
                     token_type: Dot,
                     span: Span { offset: 0, len: 1 },
-                    lexeme: ".".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: Eof,
                     span: Span { offset: 1, len: 0 },
-                    lexeme: "".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
             ]
         );
@@ -286,32 +491,38 @@ mod tests {
                 Token {
                     token_type: LeftParen,
                     span: Span { offset: 0, len: 1 },
-                    lexeme: "(".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: LeftParen,
                     span: Span { offset: 1, len: 1 },
-                    lexeme: "(".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: Dot,
                     span: Span { offset: 2, len: 1 },
-                    lexeme: ".".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: RightParen,
                     span: Span { offset: 3, len: 1 },
-                    lexeme: ")".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: RightParen,
                     span: Span { offset: 4, len: 1 },
-                    lexeme: ")".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: Eof,
                     span: Span { offset: 5, len: 0 },
-                    lexeme: "".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
             ]
         );
@@ -328,17 +539,50 @@ mod tests {
                 Token {
                     token_type: BangEqual,
                     span: Span { offset: 0, len: 2 },
-                    lexeme: "!=".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: Bang,
                     span: Span { offset: 2, len: 1 },
-                    lexeme: "!".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: Eof,
                     span: Span { offset: 3, len: 0 },
-                    lexeme: "".to_owned()
+                    symbol: None,
+                    literal: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn arrows() {
+        use TokenType::*;
+        let source = Source::new("=>==");
+        let scanner = Scanner::new(&source);
+        assert_eq!(
+            scanner.collect::<Vec<_>>(),
+            vec![
+                Token {
+                    token_type: Arrow,
+                    span: Span { offset: 0, len: 2 },
+                    symbol: None,
+                    literal: None,
+                },
+                Token {
+                    token_type: EqualEqual,
+                    span: Span { offset: 2, len: 2 },
+                    symbol: None,
+                    literal: None,
+                },
+                Token {
+                    token_type: Eof,
+                    span: Span { offset: 4, len: 0 },
+                    symbol: None,
+                    literal: None,
                 },
             ]
         );
@@ -356,17 +600,20 @@ mod tests {
                 Token {
                     token_type: LeftParen,
                     span: Span { offset: 0, len: 1 },
-                    lexeme: "(".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: RightParen,
                     span: Span { offset: 1, len: 1 },
-                    lexeme: ")".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: Eof,
                     span: Span { offset: 11, len: 0 },
-                    lexeme: "".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
             ]
         );
@@ -382,17 +629,74 @@ mod tests {
                 Token {
                     token_type: TokenType::String,
                     span: Span { offset: 0, len: 14 },
-                    lexeme: r#""Hello there!""#.to_owned()
+                    symbol: None,
+                    literal: Some(Symbol::intern("Hello there!")),
                 },
                 Token {
                     token_type: TokenType::Eof,
                     span: Span { offset: 14, len: 0 },
-                    lexeme: "".to_owned()
+                    symbol: None,
+                    literal: None,
                 },
             ]
         );
     }
 
+    #[test]
+    fn escape_sequences() {
+        let (literal, had_error) = scan_string(r#""a\tb\n\"c\" \\ \u{1F600}""#);
+        assert_eq!(literal.unwrap().as_str(), "a\tb\n\"c\" \\ \u{1F600}");
+        assert!(!had_error);
+    }
+
+    #[test]
+    fn invalid_escape_sequences() {
+        for input in [r#""\q""#, r#""\u{}""#, r#""\u{110000}""#, r#""\u1234""#] {
+            let (literal, had_error) = scan_string(input);
+            assert!(literal.is_some(), "{input} should still produce a string");
+            assert!(had_error, "{input} should be an error");
+        }
+    }
+
+    #[test]
+    fn interpolation() {
+        use TokenType::*;
+        let source = Source::new(r#""a ${ {"k": "${x}"}["k"] } b ${y}""#);
+        let scanner = Scanner::new(&source);
+
+        let tokens: Vec<_> = scanner
+            .map(|token| (token.token_type, token.literal.map(|lit| lit.to_string())))
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                (Interpolation, Some("a ".to_owned())),
+                (LeftBrace, None),
+                (String, Some("k".to_owned())),
+                (Colon, None),
+                (Interpolation, Some("".to_owned())),
+                (Identifier, None),
+                (InterpolationEnd, Some("".to_owned())),
+                (RightBrace, None),
+                (LeftBracket, None),
+                (String, Some("k".to_owned())),
+                (RightBracket, None),
+                (Interpolation, Some(" b ".to_owned())),
+                (Identifier, None),
+                (InterpolationEnd, Some("".to_owned())),
+                (Eof, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_strings() {
+        let (literal, had_error) = scan_string("\"\"\"line \\n\n\"quoted\" text\"\"\"");
+        assert_eq!(literal.unwrap().as_str(), "line \\n\n\"quoted\" text");
+        assert!(!had_error);
+    }
+
     #[test]
     fn unterminated_strings() {
         let source = Source::new(r#""Hello there!"#);
@@ -401,7 +705,7 @@ mod tests {
         // Consume the tokens
         for _ in scanner.by_ref() {}
 
-        assert!(scanner.had_error);
+        assert!(scanner.had_error());
     }
 
     #[test]
@@ -415,39 +719,63 @@ mod tests {
                 Token {
                     token_type: TokenType::Number,
                     span: Span { offset: 0, len: 3 },
-                    lexeme: "123".to_owned(),
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: TokenType::Comma,
                     span: Span { offset: 3, len: 1 },
-                    lexeme: ",".to_owned(),
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: TokenType::Number,
                     span: Span { offset: 5, len: 5 },
-                    lexeme: "123.0".to_owned(),
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: TokenType::Comma,
                     span: Span { offset: 10, len: 1 },
-                    lexeme: ",".to_owned(),
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: TokenType::Number,
                     span: Span { offset: 12, len: 3 },
-                    lexeme: "123".to_owned(),
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: TokenType::Dot,
                     span: Span { offset: 15, len: 1 },
-                    lexeme: ".".to_owned(),
+                    symbol: None,
+                    literal: None,
                 },
                 Token {
                     token_type: TokenType::Eof,
                     span: Span { offset: 16, len: 0 },
-                    lexeme: "".to_owned(),
+                    symbol: None,
+                    literal: None,
                 },
             ]
         );
     }
+
+    #[test]
+    fn identifiers() {
+        let source = Source::new("foo this bar foo var");
+        let symbols: Vec<_> = Scanner::new(&source).map(|token| token.symbol).collect();
+        let foo = Symbol::intern("foo");
+
+        // Keywords don't get a name, except for the ones that act as variables
+        assert_eq!(symbols, vec![
+            Some(foo),
+            Some(Symbol::THIS),
+            Some(Symbol::intern("bar")),
+            Some(foo),
+            None,
+            None,
+        ]);
+    }
 }
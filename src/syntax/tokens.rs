@@ -1,6 +1,7 @@
 use std::fmt::Display;
 
 use crate::span::Span;
+use crate::symbol::Symbol;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum TokenType {
@@ -9,7 +10,10 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
     Minus,
     Plus,
@@ -22,6 +26,7 @@ pub enum TokenType {
     BangEqual,
     Equal,
     EqualEqual,
+    Arrow,
     Greater,
     GreaterEqual,
     Less,
@@ -32,9 +37,17 @@ pub enum TokenType {
     String,
     Number,
 
+    // Parts of an interpolated string: every part that's followed by an
+    // embedded `${expression}` is an `Interpolation`, the final part is an
+    // `InterpolationEnd`.
+    Interpolation,
+    InterpolationEnd,
+
     // Keywords
     And,
+    Break,
     Class,
+    Continue,
     Else,
     False,
     Fun,
@@ -53,16 +66,94 @@ pub enum TokenType {
     Eof,
 }
 
-// TODO: Make this zero-copy by not storing the lexeme on the token.
+impl Display for TokenType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use TokenType::*;
+
+        let text = match self {
+            LeftParen => "(",
+            RightParen => ")",
+            LeftBrace => "{",
+            RightBrace => "}",
+            LeftBracket => "[",
+            RightBracket => "]",
+            Comma => ",",
+            Colon => ":",
+            Dot => ".",
+            Minus => "-",
+            Plus => "+",
+            Semicolon => ";",
+            Slash => "/",
+            Star => "*",
+            Bang => "!",
+            BangEqual => "!=",
+            Equal => "=",
+            EqualEqual => "==",
+            Arrow => "=>",
+            Greater => ">",
+            GreaterEqual => ">=",
+            Less => "<",
+            LessEqual => "<=",
+            Identifier => "identifier",
+            String => "string",
+            Number => "number",
+            Interpolation | InterpolationEnd => "interpolated string",
+            And => "and",
+            Break => "break",
+            Class => "class",
+            Continue => "continue",
+            Else => "else",
+            False => "false",
+            Fun => "fun",
+            For => "for",
+            If => "if",
+            Nil => "nil",
+            Or => "or",
+            Print => "print",
+            Return => "return",
+            Super => "super",
+            This => "this",
+            True => "true",
+            Var => "var",
+            While => "while",
+            Eof => "end of file",
+        };
+
+        write!(f, "{text}")
+    }
+}
+
+/// A token doesn't hold on to its text: the span points back into the
+/// `Source` it was scanned from.
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub struct Token {
     pub token_type: TokenType,
     pub span: Span,
-    pub lexeme: String,
+
+    /// The interned name of an identifier, or of `this` and `super`, which
+    /// get looked up like variables
+    pub symbol: Option<Symbol>,
+
+    /// The decoded value of a string literal, with all escape sequences
+    /// resolved
+    pub literal: Option<Symbol>,
+}
+
+impl Token {
+    /// The name of an identifier token.
+    ///
+    /// Panics for tokens that don't carry a name, but the parser makes sure
+    /// names in the syntax tree always do.
+    pub fn name(&self) -> Symbol {
+        self.symbol.expect("token should be an identifier")
+    }
 }
 
 impl Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.lexeme)
+        match self.symbol {
+            Some(symbol) => write!(f, "{symbol}"),
+            None => write!(f, "{}", self.token_type),
+        }
     }
 }
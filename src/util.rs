@@ -1,3 +1,4 @@
+use std::cell::RefCell;
 use std::hash::Hash;
 use std::fmt::Debug;
 
@@ -25,3 +26,77 @@ impl<'a, T> PartialEq for RefEq<'a, T> {
 }
 
 impl<'a, T> Eq for RefEq<'a, T> {}
+
+thread_local! {
+    /// The collections that are being visited further up the native stack.
+    static VISITING: RefCell<Vec<*const ()>> = const { RefCell::new(Vec::new()) };
+}
+
+/// How many collections can be visited inside of each other before giving up,
+/// so that visiting deeply nested ones doesn't overflow the native stack.
+pub const MAX_NESTING: usize = 256;
+
+/// Why a collection couldn't be visited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Revisit {
+    /// It's already being visited further out, i.e., it contains itself
+    Cycle,
+
+    /// It's nested more than `MAX_NESTING` levels deep
+    TooDeep,
+}
+
+/// Marks a collection as being visited (e.g., formatted) until it's dropped,
+/// so a collection that contains itself can be caught rather than recursing
+/// forever, and one that's nested too deeply rather than recursing until the
+/// stack runs out.
+pub struct CycleGuard(*const ());
+
+impl CycleGuard {
+    /// Start visiting the collection at `ptr`, unless it's already being
+    /// visited or too many others are.
+    pub fn enter<T: ?Sized>(ptr: *const T) -> Result<Self, Revisit> {
+        let ptr = ptr as *const ();
+
+        VISITING.with(|visiting| {
+            let mut visiting = visiting.borrow_mut();
+
+            if visiting.contains(&ptr) {
+                return Err(Revisit::Cycle);
+            }
+
+            if visiting.len() >= MAX_NESTING {
+                return Err(Revisit::TooDeep);
+            }
+
+            visiting.push(ptr);
+            Ok(Self(ptr))
+        })
+    }
+}
+
+impl Drop for CycleGuard {
+    fn drop(&mut self) {
+        VISITING.with(|visiting| visiting.borrow_mut().retain(|&ptr| ptr != self.0));
+    }
+}
+
+/// A value that can hold other values, in lists and maps.
+pub trait Nested: Sized {
+    /// If this is the last reference to a collection, move everything it
+    /// holds into `into`, leaving it empty.
+    fn take_contents(&self, into: &mut Vec<Self>);
+}
+
+/// Drop values without recursing into the collections they hold: anything
+/// that would get freed along with a value gets moved onto the same worklist
+/// first. Dropping a list nested thousands of levels deep would otherwise
+/// overflow the native stack.
+///
+/// Collections call this from their own `Drop`, with the contents they took
+/// out of themselves.
+pub fn drop_nested<T: Nested>(mut values: Vec<T>) {
+    while let Some(value) = values.pop() {
+        value.take_contents(&mut values);
+    }
+}
@@ -0,0 +1,828 @@
+//! A bytecode backend: the `Compiler` turns a syntax tree into chunks of
+//! bytecode, which get executed by a stack-based `Vm`.
+//!
+//! The VM implements the same language as the tree-walking `Interpreter`,
+//! but locals live in stack slots and closures only capture the variables
+//! they use, so variable access doesn't involve walking environments or
+//! hashing names.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use chunk::{Constant, Function, OpCode};
+use disassembler::disassemble_instruction;
+use serialize::LoadError;
+use value::{BoundMethod, BuiltinMethod, Class, Closure, HashKey, Instance, List, Map, Upvalue, Value};
+use crate::gc::{GcConfig, Heap};
+use crate::output::Sink;
+use crate::interpreter::{DEFAULT_MAX_CALL_DEPTH, RuntimeError, StackFrame, UncaughtError};
+use crate::interpreter::functions::{clock, Arity};
+use crate::interpreter::map::Entries;
+use crate::sourcemap::Source;
+use crate::span::{Span, Spanned};
+use crate::symbol::Symbol;
+
+pub mod chunk;
+pub mod compiler;
+pub mod disassembler;
+pub mod native;
+pub mod serialize;
+pub mod value;
+
+type VmResult<T = ()> = Result<T, Spanned<RuntimeError>>;
+
+/// The state of a function call that is in progress.
+struct CallFrame {
+    closure: Rc<Closure>,
+
+    /// The offset of the next instruction to execute
+    ip: usize,
+
+    /// The stack slot that holds the function being called, right below its
+    /// arguments and locals
+    base: usize,
+}
+
+/// A long-lived VM session.
+///
+/// Like the `Interpreter`, the VM owns all the source code it was handed, so
+/// the globals defined by earlier chunks of code stay around.
+pub struct Vm {
+    source: Source,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: HashMap<Symbol, Value>,
+
+    /// The upvalues that still point into the stack
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+
+    /// Whether to print the stack and every instruction while executing
+    trace: bool,
+
+    /// Where `print` writes to
+    output: Sink,
+
+    /// Where the execution trace gets written to
+    diagnostics: Sink,
+
+    /// The maximum number of nested function calls
+    max_call_depth: usize,
+
+    heap: Heap,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        let mut globals = HashMap::new();
+        globals.insert(Symbol::intern("clock"), Value::Native(Rc::new(clock())));
+
+        Self {
+            source: Source::default(),
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals,
+            open_upvalues: Vec::new(),
+            trace: false,
+            output: Sink::stdout(),
+            diagnostics: Sink::stderr(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            heap: Heap::default(),
+        }
+    }
+
+    /// Print the contents of the stack and the instruction that is about to
+    /// run, for every instruction that gets executed.
+    pub fn with_trace(mut self) -> Self {
+        self.trace = true;
+        self
+    }
+
+    pub fn with_output(mut self, output: Sink) -> Self {
+        self.output = output;
+        self
+    }
+
+    pub fn with_diagnostics(mut self, diagnostics: Sink) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+    pub fn with_gc(mut self, config: GcConfig) -> Self {
+        self.heap.set_config(config);
+        self
+    }
+
+    /// Limit the number of nested function calls. Going any deeper raises a
+    /// `RuntimeError::StackOverflow`.
+    pub fn with_max_call_depth(mut self, depth: usize) -> Self {
+        self.max_call_depth = depth;
+        self
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    pub fn globals(&self) -> &HashMap<Symbol, Value> {
+        &self.globals
+    }
+
+    pub fn globals_mut(&mut self) -> &mut HashMap<Symbol, Value> {
+        &mut self.globals
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    /// Append a new chunk of code to the session's source, returning the
+    /// offset at which it starts.
+    pub fn add_source(&mut self, chunk: &str) -> usize {
+        self.source.push(chunk)
+    }
+
+    /// Load a script from a bytecode file, so it can be passed to `interpret`.
+    pub fn load(&mut self, bytes: &[u8]) -> Result<Function, LoadError> {
+        serialize::decode(bytes, &mut self.source)
+    }
+
+    /// Run the function for a compiled script.
+    pub fn interpret(&mut self, script: Function) -> Result<(), UncaughtError> {
+        let closure = Rc::new(Closure { function: Rc::new(script), upvalues: Vec::new() });
+        self.stack.push(Value::Closure(closure.clone()));
+        self.frames.push(CallFrame { closure, ip: 0, base: 0 });
+
+        match self.run() {
+            Ok(()) => {
+                // The script's return value
+                self.stack.pop();
+                Ok(())
+            },
+
+            Err(error) => Err(self.uncaught(error, false)),
+        }
+    }
+
+    /// Call a function, class or method from Rust, rather than from Lox code.
+    /// The arguments should already be checked against the callee's arity,
+    /// and native functions are best called directly.
+    ///
+    /// There's no call site, so the stack trace of an error ends at the
+    /// callee.
+    pub fn call_from_host(&mut self, callee: Value, args: &[Value]) -> Result<Value, UncaughtError> {
+        self.stack.push(callee);
+        self.stack.extend_from_slice(args);
+
+        // Classes without an initializer don't push a frame
+        let result = self.call_value(args.len())
+            .and_then(|()| if self.frames.is_empty() { Ok(()) } else { self.run() });
+
+        match result {
+            Ok(()) => Ok(self.stack.pop().unwrap()),
+            Err(error) => Err(self.uncaught(error, true)),
+        }
+    }
+
+    /// Capture the stack trace for an error that made it all the way out, and
+    /// throw away whatever the error left behind.
+    fn uncaught(&mut self, error: Spanned<RuntimeError>, from_host: bool) -> UncaughtError {
+        let trace = self.stack_trace(error.span, from_host);
+
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+
+        UncaughtError { error, trace }
+    }
+
+    /// Build a stack trace from the frames in progress, for an error at
+    /// `span`. Unless the outermost frame was called from Rust, it's the
+    /// script.
+    fn stack_trace(&self, span: Span, from_host: bool) -> Vec<StackFrame> {
+        let mut trace = Vec::with_capacity(self.frames.len());
+        let mut span = span;
+
+        for (depth, frame) in self.frames.iter().enumerate().rev() {
+            let function = match &frame.closure.function.name {
+                Some(name) => name.to_string(),
+                None if depth == 0 && !from_host => String::from("<script>"),
+                None => String::from("<lambda>"),
+            };
+
+            trace.push(StackFrame { function, span });
+
+            // The frame below is in the middle of the call instruction that
+            // created this frame
+            if depth > 0 {
+                let caller = &self.frames[depth - 1];
+                span = caller.closure.function.chunk.spans[caller.ip - 1];
+            }
+        }
+
+        trace
+    }
+
+    fn run(&mut self) -> VmResult {
+        loop {
+            if self.trace {
+                self.trace_instruction();
+            }
+
+            let byte = self.read_byte();
+            let Ok(op) = OpCode::try_from(byte) else {
+                panic!("Invalid opcode {byte}");
+            };
+
+            match op {
+                OpCode::Constant => {
+                    let value = match self.read_constant() {
+                        Constant::Num(num) => Value::Num(*num),
+                        Constant::Str(string) => Value::Str((*string).into()),
+                        Constant::Function(_) => unreachable!(),
+                    };
+
+                    self.stack.push(value);
+                },
+
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Bool(true)),
+                OpCode::False => self.stack.push(Value::Bool(false)),
+
+                OpCode::Pop => {
+                    self.stack.pop();
+                },
+
+                OpCode::GetLocal => {
+                    let slot = self.frame().base + self.read_byte() as usize;
+                    self.stack.push(self.stack[slot].clone());
+                },
+
+                OpCode::SetLocal => {
+                    let slot = self.frame().base + self.read_byte() as usize;
+                    self.stack[slot] = self.peek(0).clone();
+                },
+
+                OpCode::GetGlobal => {
+                    let name = self.read_string();
+
+                    let Some(value) = self.globals.get(&name) else {
+                        return Err(self.error(RuntimeError::UndeclaredVar(name.to_string())));
+                    };
+
+                    self.stack.push(value.clone());
+                },
+
+                OpCode::DefineGlobal => {
+                    let name = self.read_string();
+                    let value = self.stack.pop().unwrap();
+                    self.globals.insert(name, value);
+                },
+
+                OpCode::SetGlobal => {
+                    let name = self.read_string();
+                    let value = self.peek(0).clone();
+
+                    let Some(global) = self.globals.get_mut(&name) else {
+                        return Err(self.error(RuntimeError::UndeclaredVar(name.to_string())));
+                    };
+
+                    *global = value;
+                },
+
+                OpCode::GetUpvalue => {
+                    let idx = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[idx].clone();
+
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+
+                    self.stack.push(value);
+                },
+
+                OpCode::SetUpvalue => {
+                    let idx = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[idx].clone();
+                    let value = self.peek(0).clone();
+
+                    match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    };
+                },
+
+                OpCode::GetProperty => {
+                    let name = self.read_string();
+                    let object = self.stack.pop().unwrap();
+                    let value = self.get_property(object, name)?;
+                    self.stack.push(value);
+                },
+
+                OpCode::SetProperty => {
+                    let name = self.read_string();
+                    let value = self.stack.pop().unwrap();
+                    let object = self.stack.pop().unwrap();
+
+                    let Value::Instance(instance) = object else {
+                        return Err(self.error(RuntimeError::IllegalFieldAccess));
+                    };
+
+                    instance.fields.borrow_mut().insert(name, value.clone());
+                    self.stack.push(value);
+                },
+
+                OpCode::GetSuper => {
+                    let name = self.read_string();
+                    let Some(Value::Class(superclass)) = self.stack.pop() else {
+                        return Err(self.error(RuntimeError::InvalidBytecode("super of something that isn't a class")));
+                    };
+
+                    let receiver = self.stack.pop().unwrap();
+
+                    let method = superclass.methods.borrow().get(&name).cloned();
+
+                    let Some(method) = method else {
+                        return Err(self.error(RuntimeError::UndefinedProperty(name.to_string())));
+                    };
+
+                    let bound = self.heap.alloc(BoundMethod { receiver, method });
+                    self.stack.push(Value::BoundMethod(bound));
+                },
+
+                OpCode::GetIndex => {
+                    let index = self.stack.pop().unwrap();
+                    let object = self.stack.pop().unwrap();
+                    let value = self.get_index(object, index)?;
+                    self.stack.push(value);
+                },
+
+                OpCode::SetIndex => {
+                    let value = self.stack.pop().unwrap();
+                    let index = self.stack.pop().unwrap();
+                    let object = self.stack.pop().unwrap();
+                    self.set_index(object, index, value.clone())?;
+                    self.stack.push(value);
+                },
+
+                OpCode::Equal => {
+                    let right = self.stack.pop().unwrap();
+                    let left = self.stack.pop().unwrap();
+                    self.stack.push(Value::Bool(left == right));
+                },
+
+                OpCode::Greater => self.binary_num_op(|left, right| Value::Bool(left > right))?,
+                OpCode::GreaterEqual => self.binary_num_op(|left, right| Value::Bool(left >= right))?,
+                OpCode::Less => self.binary_num_op(|left, right| Value::Bool(left < right))?,
+                OpCode::LessEqual => self.binary_num_op(|left, right| Value::Bool(left <= right))?,
+                OpCode::Subtract => self.binary_num_op(|left, right| Value::Num(left - right))?,
+                OpCode::Multiply => self.binary_num_op(|left, right| Value::Num(left * right))?,
+                OpCode::Divide => self.binary_num_op(|left, right| Value::Num(left / right))?,
+
+                OpCode::Add => {
+                    let right = self.stack.pop().unwrap();
+                    let left = self.stack.pop().unwrap();
+
+                    let result = match (left, right) {
+                        (Value::Num(left), Value::Num(right)) => Value::Num(left + right),
+                        (Value::Str(left), Value::Str(right)) => {
+                            Value::Str(format!("{left}{right}").into())
+                        },
+                        _ => return Err(self.error(RuntimeError::MultiTypeError("string or number"))),
+                    };
+
+                    self.stack.push(result);
+                },
+
+                OpCode::Not => {
+                    let value = self.stack.pop().unwrap();
+                    self.stack.push(Value::Bool(!value.is_truthy()));
+                },
+
+                OpCode::Negate => {
+                    let Value::Num(num) = self.stack.pop().unwrap() else {
+                        return Err(self.error(RuntimeError::TypeError("number")));
+                    };
+
+                    self.stack.push(Value::Num(-num));
+                },
+
+                OpCode::Print => {
+                    let value = self.stack.pop().unwrap();
+                    writeln!(self.output, "{value}").map_err(|err| self.error(RuntimeError::Output(err.to_string())))?;
+                },
+
+                OpCode::Jump => {
+                    let jump = self.read_u16() as usize;
+                    self.frame_mut().ip += jump;
+                },
+
+                OpCode::JumpIfFalse => {
+                    let jump = self.read_u16() as usize;
+
+                    if !self.peek(0).is_truthy() {
+                        self.frame_mut().ip += jump;
+                    }
+                },
+
+                OpCode::Loop => {
+                    let jump = self.read_u16() as usize;
+                    self.frame_mut().ip -= jump;
+                },
+
+                OpCode::Call => {
+                    let argc = self.read_byte() as usize;
+                    self.call_value(argc)?;
+                },
+
+                OpCode::Closure => {
+                    let Constant::Function(function) = self.read_constant() else { unreachable!() };
+                    let function = function.clone();
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+
+                    for _ in 0..function.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+
+                        let upvalue = if is_local {
+                            self.capture_upvalue(self.frame().base + index)
+                        } else {
+                            self.frame().closure.upvalues[index].clone()
+                        };
+
+                        upvalues.push(upvalue);
+                    }
+
+                    let closure = self.heap.alloc(Closure { function, upvalues });
+                    self.stack.push(Value::Closure(closure));
+                },
+
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                },
+
+                OpCode::Return => {
+                    let result = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+
+                    self.close_upvalues(frame.base);
+                    self.stack.truncate(frame.base);
+                    self.stack.push(result);
+
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                },
+
+                OpCode::Class => {
+                    let name = self.read_string();
+                    let class = Class { name, methods: RefCell::new(HashMap::new()) };
+                    let class = self.heap.alloc(class);
+                    self.stack.push(Value::Class(class));
+                },
+
+                OpCode::Inherit => {
+                    let Value::Class(superclass) = self.peek(1) else {
+                        return Err(self.error(RuntimeError::SuperclassNotClass));
+                    };
+
+                    let Value::Class(class) = self.peek(0) else {
+                        return Err(self.error(RuntimeError::InvalidBytecode("inheritance into something that isn't a class")));
+                    };
+
+                    class.methods.borrow_mut().extend(
+                        superclass.methods.borrow().iter().map(|(&name, method)| (name, method.clone()))
+                    );
+
+                    self.stack.pop();
+                },
+
+                OpCode::Method => {
+                    let name = self.read_string();
+                    let Some(Value::Closure(method)) = self.stack.pop() else {
+                        return Err(self.error(RuntimeError::InvalidBytecode("method that isn't a function")));
+                    };
+
+                    let Value::Class(class) = self.peek(0) else {
+                        return Err(self.error(RuntimeError::InvalidBytecode("method on something that isn't a class")));
+                    };
+
+                    class.methods.borrow_mut().insert(name, method);
+                },
+
+                OpCode::List => {
+                    let count = self.read_u16() as usize;
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    let list = self.heap.alloc(RefCell::new(elements));
+                    self.stack.push(Value::List(List(list)));
+                },
+
+                OpCode::Map => {
+                    let count = self.read_u16() as usize;
+                    let values = self.stack.split_off(self.stack.len() - 2 * count);
+                    let mut entries = Entries::default();
+                    let mut values = values.into_iter();
+
+                    while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                        let key = HashKey::new(key).map_err(|err| self.error(err))?;
+                        entries.insert(key, value);
+                    }
+
+                    let map = self.heap.alloc(RefCell::new(entries));
+                    self.stack.push(Value::Map(Map(map)));
+                },
+
+                OpCode::Interpolate => {
+                    let count = self.read_u16() as usize;
+                    let mut string = String::new();
+
+                    for part in self.stack.drain(self.stack.len() - count..) {
+                        string.push_str(&part.to_string());
+                    }
+
+                    self.stack.push(Value::Str(string.into()));
+                },
+            }
+        }
+    }
+
+    fn trace_instruction(&self) {
+        let mut line = String::from("          ");
+
+        for value in &self.stack {
+            line.push_str(&format!("[ {} ]", value.repr()));
+        }
+
+        let frame = self.frame();
+        let chunk = &frame.closure.function.chunk;
+        let mut instruction = String::new();
+        disassemble_instruction(chunk, frame.ip, &self.source, &mut instruction);
+
+        // The trace is best-effort, so failing to write it isn't an error
+        let _ = writeln!(self.diagnostics, "{line}");
+        let _ = write!(self.diagnostics, "{instruction}");
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.closure.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let frame = self.frame_mut();
+        let value = frame.closure.function.chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        value
+    }
+
+    fn read_constant(&mut self) -> &Constant {
+        let idx = self.read_u16() as usize;
+        &self.frame().closure.function.chunk.constants[idx]
+    }
+
+    fn read_string(&mut self) -> Symbol {
+        let Constant::Str(string) = self.read_constant() else { unreachable!() };
+        *string
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
+    /// The span of the instruction that is currently executing.
+    fn current_span(&self) -> Span {
+        let frame = self.frame();
+        frame.closure.function.chunk.spans[frame.ip - 1]
+    }
+
+    fn error(&self, error: RuntimeError) -> Spanned<RuntimeError> {
+        Spanned { value: error, span: self.current_span() }
+    }
+
+    fn binary_num_op(&mut self, op: impl Fn(f64, f64) -> Value) -> VmResult {
+        let right = self.stack.pop().unwrap();
+        let left = self.stack.pop().unwrap();
+
+        let (Value::Num(left), Value::Num(right)) = (left, right) else {
+            return Err(self.error(RuntimeError::TypeError("number")));
+        };
+
+        self.stack.push(op(left, right));
+        Ok(())
+    }
+
+    fn get_property(&mut self, object: Value, name: Symbol) -> VmResult<Value> {
+        let method = match &object {
+            Value::Instance(instance) => {
+                if let Some(value) = instance.fields.borrow().get(&name) {
+                    return Ok(value.clone());
+                }
+
+                if let Some(method) = instance.class.methods.borrow().get(&name) {
+                    let bound = BoundMethod { receiver: object.clone(), method: method.clone() };
+                    return Ok(Value::BoundMethod(self.heap.alloc(bound)));
+                }
+
+                None
+            },
+
+            Value::List(_) => native::list_method(name.as_str()),
+            Value::Map(_) => native::map_method(name.as_str()),
+            _ => return Err(self.error(RuntimeError::IllegalPropertyAccess)),
+        };
+
+        let Some(method) = method else {
+            return Err(self.error(RuntimeError::UndefinedProperty(name.to_string())));
+        };
+
+        let builtin = BuiltinMethod { receiver: object, method, span: self.current_span() };
+        Ok(Value::BuiltinMethod(self.heap.alloc(builtin)))
+    }
+
+    fn get_index(&self, object: Value, index: Value) -> VmResult<Value> {
+        match object {
+            Value::List(list) => {
+                let list = list.0.borrow();
+                let idx = native::checked_position(&index, list.len(), list.len())
+                    .map_err(|err| self.error(err))?;
+
+                Ok(list[idx].clone())
+            },
+
+            Value::Map(map) => {
+                let key = HashKey::new(index).map_err(|err| self.error(err))?;
+
+                map.0.borrow().get(&key).cloned().ok_or_else(|| {
+                    self.error(RuntimeError::UndefinedKey(key.0.repr()))
+                })
+            },
+
+            _ => Err(self.error(RuntimeError::NotIndexable)),
+        }
+    }
+
+    fn set_index(&self, object: Value, index: Value, value: Value) -> VmResult {
+        match object {
+            Value::List(list) => {
+                let len = list.0.borrow().len();
+                let idx = native::checked_position(&index, len, len)
+                    .map_err(|err| self.error(err))?;
+
+                list.0.borrow_mut()[idx] = value;
+            },
+
+            Value::Map(map) => {
+                let key = HashKey::new(index).map_err(|err| self.error(err))?;
+                map.0.borrow_mut().insert(key, value);
+            },
+
+            _ => return Err(self.error(RuntimeError::NotIndexable)),
+        }
+
+        Ok(())
+    }
+
+    /// Call the value that sits below `argc` arguments on the stack.
+    fn call_value(&mut self, argc: usize) -> VmResult {
+        let base = self.stack.len() - argc - 1;
+
+        match self.stack[base].clone() {
+            Value::Closure(closure) => self.call(closure, argc),
+
+            Value::BoundMethod(bound) => {
+                self.stack[base] = bound.receiver.clone();
+                self.call(bound.method.clone(), argc)
+            },
+
+            Value::Class(class) => {
+                let init = class.methods.borrow().get(&Symbol::INIT).cloned();
+
+                if init.is_none() && argc != 0 {
+                    return Err(self.error(RuntimeError::ArityMismatch(Arity::Exactly(0), argc)));
+                }
+
+                let fields = RefCell::new(HashMap::new());
+                self.stack[base] = Value::Instance(self.heap.alloc(Instance { class, fields }));
+
+                match init {
+                    Some(init) => self.call(init, argc),
+                    None => Ok(()),
+                }
+            },
+
+            Value::Native(native) => {
+                if !native.arity.accepts(argc) {
+                    return Err(self.error(RuntimeError::ArityMismatch(native.arity, argc)));
+                }
+
+                // Native functions work on tree-walker values, so only data
+                // can cross over
+                let args = self.stack[base + 1..]
+                    .iter()
+                    .map(Value::to_lox)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|type_name| self.error(RuntimeError::Unsupported(type_name)))?;
+
+                let result = native.call_native(&args).map_err(|err| self.error(err))?;
+                let result = Value::from_lox(&result, &mut self.heap)
+                    .map_err(|type_name| self.error(RuntimeError::Unsupported(type_name)))?;
+
+                self.stack.truncate(base);
+                self.stack.push(result);
+                Ok(())
+            },
+
+            Value::BuiltinMethod(builtin) => {
+                if argc != builtin.method.arity {
+                    return Err(self.error(RuntimeError::ArityMismatch(builtin.method.arity.into(), argc)));
+                }
+
+                // The receiver goes in the callee's slot, right before the
+                // arguments
+                self.stack[base] = builtin.receiver.clone();
+
+                let result = (builtin.method.fun)(&mut self.heap, &self.stack[base..])
+                    .map_err(|err| Spanned { value: err, span: builtin.span })?;
+
+                self.stack.truncate(base);
+                self.stack.push(result);
+                Ok(())
+            },
+
+            _ => Err(self.error(RuntimeError::NotCallable)),
+        }
+    }
+
+    fn call(&mut self, closure: Rc<Closure>, argc: usize) -> VmResult {
+        let arity = closure.function.arity;
+
+        if argc != arity {
+            return Err(self.error(RuntimeError::ArityMismatch(arity.into(), argc)));
+        }
+
+        // The frame for the script itself doesn't count as a call
+        if self.frames.len() > self.max_call_depth {
+            return Err(self.error(RuntimeError::StackOverflow));
+        }
+
+        let base = self.stack.len() - argc - 1;
+        self.frames.push(CallFrame { closure, ip: 0, base });
+        Ok(())
+    }
+
+    /// Find or create the upvalue for a stack slot, so closures capturing the
+    /// same variable share it.
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let existing = self.open_upvalues
+            .iter()
+            .find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(open) if open == slot));
+
+        if let Some(upvalue) = existing {
+            return upvalue.clone();
+        }
+
+        let upvalue = self.heap.alloc(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    /// Move the values of all upvalues pointing at `from` or above off the
+    /// stack.
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            let Upvalue::Open(slot) = *upvalue else { return false };
+
+            if slot < from {
+                return true;
+            }
+
+            *upvalue = Upvalue::Closed(stack[slot].clone());
+            false
+        });
+    }
+}
@@ -0,0 +1,266 @@
+use std::fmt::Display;
+use std::rc::Rc;
+
+use crate::span::Span;
+use crate::symbol::Symbol;
+
+/// The instructions understood by the VM.
+///
+/// Operands are stored inline in the code, right after the opcode. Constant
+/// indices, jump offsets and element counts take up two bytes (big-endian),
+/// stack slots, upvalue indices and argument counts take up a single byte.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    /// Push a constant from the constant pool (`u16` constant index)
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+
+    /// Read or write a local variable (`u8` stack slot)
+    GetLocal,
+    SetLocal,
+
+    /// Read, define or write a global variable (`u16` name constant)
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+
+    /// Read or write a captured variable (`u8` upvalue index)
+    GetUpvalue,
+    SetUpvalue,
+
+    /// Read or write a property of an instance (`u16` name constant)
+    GetProperty,
+    SetProperty,
+
+    /// Look up a superclass method, bound to `this` (`u16` name constant)
+    GetSuper,
+
+    GetIndex,
+    SetIndex,
+
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+
+    Print,
+
+    /// Jump forward (`u16` offset)
+    Jump,
+
+    /// Jump forward if the top of the stack is falsy, without popping it
+    /// (`u16` offset)
+    JumpIfFalse,
+
+    /// Jump backward (`u16` offset)
+    Loop,
+
+    /// Call the value below the arguments on the stack (`u8` argument count)
+    Call,
+
+    /// Wrap a function in a closure (`u16` function constant), followed by a
+    /// pair of bytes for every upvalue it captures: whether it captures a
+    /// local of the enclosing function (1) or one of its upvalues (0), and
+    /// the slot or index of the captured variable.
+    Closure,
+
+    /// Move the local on top of the stack to the heap, for closures that
+    /// captured it
+    CloseUpvalue,
+
+    Return,
+
+    /// Create a new class (`u16` name constant)
+    Class,
+
+    /// Copy the methods of the superclass into the class on top of the stack
+    Inherit,
+
+    /// Add the closure on top of the stack as a method to the class right
+    /// below it (`u16` name constant)
+    Method,
+
+    /// Build a list out of the elements on top of the stack (`u16` count)
+    List,
+
+    /// Build a map out of the key-value pairs on top of the stack (`u16`
+    /// number of entries)
+    Map,
+
+    /// Concatenate the values on top of the stack into a single string (`u16`
+    /// count)
+    Interpolate,
+}
+
+impl OpCode {
+    /// All opcodes, indexed by their byte value.
+    const ALL: [OpCode; 42] = [
+        OpCode::Constant,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::GetIndex,
+        OpCode::SetIndex,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::GreaterEqual,
+        OpCode::Less,
+        OpCode::LessEqual,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Return,
+        OpCode::Class,
+        OpCode::Inherit,
+        OpCode::Method,
+        OpCode::List,
+        OpCode::Map,
+        OpCode::Interpolate,
+    ];
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match OpCode::ALL.get(byte as usize) {
+            Some(&op) if op as u8 == byte => Ok(op),
+            _ => Err(byte),
+        }
+    }
+}
+
+/// A value that is known at compile time, and is stored in a chunk's
+/// constant pool.
+#[derive(Debug, Clone)]
+pub enum Constant {
+    Num(f64),
+
+    /// A string literal, or the name of a variable, property or class
+    Str(Symbol),
+    Function(Rc<Function>),
+}
+
+impl Display for Constant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constant::Num(val) => write!(f, "{val}"),
+            Constant::Str(val) => write!(f, "\"{val}\""),
+            Constant::Function(fun) => write!(f, "{fun}"),
+        }
+    }
+}
+
+/// A chunk of bytecode, together with the constants it refers to.
+///
+/// Every byte of code has a span associated with it, pointing at the source
+/// that the instruction it belongs to was compiled from.
+#[derive(Debug, Default, Clone)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Constant>,
+    pub spans: Vec<Span>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, byte: u8, span: Span) {
+        self.code.push(byte);
+        self.spans.push(span);
+    }
+
+    pub fn write_op(&mut self, op: OpCode, span: Span) {
+        self.write(op as u8, span);
+    }
+
+    pub fn write_u16(&mut self, value: u16, span: Span) {
+        let [hi, lo] = value.to_be_bytes();
+        self.write(hi, span);
+        self.write(lo, span);
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    /// Overwrite a two-byte operand that was written earlier.
+    pub fn patch_u16(&mut self, offset: usize, value: u16) {
+        let [hi, lo] = value.to_be_bytes();
+        self.code[offset] = hi;
+        self.code[offset + 1] = lo;
+    }
+
+    /// Add a constant to the pool, and return its index.
+    pub fn add_constant(&mut self, constant: Constant) -> usize {
+        self.constants.push(constant);
+        self.constants.len() - 1
+    }
+}
+
+/// A compiled function: its bytecode, and everything the VM needs to know to
+/// call it.
+#[derive(Debug, Default, Clone)]
+pub struct Function {
+    /// The name of the function, or `None` for anonymous functions and the
+    /// top-level script
+    pub name: Option<Rc<String>>,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<function {name}>"),
+            None => write!(f, "<function>"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_round_trip() {
+        for byte in 0..=OpCode::Interpolate as u8 {
+            let op = OpCode::try_from(byte).unwrap();
+            assert_eq!(op as u8, byte);
+        }
+
+        assert!(OpCode::try_from(OpCode::Interpolate as u8 + 1).is_err());
+        assert!(OpCode::try_from(u8::MAX).is_err());
+    }
+}
@@ -0,0 +1,821 @@
+use std::fmt::Display;
+use std::rc::Rc;
+
+use super::chunk::{Chunk, Constant, Function, OpCode};
+use crate::interpreter::Visitor;
+use crate::span::{Span, Spanned};
+use crate::symbol::Symbol;
+use crate::syntax::ast::{Ast, Expr, Literal, Stmt};
+use crate::syntax::tokens::{Token, TokenType};
+
+type CompileResult<T = ()> = Result<T, Spanned<CompileError>>;
+
+/// Compiles a syntax tree into bytecode.
+///
+/// The compiler assumes the tree already made it through the `Resolver`, so
+/// it doesn't check for things like `return` outside of a function. It does
+/// its own bookkeeping of local variables, though, since locals live in
+/// stack slots rather than in environments.
+pub struct Compiler {
+    /// The functions currently being compiled, innermost last
+    functions: Vec<FunctionState>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+struct FunctionState {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+}
+
+struct Local {
+    /// The name of the local, or `None` for the slot that holds the function
+    /// being called, and for slots set aside by `reserve_slot`
+    name: Option<Symbol>,
+
+    /// The depth of the scope the local was declared in, or `None` while its
+    /// initializer is still being compiled
+    depth: Option<usize>,
+
+    /// Whether a closure captured the local, meaning it needs to be moved off
+    /// the stack when it goes out of scope
+    is_captured: bool,
+}
+
+/// A variable captured by a closure: either a local of the enclosing
+/// function, or one of the enclosing function's own upvalues.
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct UpvalueRef {
+    is_local: bool,
+    index: u8,
+}
+
+/// The jumps for `break` and `continue` statements in a loop, which can only
+/// be patched once we know where the loop ends.
+struct Loop {
+    /// The scope depth right outside of the loop body
+    depth: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+impl FunctionState {
+    fn new(name: Option<Rc<String>>, kind: FunctionKind) -> Self {
+        // The first stack slot holds the function that is being called, or
+        // the receiver for methods.
+        let reserved = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => Some(Symbol::THIS),
+            FunctionKind::Script | FunctionKind::Function => None,
+        };
+
+        Self {
+            function: Function { name, ..Function::default() },
+            kind,
+            locals: vec![Local { name: reserved, depth: Some(0), is_captured: false }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+        }
+    }
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self { functions: vec![FunctionState::new(None, FunctionKind::Script)] }
+    }
+
+    /// Compile a program into the function for its top-level script.
+    pub fn compile(mut self, ast: &Ast) -> CompileResult<Function> {
+        for statement in ast {
+            self.visit(statement)?;
+        }
+
+        let (function, _) = self.end_function();
+        Ok(function)
+    }
+
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().function.chunk
+    }
+
+    fn emit(&mut self, op: OpCode, span: Span) {
+        self.chunk().write_op(op, span);
+    }
+
+    fn emit_byte(&mut self, byte: u8, span: Span) {
+        self.chunk().write(byte, span);
+    }
+
+    fn emit_with_u16(&mut self, op: OpCode, operand: u16, span: Span) {
+        self.emit(op, span);
+        self.chunk().write_u16(operand, span);
+    }
+
+    /// Emit an instruction with an operand that counts a number of elements
+    fn emit_with_count(&mut self, op: OpCode, count: usize, span: Span) -> CompileResult {
+        let Ok(count) = u16::try_from(count) else {
+            return Err(Spanned { value: CompileError::TooManyElements, span });
+        };
+
+        self.emit_with_u16(op, count, span);
+        Ok(())
+    }
+
+    fn make_constant(&mut self, constant: Constant, span: Span) -> CompileResult<u16> {
+        let idx = self.chunk().add_constant(constant);
+
+        u16::try_from(idx).map_err(|_| Spanned { value: CompileError::TooManyConstants, span })
+    }
+
+    fn emit_constant(&mut self, constant: Constant, span: Span) -> CompileResult {
+        let idx = self.make_constant(constant, span)?;
+        self.emit_with_u16(OpCode::Constant, idx, span);
+        Ok(())
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> CompileResult<u16> {
+        self.make_constant(Constant::Str(name.name()), name.span)
+    }
+
+    /// Emit a jump with a placeholder offset, and return the position of the
+    /// offset so it can be patched later.
+    fn emit_jump(&mut self, op: OpCode, span: Span) -> usize {
+        self.emit_with_u16(op, u16::MAX, span);
+        self.chunk().code.len() - 2
+    }
+
+    /// Point a jump emitted earlier at the current end of the code.
+    fn patch_jump(&mut self, operand: usize, span: Span) -> CompileResult {
+        let jump = self.chunk().code.len() - operand - 2;
+
+        let Ok(jump) = u16::try_from(jump) else {
+            return Err(Spanned { value: CompileError::JumpTooLarge, span });
+        };
+
+        self.chunk().patch_u16(operand, jump);
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, start: usize, span: Span) -> CompileResult {
+        // Account for the loop instruction itself
+        let jump = self.chunk().code.len() - start + 3;
+
+        let Ok(jump) = u16::try_from(jump) else {
+            return Err(Spanned { value: CompileError::JumpTooLarge, span });
+        };
+
+        self.emit_with_u16(OpCode::Loop, jump, span);
+        Ok(())
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self, span: Span) {
+        self.current().scope_depth -= 1;
+        let depth = self.current().scope_depth;
+
+        self.discard_locals(depth, span);
+
+        let state = self.current();
+        let remaining = state.locals.iter().take_while(|local| local.depth <= Some(depth)).count();
+        state.locals.truncate(remaining);
+    }
+
+    /// Emit the instructions that pop every local deeper than `depth` off
+    /// the stack, without forgetting about them. Used when leaving a scope,
+    /// and when jumping out of one with `break` or `continue`.
+    fn discard_locals(&mut self, depth: usize, span: Span) {
+        let captured: Vec<_> = self.current().locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > Some(depth))
+            .map(|local| local.is_captured)
+            .collect();
+
+        for is_captured in captured {
+            if is_captured {
+                self.emit(OpCode::CloseUpvalue, span);
+            } else {
+                self.emit(OpCode::Pop, span);
+            }
+        }
+    }
+
+    /// Add a local to the current scope, unless we're at the top level, where
+    /// variables are globals.
+    fn declare_local(&mut self, name: &Token) -> CompileResult {
+        let state = self.current();
+
+        if state.scope_depth == 0 {
+            return Ok(());
+        }
+
+        if state.locals.len() > u8::MAX as usize {
+            return Err(Spanned { value: CompileError::TooManyLocals, span: name.span });
+        }
+
+        state.locals.push(Local { name: Some(name.name()), depth: None, is_captured: false });
+        Ok(())
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.current();
+
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        let depth = state.scope_depth;
+        state.locals.last_mut().unwrap().depth = Some(depth);
+    }
+
+    /// Finish the declaration of a variable whose value is on top of the
+    /// stack: locals simply stay where they are, globals get defined.
+    fn define_variable(&mut self, name: &Token) -> CompileResult {
+        if self.current().scope_depth > 0 {
+            self.mark_initialized();
+            return Ok(());
+        }
+
+        let global = self.identifier_constant(name)?;
+        self.emit_with_u16(OpCode::DefineGlobal, global, name.span);
+        Ok(())
+    }
+
+    fn resolve_local(&self, level: usize, name: Symbol) -> Option<u8> {
+        self.functions[level].locals
+            .iter()
+            .rposition(|local| local.name == Some(name))
+            .map(|slot| slot as u8)
+    }
+
+    /// Find a variable in one of the functions enclosing the one at `level`,
+    /// threading it through the upvalues of every function in between.
+    fn resolve_upvalue(&mut self, level: usize, name: Symbol, span: Span) -> CompileResult<Option<u8>> {
+        if level == 0 {
+            return Ok(None);
+        }
+
+        if let Some(slot) = self.resolve_local(level - 1, name) {
+            self.functions[level - 1].locals[slot as usize].is_captured = true;
+            return self.add_upvalue(level, UpvalueRef { is_local: true, index: slot }, span).map(Some);
+        }
+
+        if let Some(index) = self.resolve_upvalue(level - 1, name, span)? {
+            return self.add_upvalue(level, UpvalueRef { is_local: false, index }, span).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, level: usize, upvalue: UpvalueRef, span: Span) -> CompileResult<u8> {
+        let upvalues = &mut self.functions[level].upvalues;
+
+        if let Some(idx) = upvalues.iter().position(|&existing| existing == upvalue) {
+            return Ok(idx as u8);
+        }
+
+        if upvalues.len() > u8::MAX as usize {
+            return Err(Spanned { value: CompileError::TooManyUpvalues, span });
+        }
+
+        upvalues.push(upvalue);
+        Ok((upvalues.len() - 1) as u8)
+    }
+
+    /// Emit the instruction to read a variable, or to write the value on top
+    /// of the stack to it when `assign` is set.
+    fn named_variable(&mut self, name: &Token, assign: bool) -> CompileResult {
+        let level = self.functions.len() - 1;
+        let span = name.span;
+
+        if let Some(slot) = self.resolve_local(level, name.name()) {
+            let op = if assign { OpCode::SetLocal } else { OpCode::GetLocal };
+            self.emit(op, span);
+            self.emit_byte(slot, span);
+        } else if let Some(index) = self.resolve_upvalue(level, name.name(), span)? {
+            let op = if assign { OpCode::SetUpvalue } else { OpCode::GetUpvalue };
+            self.emit(op, span);
+            self.emit_byte(index, span);
+        } else {
+            let op = if assign { OpCode::SetGlobal } else { OpCode::GetGlobal };
+            let global = self.identifier_constant(name)?;
+            self.emit_with_u16(op, global, span);
+        }
+
+        Ok(())
+    }
+
+    /// Compile a function body, and emit the instruction that creates a
+    /// closure for it in the enclosing function.
+    fn function(
+        &mut self,
+        name: Option<&Token>,
+        params: &[Token],
+        body: &[Stmt],
+        kind: FunctionKind,
+        span: Span,
+    ) -> CompileResult {
+        let name = name.map(|name| Rc::new(name.to_string()));
+        self.functions.push(FunctionState::new(name, kind));
+        self.current().function.arity = params.len();
+        self.begin_scope();
+
+        for param in params {
+            self.declare_local(param)?;
+            self.mark_initialized();
+        }
+
+        for statement in body {
+            self.visit(statement)?;
+        }
+
+        let (function, upvalues) = self.end_function();
+        let constant = self.make_constant(Constant::Function(Rc::new(function)), span)?;
+        self.emit_with_u16(OpCode::Closure, constant, span);
+
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8, span);
+            self.emit_byte(upvalue.index, span);
+        }
+
+        Ok(())
+    }
+
+    /// Finish the innermost function by adding an implicit return, and hand
+    /// it back together with the variables it captured.
+    fn end_function(&mut self) -> (Function, Vec<UpvalueRef>) {
+        let span = self.chunk().spans.last().copied().unwrap_or_default();
+        self.emit_implicit_return(span);
+
+        let mut state = self.functions.pop().unwrap();
+        state.function.upvalue_count = state.upvalues.len();
+        (state.function, state.upvalues)
+    }
+
+    fn emit_implicit_return(&mut self, span: Span) {
+        // Initializers always hand back the instance they were called on
+        if self.current().kind == FunctionKind::Initializer {
+            self.emit(OpCode::GetLocal, span);
+            self.emit_byte(0, span);
+        } else {
+            self.emit(OpCode::Nil, span);
+        }
+
+        self.emit(OpCode::Return, span);
+    }
+
+    /// A function or class declared as the body of an if or a loop, rather
+    /// than inside of a block, belongs to the surrounding scope. Since the
+    /// body might get skipped or run more than once, its slot gets set aside
+    /// up front, for `body` to copy the declaration into. Returns the slot, if
+    /// one was needed.
+    fn reserve_slot(&mut self, body: &Stmt) -> CompileResult<Option<u8>> {
+        let (Stmt::Fun { name, .. } | Stmt::Class { name, .. }) = body else {
+            return Ok(None);
+        };
+
+        if self.current().scope_depth == 0 {
+            return Ok(None);
+        }
+
+        let slot = self.current().locals.len();
+
+        if slot > u8::MAX as usize {
+            return Err(Spanned { value: CompileError::TooManyLocals, span: name.span });
+        }
+
+        // The name only becomes visible once the declaration was compiled
+        self.current().locals.push(Local { name: None, depth: None, is_captured: false });
+        self.mark_initialized();
+        self.emit(OpCode::Nil, name.span);
+
+        Ok(Some(slot as u8))
+    }
+
+    /// Compile the body of an if or a loop, which goes into the slot set aside
+    /// by `reserve_slot` if it's a declaration.
+    fn body(&mut self, body: &Stmt, slot: Option<u8>) -> CompileResult {
+        let Some(slot) = slot else {
+            return self.visit(body);
+        };
+
+        let (Stmt::Fun { name, .. } | Stmt::Class { name, .. }) = body else { unreachable!() };
+
+        self.begin_scope();
+        self.visit(body)?;
+        self.named_variable(name, false)?;
+        self.emit(OpCode::SetLocal, name.span);
+        self.emit_byte(slot, name.span);
+        self.emit(OpCode::Pop, name.span);
+        self.end_scope(name.span);
+
+        self.current().locals[slot as usize].name = Some(name.name());
+        Ok(())
+    }
+
+    fn while_statement(&mut self, condition: &Expr, body: &Stmt, increment: Option<&Expr>) -> CompileResult {
+        let span = condition.span();
+        let slot = self.reserve_slot(body)?;
+        let loop_start = self.chunk().code.len();
+
+        self.visit(condition)?;
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+        self.emit(OpCode::Pop, span);
+
+        let depth = self.current().scope_depth;
+        self.current().loops.push(Loop { depth, breaks: Vec::new(), continues: Vec::new() });
+        let body_result = self.body(body, slot);
+        let lp = self.current().loops.pop().unwrap();
+        body_result?;
+
+        // `continue` still runs the increment clause of a `for` loop
+        for jump in lp.continues {
+            self.patch_jump(jump, span)?;
+        }
+
+        if let Some(increment) = increment {
+            self.visit(increment)?;
+            self.emit(OpCode::Pop, span);
+        }
+
+        self.emit_loop(loop_start, span)?;
+
+        self.patch_jump(exit_jump, span)?;
+        self.emit(OpCode::Pop, span);
+
+        // A `break` skips over the pop of the condition, since it didn't
+        // leave one on the stack
+        for jump in lp.breaks {
+            self.patch_jump(jump, span)?;
+        }
+
+        Ok(())
+    }
+
+    /// Jump out of the innermost loop, either to its end or to its increment
+    /// clause.
+    fn loop_control(&mut self, keyword: &Token) -> CompileResult {
+        let depth = self.current().loops.last().unwrap().depth;
+        self.discard_locals(depth, keyword.span);
+
+        let jump = self.emit_jump(OpCode::Jump, keyword.span);
+        let lp = self.current().loops.last_mut().unwrap();
+
+        if keyword.token_type == TokenType::Break {
+            lp.breaks.push(jump);
+        } else {
+            lp.continues.push(jump);
+        }
+
+        Ok(())
+    }
+
+    fn class_declaration(&mut self, name: &Token, superclass: Option<&Expr>, methods: &[Stmt]) -> CompileResult {
+        let class_name = self.identifier_constant(name)?;
+        self.declare_local(name)?;
+
+        self.emit_with_u16(OpCode::Class, class_name, name.span);
+        self.define_variable(name)?;
+
+        // Methods of a subclass close over an extra scope that holds the
+        // superclass, so `super` can be resolved like any other local.
+        if let Some(superclass) = superclass {
+            let Expr::Variable { name: super_name, .. } = superclass else { unreachable!() };
+
+            self.visit(superclass)?;
+            self.begin_scope();
+            self.current().locals.push(Local { name: Some(Symbol::SUPER), depth: None, is_captured: false });
+            self.mark_initialized();
+
+            self.named_variable(name, false)?;
+            self.emit(OpCode::Inherit, super_name.span);
+        }
+
+        // Keep the class on the stack while its methods get attached to it
+        self.named_variable(name, false)?;
+
+        for method in methods {
+            let Stmt::Fun { name, params, body, .. } = method else { unreachable!() };
+
+            let kind = if name.name() == Symbol::INIT {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+
+            self.function(Some(name), params, body, kind, name.span)?;
+
+            let method_name = self.identifier_constant(name)?;
+            self.emit_with_u16(OpCode::Method, method_name, name.span);
+        }
+
+        self.emit(OpCode::Pop, name.span);
+
+        if superclass.is_some() {
+            self.end_scope(name.span);
+        }
+
+        Ok(())
+    }
+}
+
+impl Visitor<&Stmt> for Compiler {
+    type Output = CompileResult;
+
+    fn visit(&mut self, statement: &Stmt) -> CompileResult {
+        match statement {
+            Stmt::Expression { expr } => {
+                self.visit(expr)?;
+                self.emit(OpCode::Pop, expr.span());
+            },
+
+            Stmt::Print { expr } => {
+                self.visit(expr)?;
+                self.emit(OpCode::Print, expr.span());
+            },
+
+            Stmt::Var { name, initializer, .. } => {
+                self.declare_local(name)?;
+
+                if let Some(initializer) = initializer {
+                    self.visit(initializer)?;
+                } else {
+                    self.emit(OpCode::Nil, name.span);
+                }
+
+                self.define_variable(name)?;
+            },
+
+            Stmt::Block { statements } => {
+                self.begin_scope();
+
+                for statement in statements {
+                    self.visit(statement)?;
+                }
+
+                let span = self.chunk().spans.last().copied().unwrap_or_default();
+                self.end_scope(span);
+            },
+
+            Stmt::If { condition, then_branch, else_branch } => {
+                let span = condition.span();
+                let then_slot = self.reserve_slot(then_branch)?;
+
+                let else_slot = match else_branch {
+                    Some(else_branch) => self.reserve_slot(else_branch)?,
+                    None => None,
+                };
+
+                self.visit(condition)?;
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse, span);
+                self.emit(OpCode::Pop, span);
+                self.body(then_branch, then_slot)?;
+
+                let else_jump = self.emit_jump(OpCode::Jump, span);
+                self.patch_jump(then_jump, span)?;
+                self.emit(OpCode::Pop, span);
+
+                if let Some(else_branch) = else_branch {
+                    self.body(else_branch, else_slot)?;
+                }
+
+                self.patch_jump(else_jump, span)?;
+            },
+
+            Stmt::While { condition, body, increment } => {
+                self.while_statement(condition, body, increment.as_ref())?;
+            },
+
+            Stmt::Break { keyword } | Stmt::Continue { keyword } => self.loop_control(keyword)?,
+
+            Stmt::Fun { name, params, body, .. } => {
+                // Mark the function as initialized right away, so it can
+                // refer to itself recursively
+                self.declare_local(name)?;
+                self.mark_initialized();
+
+                self.function(Some(name), params, body, FunctionKind::Function, name.span)?;
+                self.define_variable(name)?;
+            },
+
+            Stmt::Return { keyword, expr: Some(expr) } => {
+                self.visit(expr)?;
+                self.emit(OpCode::Return, keyword.span);
+            },
+
+            Stmt::Return { keyword, expr: None } => self.emit_implicit_return(keyword.span),
+
+            Stmt::Class { name, superclass, methods, .. } => {
+                self.class_declaration(name, superclass.as_ref(), methods)?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+impl Visitor<&Expr> for Compiler {
+    type Output = CompileResult;
+
+    fn visit(&mut self, expr: &Expr) -> CompileResult {
+        match expr {
+            Expr::Literal { token, value } => {
+                let span = token.span;
+
+                match value {
+                    Literal::Nil => self.emit(OpCode::Nil, span),
+                    Literal::Bool(true) => self.emit(OpCode::True, span),
+                    Literal::Bool(false) => self.emit(OpCode::False, span),
+                    Literal::Num(num) => self.emit_constant(Constant::Num(*num), span)?,
+                    Literal::Str(string) => self.emit_constant(Constant::Str(*string), span)?,
+                }
+            },
+
+            Expr::Grouping { expr } => self.visit(expr.as_ref())?,
+
+            Expr::Unary { op, right } => {
+                self.visit(right.as_ref())?;
+
+                match op.token_type {
+                    TokenType::Bang => self.emit(OpCode::Not, op.span),
+                    TokenType::Minus => self.emit(OpCode::Negate, op.span),
+                    _ => unreachable!(),
+                }
+            },
+
+            Expr::Binary { op, left, right } => {
+                self.visit(left.as_ref())?;
+                self.visit(right.as_ref())?;
+
+                let op_code = match op.token_type {
+                    TokenType::Plus => OpCode::Add,
+                    TokenType::Minus => OpCode::Subtract,
+                    TokenType::Star => OpCode::Multiply,
+                    TokenType::Slash => OpCode::Divide,
+                    TokenType::Greater => OpCode::Greater,
+                    TokenType::GreaterEqual => OpCode::GreaterEqual,
+                    TokenType::Less => OpCode::Less,
+                    TokenType::LessEqual => OpCode::LessEqual,
+                    TokenType::EqualEqual => OpCode::Equal,
+                    TokenType::BangEqual => {
+                        self.emit(OpCode::Equal, op.span);
+                        OpCode::Not
+                    },
+                    _ => unreachable!(),
+                };
+
+                self.emit(op_code, op.span);
+            },
+
+            Expr::Logical { op, left, right } => {
+                self.visit(left.as_ref())?;
+
+                if op.token_type == TokenType::Or {
+                    // Skip the right-hand side if the left-hand side is truthy
+                    let else_jump = self.emit_jump(OpCode::JumpIfFalse, op.span);
+                    let end_jump = self.emit_jump(OpCode::Jump, op.span);
+                    self.patch_jump(else_jump, op.span)?;
+                    self.emit(OpCode::Pop, op.span);
+                    self.visit(right.as_ref())?;
+                    self.patch_jump(end_jump, op.span)?;
+                } else {
+                    let end_jump = self.emit_jump(OpCode::JumpIfFalse, op.span);
+                    self.emit(OpCode::Pop, op.span);
+                    self.visit(right.as_ref())?;
+                    self.patch_jump(end_jump, op.span)?;
+                }
+            },
+
+            Expr::Variable { name, .. } => self.named_variable(name, false)?,
+
+            Expr::Assignment { name, value, .. } => {
+                self.visit(value.as_ref())?;
+                self.named_variable(name, true)?;
+            },
+
+            Expr::Get { object, name } => {
+                self.visit(object.as_ref())?;
+                let name_constant = self.identifier_constant(name)?;
+                self.emit_with_u16(OpCode::GetProperty, name_constant, name.span);
+            },
+
+            Expr::Set { name, object, value } => {
+                self.visit(object.as_ref())?;
+                self.visit(value.as_ref())?;
+                let name_constant = self.identifier_constant(name)?;
+                self.emit_with_u16(OpCode::SetProperty, name_constant, name.span);
+            },
+
+            Expr::This { keyword, .. } => self.named_variable(keyword, false)?,
+
+            Expr::Super { keyword, method, .. } => {
+                let this = Token { symbol: Some(Symbol::THIS), ..keyword.clone() };
+                self.named_variable(&this, false)?;
+                self.named_variable(keyword, false)?;
+
+                let method_name = self.identifier_constant(method)?;
+                self.emit_with_u16(OpCode::GetSuper, method_name, method.span);
+            },
+
+            Expr::Call { callee, paren, arguments } => {
+                self.visit(callee.as_ref())?;
+
+                for arg in arguments {
+                    self.visit(arg)?;
+                }
+
+                // The parser already limits the number of arguments to 255
+                self.emit(OpCode::Call, paren.span);
+                self.emit_byte(arguments.len() as u8, paren.span);
+            },
+
+            Expr::Lambda { keyword, params, body } => {
+                self.function(None, params, body, FunctionKind::Function, keyword.span)?;
+            },
+
+            Expr::List { bracket, elements } => {
+                for element in elements {
+                    self.visit(element)?;
+                }
+
+                self.emit_with_count(OpCode::List, elements.len(), bracket.span)?;
+            },
+
+            Expr::Map { brace, entries } => {
+                for (key, value) in entries {
+                    self.visit(key)?;
+                    self.visit(value)?;
+                }
+
+                self.emit_with_count(OpCode::Map, entries.len(), brace.span)?;
+            },
+
+            Expr::Index { object, bracket, index } => {
+                self.visit(object.as_ref())?;
+                self.visit(index.as_ref())?;
+                self.emit(OpCode::GetIndex, bracket.span);
+            },
+
+            Expr::SetIndex { object, bracket, index, value } => {
+                self.visit(object.as_ref())?;
+                self.visit(index.as_ref())?;
+                self.visit(value.as_ref())?;
+                self.emit(OpCode::SetIndex, bracket.span);
+            },
+
+            Expr::Interpolation { token, parts } => {
+                for part in parts {
+                    self.visit(part)?;
+                }
+
+                self.emit_with_count(OpCode::Interpolate, parts.len(), token.span)?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CompileError {
+    TooManyConstants,
+    TooManyLocals,
+    TooManyUpvalues,
+    TooManyElements,
+    JumpTooLarge,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::TooManyConstants => write!(f, "Too many constants in one function"),
+            CompileError::TooManyLocals => write!(f, "Too many local variables in one function"),
+            CompileError::TooManyUpvalues => write!(f, "Too many captured variables in one function"),
+            CompileError::TooManyElements => write!(f, "Too many elements in one literal"),
+            CompileError::JumpTooLarge => write!(f, "Too much code to jump over"),
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Human-readable listings of compiled bytecode.
+
+use std::fmt::Write;
+
+use super::chunk::{Chunk, Constant, Function, OpCode};
+use crate::sourcemap::Source;
+
+/// Disassemble a function, followed by every function nested inside of it.
+///
+/// Every instruction is listed with its offset, the source line it was
+/// compiled from (or `|` when it's the same line as the previous
+/// instruction), its name and its operands.
+pub fn disassemble(function: &Function, source: &Source) -> String {
+    let mut out = String::new();
+    disassemble_function(function, "<script>", source, &mut out);
+    out
+}
+
+fn disassemble_function(function: &Function, fallback_name: &str, source: &Source, out: &mut String) {
+    let name = match &function.name {
+        Some(name) => name.as_str(),
+        None => fallback_name,
+    };
+
+    writeln!(out, "== {name} ==").unwrap();
+
+    let chunk = &function.chunk;
+    let mut offset = 0;
+
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(chunk, offset, source, out);
+    }
+
+    for constant in &chunk.constants {
+        if let Constant::Function(function) = constant {
+            writeln!(out).unwrap();
+            disassemble_function(function, "<anonymous>", source, out);
+        }
+    }
+}
+
+/// Disassemble the instruction at `offset` into `out`, and return the offset
+/// of the next instruction.
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize, source: &Source, out: &mut String) -> usize {
+    write!(out, "{offset:04} ").unwrap();
+
+    let (line, _, _) = source.map_span(chunk.spans[offset]);
+    let same_line = offset > 0 && source.map_span(chunk.spans[offset - 1]).0 == line;
+
+    if same_line {
+        write!(out, "   | ").unwrap();
+    } else {
+        write!(out, "{line:4} ").unwrap();
+    }
+
+    let byte = chunk.code[offset];
+
+    let Ok(op) = OpCode::try_from(byte) else {
+        writeln!(out, "<invalid opcode {byte}>").unwrap();
+        return offset + 1;
+    };
+
+    let name = format!("{op:?}");
+
+    match op {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::GetSuper
+        | OpCode::Class
+        | OpCode::Method => {
+            let idx = chunk.read_u16(offset + 1);
+            writeln!(out, "{name:<16} {idx:4} {}", chunk.constants[idx as usize]).unwrap();
+            offset + 3
+        },
+
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call => {
+            let operand = chunk.code[offset + 1];
+            writeln!(out, "{name:<16} {operand:4}").unwrap();
+            offset + 2
+        },
+
+        OpCode::List | OpCode::Map | OpCode::Interpolate => {
+            let count = chunk.read_u16(offset + 1);
+            writeln!(out, "{name:<16} {count:4}").unwrap();
+            offset + 3
+        },
+
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            let jump = chunk.read_u16(offset + 1) as usize;
+
+            let target = if op == OpCode::Loop {
+                (offset + 3).wrapping_sub(jump)
+            } else {
+                offset + 3 + jump
+            };
+
+            writeln!(out, "{name:<16} {offset:4} -> {target}").unwrap();
+            offset + 3
+        },
+
+        OpCode::Closure => {
+            let idx = chunk.read_u16(offset + 1);
+            let constant = &chunk.constants[idx as usize];
+            writeln!(out, "{name:<16} {idx:4} {constant}").unwrap();
+
+            let Constant::Function(function) = constant else {
+                return offset + 3;
+            };
+
+            let mut offset = offset + 3;
+
+            for _ in 0..function.upvalue_count {
+                let kind = if chunk.code[offset] == 1 { "local" } else { "upvalue" };
+                let index = chunk.code[offset + 1];
+                writeln!(out, "{offset:04}    |                       {kind} {index}").unwrap();
+                offset += 2;
+            }
+
+            offset
+        },
+
+        OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::Pop
+        | OpCode::GetIndex
+        | OpCode::SetIndex
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::GreaterEqual
+        | OpCode::Less
+        | OpCode::LessEqual
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Not
+        | OpCode::Negate
+        | OpCode::Print
+        | OpCode::CloseUpvalue
+        | OpCode::Return
+        | OpCode::Inherit => {
+            writeln!(out, "{name}").unwrap();
+            offset + 1
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::Span;
+    use crate::symbol::Symbol;
+
+    #[test]
+    fn listing() {
+        let source = Source::new("print 1;\nwhile (x) {}");
+        let print = Span { offset: 0, len: 5 };
+        let cond = Span { offset: 16, len: 1 };
+
+        let mut chunk = Chunk::default();
+        let one = chunk.add_constant(Constant::Num(1.0)) as u16;
+        let x = chunk.add_constant(Constant::Str(Symbol::intern("x"))) as u16;
+
+        chunk.write_op(OpCode::Constant, print);
+        chunk.write_u16(one, print);
+        chunk.write_op(OpCode::Print, print);
+        chunk.write_op(OpCode::GetGlobal, cond);
+        chunk.write_u16(x, cond);
+        chunk.write_op(OpCode::JumpIfFalse, cond);
+        chunk.write_u16(4, cond);
+        chunk.write_op(OpCode::Pop, cond);
+        chunk.write_op(OpCode::Loop, cond);
+        chunk.write_u16(10, cond);
+
+        let function = Function { chunk, ..Function::default() };
+
+        assert_eq!(disassemble(&function, &source), "\
+== <script> ==
+0000    1 Constant            0 1
+0003    | Print
+0004    2 GetGlobal           1 \"x\"
+0007    | JumpIfFalse         7 -> 14
+0010    | Pop
+0011    | Loop               11 -> 4
+");
+    }
+}
@@ -0,0 +1,113 @@
+//! The built-in methods on lists and maps. Global native functions, like
+//! `clock`, are shared with the tree-walker.
+
+use std::cell::RefCell;
+
+use super::value::{HashKey, List, Native, Value};
+use crate::gc::Heap;
+use crate::interpreter::RuntimeError;
+
+static LIST_METHODS: [Native; 5] = [
+    Native { name: "push", arity: 1, fun: list_push },
+    Native { name: "pop", arity: 0, fun: list_pop },
+    Native { name: "len", arity: 0, fun: list_len },
+    Native { name: "insert", arity: 2, fun: list_insert },
+    Native { name: "remove", arity: 1, fun: list_remove },
+];
+
+static MAP_METHODS: [Native; 5] = [
+    Native { name: "keys", arity: 0, fun: map_keys },
+    Native { name: "values", arity: 0, fun: map_values },
+    Native { name: "has", arity: 1, fun: map_has },
+    Native { name: "remove", arity: 1, fun: map_remove },
+    Native { name: "len", arity: 0, fun: map_len },
+];
+
+pub fn list_method(name: &str) -> Option<&'static Native> {
+    LIST_METHODS.iter().find(|method| method.name == name)
+}
+
+pub fn map_method(name: &str) -> Option<&'static Native> {
+    MAP_METHODS.iter().find(|method| method.name == name)
+}
+
+/// Check that a value is a whole number that is less than `max`, where `len`
+/// is the length of the list that is reported on errors.
+pub fn checked_position(index: &Value, len: usize, max: usize) -> Result<usize, RuntimeError> {
+    let Value::Num(num) = *index else {
+        return Err(RuntimeError::TypeError("number"));
+    };
+
+    if num.fract() != 0.0 {
+        return Err(RuntimeError::NonIntegerIndex(num));
+    }
+
+    if num < 0.0 || num as usize >= max {
+        return Err(RuntimeError::IndexOutOfBounds(num, len));
+    }
+
+    Ok(num as usize)
+}
+
+fn list_push(_heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Value::List(list) = &args[0] else { unreachable!() };
+    list.0.borrow_mut().push(args[1].clone());
+    Ok(Value::Nil)
+}
+
+fn list_pop(_heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Value::List(list) = &args[0] else { unreachable!() };
+    list.0.borrow_mut().pop().ok_or(RuntimeError::EmptyList)
+}
+
+fn list_len(_heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Value::List(list) = &args[0] else { unreachable!() };
+    Ok(Value::Num(list.0.borrow().len() as f64))
+}
+
+fn list_insert(_heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Value::List(list) = &args[0] else { unreachable!() };
+    let len = list.0.borrow().len();
+
+    // Inserting right after the last element is allowed
+    let idx = checked_position(&args[1], len, len + 1)?;
+    list.0.borrow_mut().insert(idx, args[2].clone());
+    Ok(Value::Nil)
+}
+
+fn list_remove(_heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Value::List(list) = &args[0] else { unreachable!() };
+    let len = list.0.borrow().len();
+
+    let idx = checked_position(&args[1], len, len)?;
+    Ok(list.0.borrow_mut().remove(idx))
+}
+
+fn map_keys(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Value::Map(map) = &args[0] else { unreachable!() };
+    let keys = map.0.borrow().iter().map(|(key, _)| key.0.clone()).collect();
+    Ok(Value::List(List(heap.alloc(RefCell::new(keys)))))
+}
+
+fn map_values(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Value::Map(map) = &args[0] else { unreachable!() };
+    let values = map.0.borrow().iter().map(|(_, value)| value.clone()).collect();
+    Ok(Value::List(List(heap.alloc(RefCell::new(values)))))
+}
+
+fn map_has(_heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Value::Map(map) = &args[0] else { unreachable!() };
+    let key = HashKey::new(args[1].clone())?;
+    Ok(Value::Bool(map.0.borrow().get(&key).is_some()))
+}
+
+fn map_remove(_heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Value::Map(map) = &args[0] else { unreachable!() };
+    let key = HashKey::new(args[1].clone())?;
+    Ok(map.0.borrow_mut().remove(&key).unwrap_or(Value::Nil))
+}
+
+fn map_len(_heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Value::Map(map) = &args[0] else { unreachable!() };
+    Ok(Value::Num(map.0.borrow().len() as f64))
+}
@@ -0,0 +1,805 @@
+//! A binary file format for compiled programs, so scripts can be compiled once
+//! and then run without scanning, parsing and compiling them again.
+//!
+//! All numbers are little-endian, and strings are stored as a `u32` length
+//! followed by UTF-8 bytes. A file starts with a header:
+//!
+//! ```text
+//! magic        b"LOXC"
+//! version      u16
+//! length       u32, the number of bytes after the header
+//! checksum     u32, the FNV-1a hash of everything after the header
+//! line count   u32, the number of source lines the line tables refer to
+//! prototypes   u32 count, followed by the function prototypes
+//! ```
+//!
+//! Every function prototype is laid out as:
+//!
+//! ```text
+//! name         u8 (0 for anonymous functions and the script, 1 otherwise),
+//!              followed by the name if there is one
+//! arity        u8
+//! upvalues     u16
+//! constants    u32 count, followed by the constants, each tagged with a u8:
+//!              0 for a number (f64), 1 for a string, and 2 for a function
+//!              (u32 index of an earlier prototype)
+//! code         u32 length, followed by the bytecode
+//! line table   u32 count, followed by (u32 line, u32 length) pairs that
+//!              assign a source line to every byte of code, in order
+//! ```
+//!
+//! Functions only ever refer to prototypes that come before them, and the
+//! last prototype is the top-level script.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::rc::Rc;
+
+use super::chunk::{Chunk, Constant, Function, OpCode};
+use crate::sourcemap::Source;
+use crate::span::Span;
+use crate::symbol::Symbol;
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const FORMAT_VERSION: u16 = 1;
+
+/// The size of the magic bytes, version, length and checksum.
+const HEADER_LEN: usize = 14;
+
+const TAG_NUM: u8 = 0;
+const TAG_STR: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+/// Check whether a file looks like compiled bytecode rather than source code.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Serialize a compiled script, using `source` to look up the line numbers
+/// of its instructions.
+pub fn encode(script: &Function, source: &Source) -> Vec<u8> {
+    let mut encoder = Encoder { source, line_count: 1, prototypes: Vec::new() };
+    encoder.prototype(script);
+
+    let mut body = Vec::new();
+    put_u32(&mut body, encoder.line_count);
+    put_u32(&mut body, encoder.prototypes.len() as u32);
+
+    for prototype in &encoder.prototypes {
+        body.extend_from_slice(prototype);
+    }
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+    bytes.extend_from_slice(MAGIC);
+    put_u16(&mut bytes, FORMAT_VERSION);
+    put_u32(&mut bytes, body.len() as u32);
+    put_u32(&mut bytes, checksum(&body));
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+struct Encoder<'a> {
+    source: &'a Source,
+    line_count: u32,
+
+    /// The encoded prototypes, in the order they appear in the file
+    prototypes: Vec<Vec<u8>>,
+}
+
+impl Encoder<'_> {
+    /// Encode a function after all of the functions nested inside of it, and
+    /// return its index in the prototype table.
+    fn prototype(&mut self, function: &Function) -> u32 {
+        let chunk = &function.chunk;
+        let mut bytes = Vec::new();
+
+        match &function.name {
+            Some(name) => {
+                bytes.push(1);
+                put_str(&mut bytes, name);
+            },
+            None => bytes.push(0),
+        }
+
+        bytes.push(function.arity as u8);
+        put_u16(&mut bytes, function.upvalue_count as u16);
+
+        put_u32(&mut bytes, chunk.constants.len() as u32);
+
+        for constant in &chunk.constants {
+            match constant {
+                Constant::Num(num) => {
+                    bytes.push(TAG_NUM);
+                    bytes.extend_from_slice(&num.to_le_bytes());
+                },
+                Constant::Str(string) => {
+                    bytes.push(TAG_STR);
+                    put_str(&mut bytes, string.as_str());
+                },
+                Constant::Function(function) => {
+                    let idx = self.prototype(function);
+                    bytes.push(TAG_FUNCTION);
+                    put_u32(&mut bytes, idx);
+                },
+            }
+        }
+
+        put_u32(&mut bytes, chunk.code.len() as u32);
+        bytes.extend_from_slice(&chunk.code);
+
+        // Run-length encode the line of every byte
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+
+        for &span in &chunk.spans {
+            let (line, _, _) = self.source.map_span(span);
+            let line = line as u32;
+            self.line_count = self.line_count.max(line);
+
+            match runs.last_mut() {
+                Some((last, len)) if *last == line => *len += 1,
+                _ => runs.push((line, 1)),
+            }
+        }
+
+        put_u32(&mut bytes, runs.len() as u32);
+
+        for (line, len) in runs {
+            put_u32(&mut bytes, line);
+            put_u32(&mut bytes, len);
+        }
+
+        self.prototypes.push(bytes);
+        self.prototypes.len() as u32 - 1
+    }
+}
+
+fn put_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_str(bytes: &mut Vec<u8>, string: &str) {
+    put_u32(bytes, string.len() as u32);
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+/// 32-bit FNV-1a
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
+/// Load a compiled script.
+///
+/// The file doesn't contain the source code, so blank lines get appended to
+/// `source` instead, and the spans of the loaded code point at those. That
+/// way, errors and disassembly still report the right line numbers.
+pub fn decode(bytes: &[u8], source: &mut Source) -> Result<Function, LoadError> {
+    if !is_bytecode(bytes) {
+        return Err(LoadError::NotBytecode);
+    }
+
+    let mut header = Reader { bytes: &bytes[MAGIC.len()..] };
+    let version = header.u16()?;
+
+    if version != FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let len = header.u32()? as usize;
+    let expected = header.u32()?;
+    let body = header.bytes;
+
+    if body.len() < len {
+        return Err(LoadError::Truncated);
+    }
+
+    if body.len() > len {
+        return Err(LoadError::Invalid("unexpected data after the end of the file".to_owned()));
+    }
+
+    if checksum(body) != expected {
+        return Err(LoadError::ChecksumMismatch);
+    }
+
+    let mut reader = Reader { bytes: body };
+    let line_count = reader.u32()?;
+
+    if line_count == 0 {
+        return Err(LoadError::Invalid("line count is zero".to_owned()));
+    }
+
+    let start = source.push(&"\n".repeat(line_count as usize - 1));
+    let decoder = Decoder { start, line_count };
+
+    let count = reader.u32()?;
+    let mut prototypes: Vec<Rc<Function>> = Vec::new();
+
+    for idx in 0..count {
+        let function = decoder.prototype(&mut reader, &prototypes)
+            .map_err(|err| err.in_prototype(idx))?;
+
+        prototypes.push(Rc::new(function));
+    }
+
+    if !reader.bytes.is_empty() {
+        return Err(LoadError::Invalid("unexpected data after the last prototype".to_owned()));
+    }
+
+    let Some(script) = prototypes.pop() else {
+        return Err(LoadError::Invalid("file contains no functions".to_owned()));
+    };
+
+    if script.arity != 0 || script.upvalue_count != 0 {
+        return Err(LoadError::Invalid("the script can't take arguments or capture variables".to_owned()));
+    }
+
+    // Nothing refers to the script, so this never actually clones
+    Ok(Rc::try_unwrap(script).unwrap_or_else(|script| (*script).clone()))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if self.bytes.len() < len {
+            return Err(LoadError::Truncated);
+        }
+
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, LoadError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| LoadError::Invalid("string is not valid UTF-8".to_owned()))
+    }
+}
+
+struct Decoder {
+    /// The offset of the blank lines the spans of the loaded code point into
+    start: usize,
+    line_count: u32,
+}
+
+impl Decoder {
+    fn prototype(&self, reader: &mut Reader, prototypes: &[Rc<Function>]) -> Result<Function, LoadError> {
+        let name = match reader.u8()? {
+            0 => None,
+            1 => Some(Rc::new(reader.string()?)),
+            flag => return Err(LoadError::Invalid(format!("invalid name flag {flag}"))),
+        };
+
+        let arity = reader.u8()? as usize;
+        let upvalue_count = reader.u16()? as usize;
+
+        let mut chunk = Chunk::default();
+        let constant_count = reader.u32()?;
+
+        for _ in 0..constant_count {
+            let constant = match reader.u8()? {
+                TAG_NUM => Constant::Num(reader.f64()?),
+                TAG_STR => Constant::Str(Symbol::intern(&reader.string()?)),
+                TAG_FUNCTION => {
+                    let idx = reader.u32()? as usize;
+
+                    let Some(function) = prototypes.get(idx) else {
+                        return Err(LoadError::Invalid(format!("reference to unknown prototype {idx}")));
+                    };
+
+                    Constant::Function(function.clone())
+                },
+                tag => return Err(LoadError::Invalid(format!("invalid constant tag {tag}"))),
+            };
+
+            chunk.constants.push(constant);
+        }
+
+        let code_len = reader.u32()? as usize;
+        chunk.code = reader.take(code_len)?.to_vec();
+
+        let run_count = reader.u32()?;
+
+        for _ in 0..run_count {
+            let line = reader.u32()?;
+            let len = reader.u32()? as usize;
+
+            if line == 0 || line > self.line_count {
+                return Err(LoadError::Invalid(format!("line {line} is out of range")));
+            }
+
+            if chunk.spans.len() + len > code_len {
+                return Err(LoadError::Invalid("line table is longer than the code".to_owned()));
+            }
+
+            let span = Span::new_at(self.start + line as usize - 1);
+            chunk.spans.extend(std::iter::repeat_n(span, len));
+        }
+
+        if chunk.spans.len() != code_len {
+            return Err(LoadError::Invalid("line table doesn't cover all of the code".to_owned()));
+        }
+
+        let function = Function { name, arity, upvalue_count, chunk };
+        verify(&function)?;
+        Ok(function)
+    }
+}
+
+/// Check that a function's bytecode is well-formed: every instruction is
+/// valid, its operands refer to constants of the right kind and upvalues that
+/// exist, every jump lands on an instruction, execution can't run off the end
+/// of the code, and the stack stays in bounds (see `verify_stack`).
+fn verify(function: &Function) -> Result<(), LoadError> {
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+    let invalid = |offset: usize, message: &str| {
+        Err(LoadError::Invalid(format!("{message} at offset {offset}")))
+    };
+
+    let mut starts = HashSet::new();
+    let mut jumps = Vec::new();
+    let mut offset = 0;
+    let mut last = None;
+
+    while offset < code.len() {
+        starts.insert(offset);
+
+        let Ok(op) = OpCode::try_from(code[offset]) else {
+            return invalid(offset, "invalid opcode");
+        };
+
+        let operand_len = operand_len(op);
+
+        if offset + operand_len >= code.len() {
+            return invalid(offset, "truncated instruction");
+        }
+
+        let constant = || chunk.constants.get(chunk.read_u16(offset + 1) as usize);
+
+        match op {
+            OpCode::Constant
+                if !matches!(constant(), Some(Constant::Num(_) | Constant::Str(_))) => {
+                return invalid(offset, "expected a number or string constant");
+            },
+
+            OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method
+                if !matches!(constant(), Some(Constant::Str(_))) => {
+                return invalid(offset, "expected a name constant");
+            },
+
+            OpCode::GetUpvalue | OpCode::SetUpvalue
+                if code[offset + 1] as usize >= function.upvalue_count => {
+                return invalid(offset, "reference to unknown upvalue");
+            },
+
+            OpCode::Jump | OpCode::JumpIfFalse => {
+                jumps.push((offset, (offset + 3).checked_add(chunk.read_u16(offset + 1) as usize)));
+            },
+
+            OpCode::Loop => {
+                jumps.push((offset, (offset + 3).checked_sub(chunk.read_u16(offset + 1) as usize)));
+            },
+
+            OpCode::Closure => {
+                let Some(Constant::Function(closure)) = constant() else {
+                    return invalid(offset, "expected a function constant");
+                };
+
+                let captures = 2 * closure.upvalue_count;
+
+                if offset + 3 + captures > code.len() {
+                    return invalid(offset, "truncated instruction");
+                }
+
+                for capture in code[offset + 3..offset + 3 + captures].chunks(2) {
+                    let valid = match capture[0] {
+                        1 => true,
+                        0 => (capture[1] as usize) < function.upvalue_count,
+                        _ => false,
+                    };
+
+                    if !valid {
+                        return invalid(offset, "invalid upvalue capture");
+                    }
+                }
+
+                offset += captures;
+            },
+
+            _ => {},
+        }
+
+        last = Some(op);
+        offset += 1 + operand_len;
+    }
+
+    for (offset, target) in jumps {
+        if !target.is_some_and(|target| starts.contains(&target)) {
+            return invalid(offset, "jump to the middle of an instruction");
+        }
+    }
+
+    // Every path through a function ends in a return, so the VM never reads
+    // past the end of the code
+    if last != Some(OpCode::Return) {
+        return invalid(code.len(), "missing return");
+    }
+
+    verify_stack(function)
+}
+
+/// The number of bytes of operands that follow an opcode, not counting the
+/// upvalue captures of a `Closure`.
+fn operand_len(op: OpCode) -> usize {
+    match op {
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call => 1,
+
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::GetSuper
+        | OpCode::Class
+        | OpCode::Method
+        | OpCode::Jump
+        | OpCode::JumpIfFalse
+        | OpCode::Loop
+        | OpCode::Closure
+        | OpCode::List
+        | OpCode::Map
+        | OpCode::Interpolate => 2,
+
+        _ => 0,
+    }
+}
+
+/// Follow every path through a function, keeping track of how many values
+/// it has on the stack, to check that no instruction takes more values off
+/// the stack than there are, and that locals (including the ones captured by
+/// closures) are below the top of the stack. Wherever paths join, like at the
+/// start of a loop, the stack has to be just as high on all of them.
+///
+/// Assumes the rest of `verify` already checked that every instruction and
+/// jump target is valid. The types of the values aren't known up front, so
+/// those still get checked when the code runs.
+fn verify_stack(function: &Function) -> Result<(), LoadError> {
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+    let invalid = |offset: usize, message: &str| {
+        Err(LoadError::Invalid(format!("{message} at offset {offset}")))
+    };
+
+    // A call starts out with the function and its arguments on the stack
+    let mut heights = HashMap::from([(0, 1 + function.arity)]);
+    let mut pending = vec![0];
+
+    while let Some(offset) = pending.pop() {
+        let height = heights[&offset];
+        let Ok(op) = OpCode::try_from(code[offset]) else { unreachable!("opcodes were checked") };
+
+        let byte = || code[offset + 1] as usize;
+        let count = || chunk.read_u16(offset + 1) as usize;
+        let mut next = offset + 1 + operand_len(op);
+
+        // How many values the instruction looks at, and how many it leaves
+        // in their place
+        let (pops, pushes) = match op {
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetGlobal
+            | OpCode::GetUpvalue
+            | OpCode::Closure
+            | OpCode::Class => (0, 1),
+
+            OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return => (1, 0),
+
+            OpCode::SetLocal
+            | OpCode::SetGlobal
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::JumpIfFalse
+            | OpCode::Not
+            | OpCode::Negate => (1, 1),
+
+            OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::GetIndex
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Inherit
+            | OpCode::Method => (2, 1),
+
+            OpCode::SetIndex => (3, 1),
+            OpCode::Jump | OpCode::Loop => (0, 0),
+            OpCode::Call => (byte() + 1, 1),
+            OpCode::List | OpCode::Interpolate => (count(), 1),
+            OpCode::Map => (2 * count(), 1),
+        };
+
+        if height < pops {
+            return invalid(offset, "stack underflow");
+        }
+
+        match op {
+            OpCode::GetLocal | OpCode::SetLocal if byte() >= height => {
+                return invalid(offset, "reference to a local above the top of the stack");
+            },
+
+            OpCode::Closure => {
+                let Some(Constant::Function(closure)) = chunk.constants.get(count()) else {
+                    unreachable!("closure constants were checked");
+                };
+
+                for capture in code[next..next + 2 * closure.upvalue_count].chunks(2) {
+                    if capture[0] == 1 && capture[1] as usize >= height {
+                        return invalid(offset, "capture of a local above the top of the stack");
+                    }
+                }
+
+                next += 2 * closure.upvalue_count;
+            },
+
+            _ => {},
+        }
+
+        let height = height - pops + pushes;
+
+        let successors = match op {
+            OpCode::Return => vec![],
+            OpCode::Jump => vec![next + count()],
+            OpCode::JumpIfFalse => vec![next, next + count()],
+            OpCode::Loop => vec![next - count()],
+            _ => vec![next],
+        };
+
+        for successor in successors {
+            match heights.get(&successor) {
+                Some(&expected) if expected != height => {
+                    return invalid(successor, "inconsistent stack height");
+                },
+
+                Some(_) => {},
+
+                None => {
+                    heights.insert(successor, height);
+                    pending.push(successor);
+                },
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    NotBytecode,
+    UnsupportedVersion(u16),
+    Truncated,
+    ChecksumMismatch,
+    Invalid(String),
+    InPrototype(u32, Box<LoadError>),
+
+    /// Bytecode was handed to an engine that walks the syntax tree
+    RequiresVm,
+}
+
+impl LoadError {
+    fn in_prototype(self, idx: u32) -> Self {
+        LoadError::InPrototype(idx, Box::new(self))
+    }
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "Not a Lox bytecode file"),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported bytecode version {version} (expected {FORMAT_VERSION})")
+            },
+            LoadError::Truncated => write!(f, "Bytecode file is truncated"),
+            LoadError::ChecksumMismatch => write!(f, "Bytecode file is corrupted (checksum mismatch)"),
+            LoadError::Invalid(message) => write!(f, "Invalid bytecode: {message}"),
+            LoadError::InPrototype(idx, err) => write!(f, "{err} (in function #{idx})"),
+            LoadError::RequiresVm => write!(f, "Bytecode can only run on the VM backend"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::parser::Parser;
+    use crate::syntax::tokenizer::Scanner;
+    use crate::vm::compiler::Compiler;
+
+    const PROGRAM: &str = "\
+fun counter(step) {
+  var n = 0;
+  return fun() { n = n + step; return \"${n}\"; };
+}
+
+var c = counter(2);
+while (c() != \"6\") {}";
+
+    fn compile(source: &Source) -> Function {
+        let mut scanner = Scanner::new(source);
+        let ast = Parser::new(source, &mut scanner).parse().unwrap();
+        Compiler::new().compile(&ast).ok().unwrap()
+    }
+
+    fn lines(function: &Function, source: &Source) -> Vec<usize> {
+        function.chunk.spans.iter().map(|&span| source.map_span(span).0).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = Source::new(PROGRAM);
+        let script = compile(&source);
+        let bytes = encode(&script, &source);
+
+        let mut loaded_source = Source::default();
+        let loaded = decode(&bytes, &mut loaded_source).unwrap();
+
+        assert_eq!(loaded.chunk.code, script.chunk.code);
+        assert_eq!(lines(&loaded, &loaded_source), lines(&script, &source));
+
+        let Some(Constant::Function(counter)) = loaded.chunk.constants.first() else { panic!() };
+        assert_eq!(counter.name.as_deref().map(String::as_str), Some("counter"));
+        assert_eq!(counter.arity, 1);
+
+        let Some(Constant::Function(inner)) = counter.chunk.constants.get(1) else { panic!() };
+        assert_eq!(inner.upvalue_count, 2);
+        assert_eq!(lines(inner, &loaded_source)[0], 3);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let source = Source::new(PROGRAM);
+        let bytes = encode(&compile(&source), &source);
+
+        for len in MAGIC.len()..bytes.len() {
+            assert_eq!(decode(&bytes[..len], &mut Source::default()).err(), Some(LoadError::Truncated));
+        }
+
+        assert_eq!(decode(&bytes[..2], &mut Source::default()).err(), Some(LoadError::NotBytecode));
+    }
+
+    #[test]
+    fn rejects_corrupted_files() {
+        let source = Source::new(PROGRAM);
+        let bytes = encode(&compile(&source), &source);
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        assert_eq!(decode(&wrong_magic, &mut Source::default()).err(), Some(LoadError::NotBytecode));
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 99;
+        assert_eq!(decode(&wrong_version, &mut Source::default()).err(), Some(LoadError::UnsupportedVersion(99)));
+
+        for idx in HEADER_LEN..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[idx] ^= 0x40;
+            assert_eq!(decode(&corrupted, &mut Source::default()).err(), Some(LoadError::ChecksumMismatch));
+        }
+    }
+
+    #[test]
+    fn verifies_bytecode() {
+        let span = Span::new();
+        let mut chunk = Chunk::default();
+        chunk.write_op(OpCode::Jump, span);
+        chunk.write_u16(1, span);
+        chunk.write_op(OpCode::Nil, span);
+        chunk.write_op(OpCode::Return, span);
+
+        // Jumping over the nil lands on the return
+        let mut function = Function { chunk, ..Function::default() };
+        assert!(verify(&function).is_ok());
+
+        function.chunk.patch_u16(1, 2);
+        assert!(verify(&function).is_err());
+
+        function.chunk.patch_u16(1, 0);
+        function.chunk.code.pop();
+        assert!(verify(&function).is_err());
+
+        function.chunk.code = vec![OpCode::GetUpvalue as u8, 0, OpCode::Return as u8];
+        assert!(verify(&function).is_err());
+
+        function.chunk.code = vec![OpCode::Constant as u8, 0];
+        assert!(verify(&function).is_err());
+    }
+
+    #[test]
+    fn verifies_stack_effects() {
+        let check = |arity: usize, code: &[OpCode], operands: &[u8]| {
+            let mut code: Vec<u8> = code.iter().map(|&op| op as u8).collect();
+            code.extend(operands);
+            code.push(OpCode::Return as u8);
+
+            let chunk = Chunk { code, ..Chunk::default() };
+            verify(&Function { arity, chunk, ..Function::default() })
+        };
+
+        // The function itself is in slot 0, followed by its parameters
+        assert!(check(1, &[OpCode::GetLocal], &[1]).is_ok());
+        assert!(check(1, &[OpCode::GetLocal], &[2]).is_err());
+        assert!(check(0, &[OpCode::Nil, OpCode::SetLocal], &[2]).is_err());
+
+        // Nothing left for the return to pop
+        assert!(check(0, &[OpCode::Pop], &[]).is_err());
+
+        // Not enough values for the list or the arguments
+        assert!(check(0, &[OpCode::Nil, OpCode::Nil, OpCode::List], &[0, 2]).is_ok());
+        assert!(check(0, &[OpCode::Nil, OpCode::List], &[0, 3]).is_err());
+        assert!(check(0, &[OpCode::Nil, OpCode::Map], &[0, 2]).is_err());
+        assert!(check(0, &[OpCode::Nil, OpCode::Interpolate], &[0, 3]).is_err());
+        assert!(check(0, &[OpCode::Nil, OpCode::Call], &[1]).is_ok());
+        assert!(check(0, &[OpCode::Nil, OpCode::Call], &[2]).is_err());
+
+        // A loop that pushes a value every time around
+        let function = Function {
+            chunk: Chunk {
+                code: vec![OpCode::Nil as u8, OpCode::Loop as u8, 0, 4, OpCode::Return as u8],
+                ..Chunk::default()
+            },
+            ..Function::default()
+        };
+
+        assert!(verify(&function).is_err());
+    }
+}
@@ -0,0 +1,575 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+use std::rc::Rc;
+
+use super::chunk::Function;
+use crate::gc::{Heap, Trace, Tracer};
+use crate::interpreter::RuntimeError;
+use crate::interpreter::functions::{Arity, NativeFunction};
+use crate::interpreter::value::LoxValue;
+use crate::interpreter::list;
+use crate::interpreter::map::{self, Entries};
+use crate::span::Span;
+use crate::symbol::{LoxStr, Symbol};
+use crate::util::{drop_nested, CycleGuard, Nested};
+
+/// A value on the VM's stack.
+///
+/// This mirrors `LoxValue`, but functions and classes are backed by compiled
+/// bytecode instead of syntax trees.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Num(f64),
+    Str(LoxStr),
+    Native(Rc<NativeFunction>),
+    BuiltinMethod(Rc<BuiltinMethod>),
+    Closure(Rc<Closure>),
+    BoundMethod(Rc<BoundMethod>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    List(List),
+    Map(Map),
+}
+
+impl Value {
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Nil => false,
+            Value::Bool(b) => *b,
+            _ => true,
+        }
+    }
+
+    /// The number of arguments the value accepts when called, or `None` if
+    /// it can't be called.
+    pub fn arity(&self) -> Option<Arity> {
+        match self {
+            Value::Native(native) => Some(native.arity),
+            Value::BuiltinMethod(builtin) => Some(Arity::Exactly(builtin.method.arity)),
+            Value::Closure(closure) => Some(Arity::Exactly(closure.function.arity)),
+            Value::BoundMethod(bound) => Some(Arity::Exactly(bound.method.function.arity)),
+
+            Value::Class(class) => {
+                let init = class.methods.borrow().get(&Symbol::INIT).map(|init| init.function.arity);
+                Some(Arity::Exactly(init.unwrap_or(0)))
+            },
+
+            _ => None,
+        }
+    }
+
+    /// A human-readable name for the type of the value, for use in error
+    /// messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Num(_) => "number",
+            Value::Str(_) => "string",
+            Value::Native(_)
+            | Value::BuiltinMethod(_)
+            | Value::Closure(_)
+            | Value::BoundMethod(_) => "function",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+        }
+    }
+
+    /// Convert a value that was handed over by Rust code as a tree-walker
+    /// value. Plain data (`nil`, booleans, numbers and strings) looks the same
+    /// on both backends, and lists and maps get copied onto the VM's heap.
+    ///
+    /// Fails with the type name of the first value that can't be converted,
+    /// i.e., a function, class or instance.
+    pub fn from_lox(value: &LoxValue, heap: &mut Heap) -> Result<Value, &'static str> {
+        Self::copy_from_lox(value, heap, &mut HashMap::new())
+    }
+
+    /// Convert a value to a tree-walker value, to hand it over to Rust code.
+    /// Lists and maps get copied. See `from_lox`.
+    pub fn to_lox(&self) -> Result<LoxValue, &'static str> {
+        self.copy_to_lox(&mut HashMap::new())
+    }
+
+    /// Convert a value, reusing the copies of the collections that were
+    /// already converted. That way, collections that show up more than once
+    /// (or contain themselves) get copied once, and stay shared.
+    fn copy_from_lox(
+        value: &LoxValue,
+        heap: &mut Heap,
+        copies: &mut HashMap<*const (), Value>,
+    ) -> Result<Value, &'static str> {
+        match value {
+            LoxValue::Nil => Ok(Value::Nil),
+            LoxValue::Bool(bool) => Ok(Value::Bool(*bool)),
+            LoxValue::Num(num) => Ok(Value::Num(*num)),
+            LoxValue::Str(string) => Ok(Value::Str(string.clone())),
+
+            LoxValue::List(list) => {
+                let ptr = Rc::as_ptr(&list.0) as *const ();
+
+                if let Some(copy) = copies.get(&ptr) {
+                    return Ok(copy.clone());
+                }
+
+                let copy = heap.alloc(RefCell::new(Vec::new()));
+                copies.insert(ptr, Value::List(List(copy.clone())));
+
+                let values = list.0.borrow()
+                    .iter()
+                    .map(|value| Self::copy_from_lox(value, heap, copies))
+                    .collect::<Result<_, _>>()?;
+
+                *copy.borrow_mut() = values;
+                Ok(Value::List(List(copy)))
+            },
+
+            LoxValue::Map(map) => {
+                let ptr = Rc::as_ptr(&map.0) as *const ();
+
+                if let Some(copy) = copies.get(&ptr) {
+                    return Ok(copy.clone());
+                }
+
+                let copy = heap.alloc(RefCell::new(Entries::default()));
+                copies.insert(ptr, Value::Map(Map(copy.clone())));
+
+                for (key, value) in map.0.borrow().iter() {
+                    let Ok(key) = HashKey::new(Self::copy_from_lox(key.value(), heap, copies)?) else {
+                        unreachable!("keys are plain data");
+                    };
+
+                    let value = Self::copy_from_lox(value, heap, copies)?;
+                    copy.borrow_mut().insert(key, value);
+                }
+
+                Ok(Value::Map(Map(copy)))
+            },
+
+            _ => Err(value.type_name()),
+        }
+    }
+
+    fn copy_to_lox(&self, copies: &mut HashMap<*const (), LoxValue>) -> Result<LoxValue, &'static str> {
+        match self {
+            Value::Nil => Ok(LoxValue::Nil),
+            Value::Bool(bool) => Ok(LoxValue::Bool(*bool)),
+            Value::Num(num) => Ok(LoxValue::Num(*num)),
+            Value::Str(string) => Ok(LoxValue::Str(string.clone())),
+
+            Value::List(list) => {
+                let ptr = Rc::as_ptr(&list.0) as *const ();
+
+                if let Some(copy) = copies.get(&ptr) {
+                    return Ok(copy.clone());
+                }
+
+                let copy = list::List(Rc::new(RefCell::new(Vec::new())));
+                copies.insert(ptr, LoxValue::List(copy.clone()));
+
+                let values = list.0.borrow()
+                    .iter()
+                    .map(|value| value.copy_to_lox(copies))
+                    .collect::<Result<_, _>>()?;
+
+                *copy.0.borrow_mut() = values;
+                Ok(LoxValue::List(copy))
+            },
+
+            Value::Map(map) => {
+                let ptr = Rc::as_ptr(&map.0) as *const ();
+
+                if let Some(copy) = copies.get(&ptr) {
+                    return Ok(copy.clone());
+                }
+
+                let copy = map::Map(Rc::new(RefCell::new(Entries::default())));
+                copies.insert(ptr, LoxValue::Map(copy.clone()));
+
+                for (key, value) in map.0.borrow().iter() {
+                    let Ok(key) = map::HashKey::try_from(key.0.copy_to_lox(copies)?) else {
+                        unreachable!("keys are plain data");
+                    };
+
+                    let value = value.copy_to_lox(copies)?;
+                    copy.0.borrow_mut().insert(key, value);
+                }
+
+                Ok(LoxValue::Map(copy))
+            },
+
+            _ => Err(self.type_name()),
+        }
+    }
+
+    /// Report the heap object the value refers to, if any.
+    pub fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Value::BuiltinMethod(val) => tracer.edge(val),
+            Value::Closure(val) => tracer.edge(val),
+            Value::BoundMethod(val) => tracer.edge(val),
+            Value::Class(val) => tracer.edge(val),
+            Value::Instance(val) => tracer.edge(val),
+            Value::List(val) => tracer.edge(&val.0),
+            Value::Map(val) => tracer.edge(&val.0),
+            Value::Nil | Value::Bool(_) | Value::Num(_) | Value::Str(_) | Value::Native(_) => {},
+        }
+    }
+
+    /// Format the value the way it should show up inside of a collection,
+    /// i.e., with strings quoted.
+    pub fn repr(&self) -> String {
+        match self {
+            Value::Str(val) => format!("\"{val}\""),
+            _ => format!("{self}"),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(left), Value::Bool(right)) => left == right,
+            (Value::Num(left), Value::Num(right)) => left == right,
+            (Value::Str(left), Value::Str(right)) => left == right,
+            (Value::Native(left), Value::Native(right)) => Rc::ptr_eq(left, right),
+            (Value::BuiltinMethod(left), Value::BuiltinMethod(right)) => Rc::ptr_eq(left, right),
+            (Value::Closure(left), Value::Closure(right)) => Rc::ptr_eq(left, right),
+            (Value::BoundMethod(left), Value::BoundMethod(right)) => Rc::ptr_eq(left, right),
+            (Value::Class(left), Value::Class(right)) => Rc::ptr_eq(left, right),
+            (Value::Instance(left), Value::Instance(right)) => Rc::ptr_eq(left, right),
+            (Value::List(left), Value::List(right)) => Rc::ptr_eq(&left.0, &right.0),
+            (Value::Map(left), Value::Map(right)) => Rc::ptr_eq(&left.0, &right.0),
+            _ => false,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(val) => write!(f, "{val}"),
+            Value::Num(val) => write!(f, "{val}"),
+            Value::Str(val) => write!(f, "{val}"),
+            Value::Native(native) => write!(f, "<native fn: {}>", native.name),
+            Value::BuiltinMethod(method) => write!(f, "<native fn: {}>", method.method.name),
+            Value::Closure(closure) => write!(f, "{}", closure.function),
+            Value::BoundMethod(bound) => write!(f, "{}", bound.method.function),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "[{}]", instance.class.name),
+            Value::List(list) => {
+                let Ok(_guard) = CycleGuard::enter(Rc::as_ptr(&list.0)) else {
+                    return write!(f, "[...]");
+                };
+
+                write!(f, "[")?;
+
+                for (idx, value) in list.0.borrow().iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+
+                    write!(f, "{}", value.repr())?;
+                }
+
+                write!(f, "]")
+            },
+            Value::Map(map) => {
+                let Ok(_guard) = CycleGuard::enter(Rc::as_ptr(&map.0)) else {
+                    return write!(f, "{{...}}");
+                };
+
+                write!(f, "{{")?;
+
+                for (idx, (key, value)) in map.0.borrow().iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+
+                    write!(f, "{}: {}", key.0.repr(), value.repr())?;
+                }
+
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+/// A value that can be used as a map key. See `interpreter::map::HashKey`.
+#[derive(Debug, Clone)]
+pub struct HashKey(pub Value);
+
+impl HashKey {
+    pub fn new(value: Value) -> Result<Self, RuntimeError> {
+        match value {
+            Value::Nil | Value::Bool(_) | Value::Num(_) => Ok(Self(value)),
+
+            // Keys get interned, so looking them up mostly compares pointers
+            Value::Str(string) => Ok(Self(Value::Str(string.key()))),
+            _ => Err(RuntimeError::UnhashableKey(value.type_name())),
+        }
+    }
+
+    /// Normalize numbers so that keys that compare equal also hash the same:
+    /// `0` and `-0` are the same key, and so are all NaNs.
+    fn num_bits(num: f64) -> u64 {
+        if num == 0.0 {
+            0.0f64.to_bits()
+        } else if num.is_nan() {
+            f64::NAN.to_bits()
+        } else {
+            num.to_bits()
+        }
+    }
+}
+
+impl Hash for HashKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        core::mem::discriminant(&self.0).hash(state);
+
+        match &self.0 {
+            Value::Bool(val) => val.hash(state),
+            Value::Num(val) => Self::num_bits(*val).hash(state),
+            Value::Str(val) => val.hash(state),
+            _ => {}
+        }
+    }
+}
+
+impl PartialEq for HashKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Value::Num(left), Value::Num(right)) => {
+                Self::num_bits(*left) == Self::num_bits(*right)
+            },
+            (left, right) => left == right,
+        }
+    }
+}
+
+impl Eq for HashKey {}
+
+pub type NativeFn = fn(&mut Heap, &[Value]) -> Result<Value, RuntimeError>;
+
+/// A function implemented in Rust.
+#[derive(Debug, Clone, Copy)]
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    pub fun: NativeFn,
+}
+
+/// A built-in list or map method, together with the value it was accessed
+/// on. The receiver gets passed to the method as its first argument.
+#[derive(Debug)]
+pub struct BuiltinMethod {
+    pub receiver: Value,
+    pub method: &'static Native,
+
+    /// The span of the method name, used for reporting errors
+    pub span: Span,
+}
+
+/// A variable captured by a closure. It points into the stack for as long as
+/// the variable is in scope, and gets moved into the upvalue afterwards.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: Symbol,
+    pub methods: RefCell<HashMap<Symbol, Rc<Closure>>>,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: RefCell<HashMap<Symbol, Value>>,
+}
+
+/// A method, together with the instance it was accessed on.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
+
+/// A list on the heap. See `interpreter::list::List`.
+#[derive(Debug, Clone)]
+pub struct List(pub Rc<RefCell<Vec<Value>>>);
+
+/// A map on the heap. See `interpreter::map::Map`.
+#[derive(Debug, Clone)]
+pub struct Map(pub Rc<RefCell<Entries<HashKey, Value>>>);
+
+/// Lists nested too deeply in each other get dropped without recursing. See
+/// `drop_nested`.
+impl Drop for List {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.0) > 1 {
+            return;
+        }
+
+        if let Ok(mut values) = self.0.try_borrow_mut() {
+            drop_nested(std::mem::take(&mut *values));
+        }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.0) > 1 {
+            return;
+        }
+
+        if let Ok(mut entries) = self.0.try_borrow_mut() {
+            drop_nested(std::mem::take(&mut *entries).into_values().collect());
+        }
+    }
+}
+
+impl Nested for Value {
+    fn take_contents(&self, into: &mut Vec<Self>) {
+        match self {
+            Value::List(list) if Rc::strong_count(&list.0) == 1 => {
+                if let Ok(mut values) = list.0.try_borrow_mut() {
+                    into.append(&mut values);
+                }
+            },
+
+            Value::Map(map) if Rc::strong_count(&map.0) == 1 => {
+                if let Ok(mut entries) = map.0.try_borrow_mut() {
+                    into.extend(std::mem::take(&mut *entries).into_values());
+                }
+            },
+
+            _ => {},
+        }
+    }
+}
+
+impl Trace for RefCell<Vec<Value>> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let Ok(values) = self.try_borrow() else {
+            return tracer.busy();
+        };
+
+        for value in values.iter() {
+            value.trace(tracer);
+        }
+    }
+
+    fn clear(&self) {
+        let values = std::mem::take(&mut *self.borrow_mut());
+        drop(values);
+    }
+}
+
+impl Trace for RefCell<Entries<HashKey, Value>> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let Ok(entries) = self.try_borrow() else {
+            return tracer.busy();
+        };
+
+        for (key, value) in entries.iter() {
+            key.0.trace(tracer);
+            value.trace(tracer);
+        }
+    }
+
+    fn clear(&self) {
+        let entries = std::mem::take(&mut *self.borrow_mut());
+        drop(entries);
+    }
+}
+
+impl Trace for BuiltinMethod {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.receiver.trace(tracer);
+    }
+}
+
+impl Trace for RefCell<Upvalue> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let Ok(upvalue) = self.try_borrow() else {
+            return tracer.busy();
+        };
+
+        if let Upvalue::Closed(value) = &*upvalue {
+            value.trace(tracer);
+        }
+    }
+
+    fn clear(&self) {
+        let upvalue = std::mem::replace(&mut *self.borrow_mut(), Upvalue::Closed(Value::Nil));
+        drop(upvalue);
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, tracer: &mut Tracer) {
+        for upvalue in &self.upvalues {
+            tracer.edge(upvalue);
+        }
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, tracer: &mut Tracer) {
+        let Ok(methods) = self.methods.try_borrow() else {
+            return tracer.busy();
+        };
+
+        for method in methods.values() {
+            tracer.edge(method);
+        }
+    }
+
+    fn clear(&self) {
+        let methods = std::mem::take(&mut *self.methods.borrow_mut());
+        drop(methods);
+    }
+}
+
+impl Trace for Instance {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.edge(&self.class);
+
+        let Ok(fields) = self.fields.try_borrow() else {
+            return tracer.busy();
+        };
+
+        for value in fields.values() {
+            value.trace(tracer);
+        }
+    }
+
+    fn clear(&self) {
+        let fields = std::mem::take(&mut *self.fields.borrow_mut());
+        drop(fields);
+    }
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.receiver.trace(tracer);
+        tracer.edge(&self.method);
+    }
+}